@@ -1,15 +1,13 @@
-use super::types::{AnalysisConfig, AudioError, WavData};
+use super::types::{
+    AnalysisConfig, AudioError, AveragedHarmonics, Averaging, Harmonic, HarmonicAnalysis,
+    PitchEstimate, WavData,
+};
+use super::window::FrameWindow;
 use rustfft::{num_complex::Complex, FftPlanner};
-use std::f32::consts::PI;
 
 /// Analyzes a WAV file to extract harmonic content.
 ///
-/// This function performs the following steps:
-/// 1. Validates the analysis configuration
-/// 2. Extracts mono samples from the WAV data
-/// 3. Applies a Hann window to the samples
-/// 4. Performs FFT analysis
-/// 5. Extracts and normalizes harmonic weights
+/// This returns the weights of [`analyze_harmonics_detailed`], as used in soundfonts.
 ///
 /// # Arguments
 /// * `wav_data` - The WAV data to analyze
@@ -20,92 +18,450 @@ use std::f32::consts::PI;
 ///
 /// # Errors
 /// * If the configuration is invalid
+/// * If no base frequency is configured and the samples are silent
 /// * If there's an error during FFT processing
 pub fn analyze_harmonics(
     wav_data: &WavData,
     config: &AnalysisConfig,
 ) -> Result<Vec<f32>, AudioError> {
+    Ok(analyze_harmonics_detailed(wav_data, config)?.soundfont())
+}
+
+/// Analyzes a WAV file to extract harmonic content, averaged over frames.
+///
+/// This returns the weights of [`analyze_harmonics_detailed`] along with the
+/// variance of each one across the analyzed frames.
+///
+/// # Arguments
+/// * `wav_data` - The WAV data to analyze
+/// * `config` - Configuration parameters for the analysis
+///
+/// # Returns
+/// * `Result<AveragedHarmonics, AudioError>` - Harmonic weights and their variance
+///
+/// # Errors
+/// * If the configuration is invalid
+/// * If no base frequency is configured and the frames are silent
+/// * If there's an error during FFT processing
+pub fn analyze_harmonic_frames(
+    wav_data: &WavData,
+    config: &AnalysisConfig,
+) -> Result<AveragedHarmonics, AudioError> {
+    Ok(AveragedHarmonics::from(&analyze_harmonics_detailed(
+        wav_data, config,
+    )?))
+}
+
+/// Analyzes a WAV file to measure its harmonics in detail.
+///
+/// This function performs the following steps:
+/// 1. Validates the analysis configuration
+/// 2. Extracts mono frames from the WAV data, one at the start time, or
+///    overlapping frames across the time range if an end time is configured
+/// 3. Detects the base frequency of the frames, if none is configured
+/// 4. Applies the configured window to each frame, and pads it with zeros
+/// 5. Performs FFT analysis of each frame
+/// 6. Averages the magnitude spectra of the frames
+/// 7. Measures each harmonic in the averaged spectrum, and normalizes their weights
+/// 8. Measures the noise around the harmonics and how far their peaks are from
+///    whole multiples of the base frequency
+///
+/// The variance of each harmonic is that of its weight in each frame, scaled the
+/// same way as the averaged weights, so a stable region has variances near zero.
+///
+/// # Arguments
+/// * `wav_data` - The WAV data to analyze
+/// * `config` - Configuration parameters for the analysis
+///
+/// # Returns
+/// * `Result<HarmonicAnalysis, AudioError>` - Measurements of every harmonic
+///
+/// # Errors
+/// * If the configuration is invalid
+/// * If no base frequency is configured and the frames are silent
+/// * If there's an error during FFT processing
+pub fn analyze_harmonics_detailed(
+    wav_data: &WavData,
+    config: &AnalysisConfig,
+) -> Result<HarmonicAnalysis, AudioError> {
     // Validate configuration
     config.validate(wav_data)?;
+    let sample_rate = wav_data.sample_rate;
 
-    // Extract mono samples for analysis
-    let mono_samples = extract_mono_samples(wav_data, config)?;
+    // Extract mono frames for analysis
+    let frames = extract_mono_frames(wav_data, config)?;
 
-    // Apply window function
-    let windowed_samples = apply_hann_window(&mono_samples);
+    // Use the configured base frequency, or detect it from the frames
+    let (base_freq, pitch_confidence) = match config.base_freq {
+        Some(base_freq) => (base_freq, None),
+        None => {
+            let pitch = estimate_frames_pitch(&frames, sample_rate)?;
+            (pitch.frequency, Some(pitch.confidence))
+        }
+    };
+    config.validate_base_freq(base_freq, sample_rate)?;
 
-    // Perform FFT
-    let spectrum = compute_fft(&windowed_samples)?;
+    // Window and pad each frame, and average the magnitudes of their spectra
+    let window = FrameWindow::new(config.window, config.samples, config.zero_padding);
+    let spectra = frames
+        .iter()
+        .map(|frame| compute_fft(&window.apply(frame)))
+        .collect::<Result<Vec<Vec<Complex<f32>>>, AudioError>>()?;
+    let magnitude_spectra: Vec<Vec<f32>> = spectra
+        .iter()
+        .map(|spectrum| spectrum.iter().map(|bin| bin.norm()).collect())
+        .collect();
+    let spectrum = average_spectra(&magnitude_spectra, config.averaging);
+
+    // Scale magnitudes so a full-scale sine wave has an amplitude of 1
+    let window_sum = window.sum();
+    let amplitude = |magnitude: f32| 2.0 * magnitude / window_sum;
+
+    // Extract harmonics, scaling the loudest one of the averaged spectrum to 1
+    let magnitudes = extract_harmonic_magnitudes(
+        &spectrum,
+        base_freq,
+        config.num_harmonics,
+        sample_rate,
+        &window,
+    )?;
+    let max_magnitude = magnitudes.iter().copied().fold(0.0, f32::max);
+    let scale = |magnitude: f32| {
+        let normalized = if max_magnitude > 0.0 {
+            magnitude / max_magnitude
+        } else {
+            magnitude
+        };
+        normalized * config.boost
+    };
+    let frame_magnitudes = magnitude_spectra
+        .iter()
+        .map(|frame| {
+            extract_harmonic_magnitudes(
+                frame,
+                base_freq,
+                config.num_harmonics,
+                sample_rate,
+                &window,
+            )
+        })
+        .collect::<Result<Vec<Vec<f32>>, AudioError>>()?;
+
+    let freq_resolution = sample_rate as f32 / spectrum.len() as f32;
+    let spacing = base_freq / freq_resolution;
+    let mut harmonics = Vec::with_capacity(config.num_harmonics);
+    let mut peak_bins = Vec::with_capacity(config.num_harmonics);
+    for (i, &magnitude) in magnitudes.iter().enumerate() {
+        let number = i + 1;
+        let frequency = base_freq * number as f32;
+        let (peak_bin, peak_offset) =
+            find_peak(&spectrum, frequency / freq_resolution, spacing, &window);
+        peak_bins.push(peak_bin);
+
+        // Variance of the harmonic's weight across the frames
+        let weights: Vec<f32> = frame_magnitudes.iter().map(|m| scale(m[i])).collect();
+        let mean = weights.iter().sum::<f32>() / weights.len() as f32;
+        let variance =
+            weights.iter().map(|w| (w - mean).powi(2)).sum::<f32>() / weights.len() as f32;
 
-    // Extract harmonics
-    extract_harmonic_weights(&spectrum, config, wav_data.sample_rate)
+        harmonics.push(Harmonic {
+            number,
+            frequency,
+            peak_frequency: (peak_bin as f32 + peak_offset) * freq_resolution,
+            magnitude: amplitude(magnitude),
+            db: to_db(amplitude(magnitude)),
+            phase: spectra[0][peak_bin].arg(),
+            noise_floor_db: to_db(amplitude(noise_floor(
+                &spectrum, peak_bin, spacing, &window,
+            ))),
+            // Round to 5 decimal places
+            weight: (scale(magnitude) * 100000.0).round() / 100000.0,
+            variance,
+        });
+    }
+
+    Ok(HarmonicAnalysis {
+        base_freq,
+        pitch_confidence,
+        frames: frames.len(),
+        snr_db: signal_to_noise(&spectrum, &peak_bins, &window),
+        inharmonicity: inharmonicity(&harmonics),
+        harmonics,
+    })
 }
 
-/// Extracts mono samples from multi-channel WAV data.
+/// Estimates the fundamental frequency of several frames.
 ///
-/// If the input is multi-channel, the samples are averaged across all channels.
-/// The extracted samples start at the configured start time and include the
-/// specified number of samples.
+/// # Arguments
+/// * `frames` - Mono frames to analyze
+/// * `sample_rate` - Sample rate of the audio
+///
+/// # Returns
+/// * `Result<PitchEstimate, AudioError>` - Median frequency and confidence of the
+///   frames a pitch was detected in
+///
+/// # Errors
+/// * If no pitch can be detected in any frame
+fn estimate_frames_pitch(
+    frames: &[Vec<f32>],
+    sample_rate: u32,
+) -> Result<PitchEstimate, AudioError> {
+    let mut estimates = Vec::with_capacity(frames.len());
+    let mut error = None;
+    for frame in frames {
+        match estimate_pitch(frame, sample_rate) {
+            Ok(estimate) => estimates.push(estimate),
+            Err(e) => error = error.or(Some(e)),
+        }
+    }
+    if estimates.is_empty() {
+        return Err(error.unwrap_or_else(|| {
+            AudioError::InvalidParams("No frames to detect the base frequency in".to_string())
+        }));
+    }
+
+    let mut frequencies: Vec<f32> = estimates.iter().map(|e| e.frequency).collect();
+    let mut confidences: Vec<f32> = estimates.iter().map(|e| e.confidence).collect();
+    Ok(PitchEstimate {
+        frequency: median(&mut frequencies),
+        confidence: median(&mut confidences),
+    })
+}
+
+/// Estimates the fundamental frequency of samples with the YIN algorithm.
+///
+/// The algorithm:
+/// 1. Computes the squared difference between the first half of the samples and
+///    the samples delayed by every lag up to half their length, using an FFT
+/// 2. Normalizes each difference by the mean difference of all shorter lags
+/// 3. Picks the first lag whose normalized difference falls below a threshold,
+///    at the bottom of its dip, or the lag with the lowest difference if none does
+/// 4. Refines the lag with parabolic interpolation
+///
+/// The confidence is one minus the normalized difference at the chosen lag.
 ///
 /// # Arguments
-/// * `wav_data` - The WAV data to extract samples from
-/// * `config` - Configuration specifying start time and number of samples
+/// * `samples` - Mono samples to analyze
+/// * `sample_rate` - Sample rate of the audio
 ///
 /// # Returns
-/// * `Result<Vec<f32>, AudioError>` - Vector of mono samples
+/// * `Result<PitchEstimate, AudioError>` - Detected frequency and its confidence
+///
+/// # Errors
+/// * If the samples are silent or too few to detect a pitch in
+fn estimate_pitch(samples: &[f32], sample_rate: u32) -> Result<PitchEstimate, AudioError> {
+    // Normalized difference below which a lag is taken as the period
+    const THRESHOLD: f32 = 0.1;
+    // Shortest lag considered, so the pitch is below the Nyquist frequency
+    const MIN_LAG: usize = 2;
+
+    let window = samples.len() / 2;
+    let max_lag = samples.len() - window;
+    if max_lag <= MIN_LAG + 1 {
+        return Err(AudioError::InvalidParams(format!(
+            "At least {} samples are needed to detect the base frequency",
+            2 * (MIN_LAG + 2)
+        )));
+    }
+
+    // Energy of the samples before each index
+    let mut energy = Vec::with_capacity(samples.len() + 1);
+    energy.push(0.0f64);
+    for &sample in samples {
+        energy.push(energy[energy.len() - 1] + (sample as f64).powi(2));
+    }
+    if energy[samples.len()] == 0.0 {
+        return Err(AudioError::InvalidParams(
+            "Cannot detect the base frequency of silent audio".to_string(),
+        ));
+    }
+
+    // Squared difference for every lag: both energies minus twice the correlation
+    let correlation = cross_correlate(&samples[..window], samples, max_lag);
+    let difference: Vec<f64> = (0..max_lag)
+        .map(|lag| {
+            let delayed = energy[window + lag] - energy[lag];
+            (energy[window] + delayed - 2.0 * correlation[lag]).max(0.0)
+        })
+        .collect();
+
+    // Cumulative mean normalized difference
+    let mut normalized = vec![1.0f32; max_lag];
+    let mut sum = 0.0;
+    for lag in 1..max_lag {
+        sum += difference[lag];
+        if sum > 0.0 {
+            normalized[lag] = (difference[lag] * lag as f64 / sum) as f32;
+        }
+    }
+
+    let lag = match (MIN_LAG..max_lag).find(|&lag| normalized[lag] < THRESHOLD) {
+        Some(mut lag) => {
+            // Follow the dip down to its lowest point
+            while lag + 1 < max_lag && normalized[lag + 1] < normalized[lag] {
+                lag += 1;
+            }
+            lag
+        }
+        None => (MIN_LAG..max_lag)
+            .min_by(|&a, &b| normalized[a].total_cmp(&normalized[b]))
+            .unwrap_or(MIN_LAG),
+    };
+
+    // Parabolic interpolation between the neighbouring lags
+    let mut period = lag as f32;
+    if lag + 1 < max_lag {
+        let (alpha, beta, gamma) = (normalized[lag - 1], normalized[lag], normalized[lag + 1]);
+        let curvature = alpha - 2.0 * beta + gamma;
+        if curvature > 0.0 {
+            period += 0.5 * (alpha - gamma) / curvature;
+        }
+    }
+
+    Ok(PitchEstimate {
+        frequency: sample_rate as f32 / period,
+        confidence: (1.0 - normalized[lag]).clamp(0.0, 1.0),
+    })
+}
+
+/// Correlates a span of samples with the samples at every lag, using an FFT.
+///
+/// # Arguments
+/// * `span` - Samples to correlate, from the start of `samples`
+/// * `samples` - Samples to correlate with
+/// * `lags` - Number of lags to compute
+///
+/// # Returns
+/// * `Vec<f64>` - Sum of `span[j] * samples[j + lag]` over `j`, for every lag
+fn cross_correlate(span: &[f32], samples: &[f32], lags: usize) -> Vec<f64> {
+    // Pad so the circular correlation does not wrap around for the lags we need
+    let len = (samples.len() + span.len()).next_power_of_two();
+    let mut planner = FftPlanner::new();
+    let forward = planner.plan_fft_forward(len);
+    let inverse = planner.plan_fft_inverse(len);
+
+    let padded = |values: &[f32]| -> Vec<Complex<f32>> {
+        let mut buffer = vec![Complex::new(0.0, 0.0); len];
+        for (slot, &value) in buffer.iter_mut().zip(values) {
+            slot.re = value;
+        }
+        buffer
+    };
+    let mut span_spectrum = padded(span);
+    let mut spectrum = padded(samples);
+    forward.process(&mut span_spectrum);
+    forward.process(&mut spectrum);
+
+    for (value, span_value) in spectrum.iter_mut().zip(&span_spectrum) {
+        *value *= span_value.conj();
+    }
+    inverse.process(&mut spectrum);
+
+    spectrum[..lags]
+        .iter()
+        .map(|value| value.re as f64 / len as f64)
+        .collect()
+}
+
+/// Extracts the mono frames to analyze from multi-channel WAV data.
+///
+/// Without an end time, there is a single frame of the configured number of
+/// samples at the start time. With one, frames of that size are taken from the
+/// start time onwards, each overlapping the previous one by the configured
+/// fraction, for as long as they end before the end time.
+///
+/// # Arguments
+/// * `wav_data` - The WAV data to extract frames from
+/// * `config` - Configuration specifying the time range, frame size and overlap
+///
+/// # Returns
+/// * `Result<Vec<Vec<f32>>, AudioError>` - Mono samples of each frame
 ///
 /// # Errors
 /// * If the requested sample range exceeds the file length
-fn extract_mono_samples(
+fn extract_mono_frames(
     wav_data: &WavData,
     config: &AnalysisConfig,
-) -> Result<Vec<f32>, AudioError> {
-    let start_sample = (config.start_time * wav_data.sample_rate as f32) as usize;
+) -> Result<Vec<Vec<f32>>, AudioError> {
     let samples_per_channel = wav_data.samples.len() / wav_data.channels as usize;
+    let start_sample = (config.start_time * wav_data.sample_rate as f32) as usize;
+    let end_sample = match config.end_time {
+        Some(end_time) => {
+            ((end_time * wav_data.sample_rate as f32) as usize).min(samples_per_channel)
+        }
+        None => start_sample + config.samples,
+    };
 
-    if start_sample + config.samples > samples_per_channel {
+    if end_sample > samples_per_channel || start_sample + config.samples > end_sample {
         return Err(AudioError::InvalidParams(
             "Sample range exceeds file length".to_string(),
         ));
     }
 
-    let mut mono_samples = Vec::with_capacity(config.samples);
+    let hop = ((config.samples as f32 * (1.0 - config.overlap)).round() as usize).max(1);
+    Ok((start_sample..=end_sample - config.samples)
+        .step_by(hop)
+        .map(|frame_start| extract_mono_samples(wav_data, frame_start, config.samples))
+        .collect())
+}
 
-    // Average all channels if multi-channel
-    for i in 0..config.samples {
-        let sample_idx = (start_sample + i) * wav_data.channels as usize;
-        let mut sum = 0.0;
-        for ch in 0..wav_data.channels as usize {
-            sum += wav_data.samples[sample_idx + ch];
-        }
-        mono_samples.push(sum / wav_data.channels as f32);
-    }
+/// Extracts mono samples from multi-channel WAV data.
+///
+/// If the input is multi-channel, the samples are averaged across all channels.
+///
+/// # Arguments
+/// * `wav_data` - The WAV data to extract samples from
+/// * `start_sample` - Index of the first sample to extract, per channel
+/// * `count` - Number of samples to extract
+///
+/// # Returns
+/// * `Vec<f32>` - Vector of mono samples
+fn extract_mono_samples(wav_data: &WavData, start_sample: usize, count: usize) -> Vec<f32> {
+    let channels = wav_data.channels as usize;
 
-    Ok(mono_samples)
+    // Average all channels if multi-channel
+    wav_data.samples[start_sample * channels..(start_sample + count) * channels]
+        .chunks_exact(channels)
+        .map(|sample| sample.iter().sum::<f32>() / channels as f32)
+        .collect()
 }
 
-/// Applies a Hann window function to the input samples.
-///
-/// The Hann window is used to reduce spectral leakage in the FFT analysis.
-/// The window function is: w(n) = 0.5 * (1 - cos(2π*n/(N-1)))
+/// Combines the magnitude spectra of several frames into one.
 ///
 /// # Arguments
-/// * `samples` - Input samples to apply the window to
+/// * `spectra` - Magnitude spectra of the frames, all of the same length
+/// * `averaging` - Whether to take the mean or median of each bin
 ///
 /// # Returns
-/// * `Vec<f32>` - Windowed samples
-fn apply_hann_window(samples: &[f32]) -> Vec<f32> {
-    let len = samples.len();
-    samples
-        .iter()
-        .enumerate()
-        .map(|(i, &sample)| {
-            let window = 0.5 * (1.0 - (2.0 * PI * i as f32 / (len - 1) as f32).cos());
-            sample * window
+/// * `Vec<f32>` - Averaged magnitude spectrum
+fn average_spectra(spectra: &[Vec<f32>], averaging: Averaging) -> Vec<f32> {
+    let len = spectra.first().map_or(0, Vec::len);
+    let mut bin_values = Vec::with_capacity(spectra.len());
+    (0..len)
+        .map(|bin| {
+            bin_values.clear();
+            bin_values.extend(spectra.iter().map(|spectrum| spectrum[bin]));
+            match averaging {
+                Averaging::Mean => bin_values.iter().sum::<f32>() / bin_values.len() as f32,
+                Averaging::Median => median(&mut bin_values),
+            }
         })
         .collect()
 }
 
+/// Returns the median of some values, reordering them.
+///
+/// For an even number of values, this is the mean of the middle two.
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(f32::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
 /// Performs Fast Fourier Transform (FFT) on the input samples.
 ///
 /// Converts the real-valued input samples to complex numbers and
@@ -129,74 +485,180 @@ fn compute_fft(samples: &[f32]) -> Result<Vec<Complex<f32>>, AudioError> {
     Ok(buffer)
 }
 
-/// Extracts harmonic weights from the FFT spectrum.
+/// Finds the spectral peak of a harmonic.
+///
+/// The loudest bin within a quarter of the harmonic spacing of the expected bin
+/// is taken as the peak, and its position is refined to suit the window.
+///
+/// # Arguments
+/// * `spectrum` - Magnitudes of the FFT spectrum
+/// * `expected_bin` - Bin the harmonic is expected at, which may be fractional
+/// * `spacing` - Number of bins between consecutive harmonics
+/// * `window` - Window the spectrum was computed with
+///
+/// # Returns
+/// * `(usize, f32)` - Loudest bin, and the offset of the interpolated peak from it
+fn find_peak(
+    spectrum: &[f32],
+    expected_bin: f32,
+    spacing: f32,
+    window: &FrameWindow,
+) -> (usize, f32) {
+    let radius = ((spacing / 4.0) as usize).max(window.padding());
+    let bin = loudest_bin(spectrum, expected_bin, radius);
+    (bin, window.interpolate(spectrum, bin).0)
+}
+
+/// Finds the loudest bin near an expected bin, below the Nyquist frequency.
+///
+/// # Arguments
+/// * `spectrum` - Magnitudes of the FFT spectrum
+/// * `expected_bin` - Bin to search around, which may be fractional
+/// * `radius` - Number of bins to search on each side
+///
+/// # Returns
+/// * `usize` - Loudest bin, which is never the first bin or the Nyquist bin
+fn loudest_bin(spectrum: &[f32], expected_bin: f32, radius: usize) -> usize {
+    let last_bin = (spectrum.len() / 2).saturating_sub(1).max(1);
+    let center = (expected_bin.round() as usize).clamp(1, last_bin);
+    let low = center.saturating_sub(radius).max(1);
+    let high = (center + radius).min(last_bin);
+    (low..=high)
+        .max_by(|&a, &b| spectrum[a].total_cmp(&spectrum[b]))
+        .unwrap_or(center)
+}
+
+/// Estimates the noise floor around a harmonic.
+///
+/// This is the median magnitude of the bins within half the harmonic spacing of
+/// the peak, leaving out the main lobe of the peak itself.
+///
+/// # Arguments
+/// * `spectrum` - Magnitudes of the FFT spectrum
+/// * `peak_bin` - Bin of the harmonic's peak
+/// * `spacing` - Number of bins between consecutive harmonics
+/// * `window` - Window the spectrum was computed with
+///
+/// # Returns
+/// * `f32` - Median magnitude around the peak
+fn noise_floor(spectrum: &[f32], peak_bin: usize, spacing: f32, window: &FrameWindow) -> f32 {
+    let main_lobe = window.main_lobe_bins();
+    let radius = ((spacing / 2.0) as usize).max(main_lobe + 4 * window.padding());
+    let low = peak_bin.saturating_sub(radius).max(1);
+    let high = (peak_bin + radius).min(spectrum.len() / 2);
+    let mut values: Vec<f32> = (low..=high)
+        .filter(|bin| bin.abs_diff(peak_bin) > main_lobe)
+        .map(|bin| spectrum[bin])
+        .collect();
+    if values.is_empty() {
+        return 0.0;
+    }
+    median(&mut values)
+}
+
+/// Computes the ratio of the power in the harmonic peaks to all other power.
+///
+/// The power of a peak is that of the bins in its main lobe. Everything else up to
+/// the Nyquist frequency, apart from the DC bin, counts as noise.
+///
+/// # Arguments
+/// * `spectrum` - Magnitudes of the FFT spectrum
+/// * `peak_bins` - Bins of the harmonic peaks
+/// * `window` - Window the spectrum was computed with
+///
+/// # Returns
+/// * `f32` - Signal-to-noise ratio (dB)
+fn signal_to_noise(spectrum: &[f32], peak_bins: &[usize], window: &FrameWindow) -> f32 {
+    let main_lobe = window.main_lobe_bins();
+    let mut signal = 0.0f64;
+    let mut noise = 0.0f64;
+    for (bin, &magnitude) in spectrum
+        .iter()
+        .enumerate()
+        .take(spectrum.len() / 2 + 1)
+        .skip(1)
+    {
+        let power = (magnitude as f64).powi(2);
+        if peak_bins
+            .iter()
+            .any(|&peak| bin.abs_diff(peak) <= main_lobe)
+        {
+            signal += power;
+        } else {
+            noise += power;
+        }
+    }
+    (10.0 * (signal.max(1e-20) / noise.max(1e-20)).log10()) as f32
+}
+
+/// Computes how far the harmonic peaks are from whole multiples of the base frequency.
 ///
-/// For each harmonic:
-/// 1. Calculates the target frequency bin
-/// 2. Uses quadratic interpolation for precise magnitude
-/// 3. Normalizes the magnitudes to [0, 1] range
-/// 4. Applies the boost factor
-/// 5. Rounds to 5 decimal places
+/// # Arguments
+/// * `harmonics` - Measured harmonics
+///
+/// # Returns
+/// * `f32` - Mean distance of each peak from its expected frequency in cents,
+///   weighted by the harmonics' magnitudes
+fn inharmonicity(harmonics: &[Harmonic]) -> f32 {
+    let total: f32 = harmonics.iter().map(|h| h.magnitude).sum();
+    if total <= 0.0 {
+        return 0.0;
+    }
+    harmonics
+        .iter()
+        .map(|h| h.magnitude * (1200.0 * (h.peak_frequency / h.frequency).log2()).abs())
+        .sum::<f32>()
+        / total
+}
+
+/// Converts an amplitude to decibels relative to full scale.
+///
+/// Amplitudes of zero are given a level of -200 dB rather than negative infinity.
+fn to_db(amplitude: f32) -> f32 {
+    20.0 * amplitude.max(1e-10).log10()
+}
+
+/// Extracts harmonic magnitudes from a magnitude spectrum.
+///
+/// For each harmonic, the loudest bin within one unpadded bin of its frequency is
+/// taken as its peak, and the magnitude of the peak is refined to suit the window.
 ///
 /// # Arguments
-/// * `spectrum` - FFT spectrum to analyze
-/// * `config` - Analysis configuration
+/// * `spectrum` - Magnitudes of the FFT spectrum to analyze
+/// * `base_freq` - Base frequency of the harmonics (Hz)
+/// * `num_harmonics` - Number of harmonics to extract
 /// * `sample_rate` - Sample rate of the audio
+/// * `window` - Window the spectrum was computed with
 ///
 /// # Returns
-/// * `Result<Vec<f32>, AudioError>` - Vector of harmonic weights
+/// * `Result<Vec<f32>, AudioError>` - Vector of harmonic magnitudes
 ///
 /// # Errors
 /// * If any harmonic frequency exceeds the Nyquist frequency
-fn extract_harmonic_weights(
-    spectrum: &[Complex<f32>],
-    config: &AnalysisConfig,
+fn extract_harmonic_magnitudes(
+    spectrum: &[f32],
+    base_freq: f32,
+    num_harmonics: usize,
     sample_rate: u32,
+    window: &FrameWindow,
 ) -> Result<Vec<f32>, AudioError> {
     let freq_resolution = sample_rate as f32 / spectrum.len() as f32;
-    let mut harmonics = Vec::with_capacity(config.num_harmonics);
+    let mut harmonics = Vec::with_capacity(num_harmonics);
 
     // Extract magnitude for each harmonic
-    for k in 1..=config.num_harmonics {
-        let target_freq = config.base_freq * k as f32;
-        let bin = (target_freq / freq_resolution) as usize;
+    for k in 1..=num_harmonics {
+        let target_bin = base_freq * k as f32 / freq_resolution;
 
-        if bin >= spectrum.len() - 1 {
+        if target_bin as usize >= spectrum.len() - 1 {
             return Err(AudioError::InvalidParams(format!(
                 "Harmonic {} exceeds Nyquist frequency",
                 k
             )));
         }
 
-        // Use quadratic interpolation for more precise magnitude
-        let alpha = spectrum[bin - 1].norm();
-        let beta = spectrum[bin].norm();
-        let gamma = spectrum[bin + 1].norm();
-
-        let p = if beta > 0.0 {
-            0.5 * (alpha - gamma) / (alpha - 2.0 * beta + gamma)
-        } else {
-            0.0
-        };
-
-        let interpolated_magnitude = (beta - 0.25 * (alpha - gamma) * p).abs();
-        harmonics.push(interpolated_magnitude);
+        let bin = loudest_bin(spectrum, target_bin, window.padding());
+        harmonics.push(window.interpolate(spectrum, bin).1);
     }
 
-    // Normalize harmonics
-    if let Some(&max_harmonic) = harmonics.iter().max_by(|a, b| a.partial_cmp(b).unwrap()) {
-        if max_harmonic > 0.0 {
-            harmonics.iter_mut().for_each(|x| *x /= max_harmonic);
-        }
-    }
-
-    // Apply boost factor
-    harmonics.iter_mut().for_each(|x| *x *= config.boost);
-
-    // Round to 5 decimal places
-    harmonics
-        .iter_mut()
-        .for_each(|x| *x = (*x * 100000.0).round() / 100000.0);
-
     Ok(harmonics)
 }
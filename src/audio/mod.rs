@@ -3,11 +3,16 @@
 /// This module provides functionality to:
 /// - Read and parse WAV files
 /// - Analyze audio data to extract harmonic content
+/// - Detect the fundamental frequency of audio data
 /// - Generate soundfonts from audio analysis
 mod analysis;
 mod types;
 mod wav;
+mod window;
 
-pub use analysis::analyze_harmonics;
-pub use types::{AnalysisConfig, AudioError, WavData};
+pub use analysis::{analyze_harmonic_frames, analyze_harmonics, analyze_harmonics_detailed};
+pub use types::{
+    AnalysisConfig, AudioError, AveragedHarmonics, Averaging, Harmonic, HarmonicAnalysis, WavData,
+    WindowFunction, DEFAULT_KAISER_BETA, MAX_KAISER_BETA, MAX_ZERO_PADDING,
+};
 pub use wav::read_wav_file;
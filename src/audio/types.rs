@@ -1,3 +1,15 @@
+use serde::Serialize;
+
+/// Largest factor analyzed samples can be lengthened by with zeros
+pub const MAX_ZERO_PADDING: usize = 16;
+
+/// Shape parameter of a Kaiser window when none is given, which is close to a
+/// Blackman window
+pub const DEFAULT_KAISER_BETA: f32 = 8.6;
+
+/// Largest shape parameter of a Kaiser window
+pub const MAX_KAISER_BETA: f32 = 50.0;
+
 /// Represents the data from a WAV file after reading
 #[derive(Debug)]
 pub struct WavData {
@@ -16,12 +28,155 @@ pub struct AnalysisConfig {
     pub samples: usize,
     /// Start time in seconds
     pub start_time: f32,
-    /// Base frequency for harmonic analysis (Hz)
-    pub base_freq: f32,
+    /// Base frequency for harmonic analysis (Hz), detected from the samples if not given
+    pub base_freq: Option<f32>,
     /// Number of harmonics to extract
     pub num_harmonics: usize,
     /// Boost factor for the output (multiplies the final amplitudes)
     pub boost: f32,
+    /// End of the time range to average frames over (seconds), or None to analyze
+    /// a single frame at the start time
+    pub end_time: Option<f32>,
+    /// Fraction of each frame overlapped by the next one, from 0 up to (not including) 1
+    pub overlap: f32,
+    /// How the spectra of the frames are averaged
+    pub averaging: Averaging,
+    /// Window function applied to each frame before its FFT
+    pub window: WindowFunction,
+    /// Factor each frame is lengthened by with zeros before its FFT, for finer
+    /// frequency resolution (1 for no padding)
+    pub zero_padding: usize,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            samples: 8192,
+            start_time: 0.0,
+            base_freq: None,
+            num_harmonics: 16,
+            boost: 1.0,
+            end_time: None,
+            overlap: 0.5,
+            averaging: Averaging::Mean,
+            window: WindowFunction::Hann,
+            zero_padding: 1,
+        }
+    }
+}
+
+/// How the magnitude spectra of several frames are averaged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Averaging {
+    /// Mean magnitude of each frequency bin
+    #[default]
+    Mean,
+    /// Median magnitude of each frequency bin, which ignores brief noises
+    Median,
+}
+
+/// Window function applied to frames before their FFT
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WindowFunction {
+    /// Hann window, a good balance of frequency resolution and leakage
+    #[default]
+    Hann,
+    /// Hamming window, with a narrow main lobe but more distant leakage
+    Hamming,
+    /// 4-term Blackman-Harris window, with very little leakage
+    BlackmanHarris,
+    /// Flat-top window, with the most accurate amplitudes but a wide main lobe
+    FlatTop,
+    /// Kaiser window, where a higher beta trades resolution for less leakage
+    Kaiser {
+        /// Shape parameter of the window
+        beta: f32,
+    },
+}
+
+/// Detailed measurements of the harmonics of analyzed audio
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarmonicAnalysis {
+    /// Base frequency the harmonics were measured at (Hz)
+    pub base_freq: f32,
+    /// Confidence of the detected base frequency, or None if it was configured
+    pub pitch_confidence: Option<f32>,
+    /// Number of frames analyzed
+    pub frames: usize,
+    /// Measurements of each harmonic, starting with the fundamental
+    pub harmonics: Vec<Harmonic>,
+    /// Ratio of the power in the harmonic peaks to all other power (dB)
+    pub snr_db: f32,
+    /// Mean distance of the harmonic peaks from whole multiples of the base
+    /// frequency (cents), weighted by their magnitudes
+    pub inharmonicity: f32,
+}
+
+impl HarmonicAnalysis {
+    /// Returns the harmonic weights as written to a soundfont
+    pub fn soundfont(&self) -> Vec<f32> {
+        self.harmonics
+            .iter()
+            .map(|harmonic| harmonic.weight)
+            .collect()
+    }
+}
+
+/// Measurements of a single harmonic
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Harmonic {
+    /// Number of the harmonic, where 1 is the fundamental
+    pub number: usize,
+    /// Whole multiple of the base frequency the harmonic is expected at (Hz)
+    pub frequency: f32,
+    /// Frequency of the nearest spectral peak (Hz)
+    pub peak_frequency: f32,
+    /// Amplitude at the expected frequency, where a full-scale sine wave is 1
+    pub magnitude: f32,
+    /// Amplitude relative to full scale (dB)
+    pub db: f32,
+    /// Phase of the FFT bin nearest the peak in the first frame, after windowing,
+    /// relative to the frame's first sample (radians). This is not corrected for
+    /// the peak falling between bins
+    pub phase: f32,
+    /// Level of the noise around the peak relative to full scale (dB)
+    pub noise_floor_db: f32,
+    /// Normalized and boosted weight, as written to a soundfont
+    pub weight: f32,
+    /// Variance of the weight across the analyzed frames
+    pub variance: f32,
+}
+
+/// Harmonic weights averaged over several frames
+#[derive(Debug, Clone, PartialEq)]
+pub struct AveragedHarmonics {
+    /// Normalized harmonic weights of the averaged spectrum
+    pub weights: Vec<f32>,
+    /// Variance of each harmonic's weight across the frames
+    pub variance: Vec<f32>,
+    /// Number of frames analyzed
+    pub frames: usize,
+}
+
+impl From<&HarmonicAnalysis> for AveragedHarmonics {
+    fn from(analysis: &HarmonicAnalysis) -> Self {
+        Self {
+            weights: analysis.soundfont(),
+            variance: analysis.harmonics.iter().map(|h| h.variance).collect(),
+            frames: analysis.frames,
+        }
+    }
+}
+
+/// Fundamental frequency detected in audio samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchEstimate {
+    /// Detected fundamental frequency (Hz)
+    pub frequency: f32,
+    /// How periodic the samples are at this frequency, from 0 (not at all) to 1 (perfectly)
+    pub confidence: f32,
 }
 
 /// Errors that can occur during audio processing
@@ -75,13 +230,69 @@ impl AnalysisConfig {
             )));
         }
 
-        // Check Nyquist frequency
-        let nyquist = wav_data.sample_rate as f32 / 2.0;
-        let max_harmonics = (nyquist / self.base_freq).floor() as usize;
-        if self.base_freq * self.num_harmonics as f32 > nyquist {
+        // Check the window and padding
+        if !(1..=MAX_ZERO_PADDING).contains(&self.zero_padding) {
+            return Err(AudioError::InvalidParams(format!(
+                "Zero-padding factor ({}) must be between 1 and {}",
+                self.zero_padding, MAX_ZERO_PADDING
+            )));
+        }
+        if let WindowFunction::Kaiser { beta } = self.window {
+            if !(0.0..=MAX_KAISER_BETA).contains(&beta) {
+                return Err(AudioError::InvalidParams(format!(
+                    "Kaiser window beta ({}) must be between 0 and {}",
+                    beta, MAX_KAISER_BETA
+                )));
+            }
+        }
+
+        // Check the time range to average frames over
+        if !(0.0..1.0).contains(&self.overlap) {
+            return Err(AudioError::InvalidParams(format!(
+                "Frame overlap ({}) must be at least 0 and less than 1",
+                self.overlap
+            )));
+        }
+        if let Some(end_time) = self.end_time {
+            let end_sample = (end_time * wav_data.sample_rate as f32) as usize;
+            if end_time > duration {
+                return Err(AudioError::InvalidParams(format!(
+                    "End time ({:.2}s) exceeds audio duration ({:.2}s)",
+                    end_time, duration
+                )));
+            }
+            if end_sample < start_sample + self.samples {
+                return Err(AudioError::InvalidParams(format!(
+                    "The range from {:.2}s to {:.2}s is shorter than the {} samples of a frame",
+                    self.start_time, end_time, self.samples
+                )));
+            }
+        }
+
+        // Check Nyquist frequency, unless the base frequency is yet to be detected
+        if let Some(base_freq) = self.base_freq {
+            self.validate_base_freq(base_freq, wav_data.sample_rate)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every harmonic of a base frequency is below the Nyquist frequency
+    ///
+    /// # Arguments
+    /// * `base_freq` - The base frequency to check (Hz)
+    /// * `sample_rate` - Sample rate of the audio
+    ///
+    /// # Returns
+    /// * `Ok(())` if all harmonics can be analyzed
+    /// * `Err(AudioError)` if the highest harmonics exceed the Nyquist frequency
+    pub fn validate_base_freq(&self, base_freq: f32, sample_rate: u32) -> Result<(), AudioError> {
+        let nyquist = sample_rate as f32 / 2.0;
+        let max_harmonics = (nyquist / base_freq).floor() as usize;
+        if base_freq * self.num_harmonics as f32 > nyquist {
             return Err(AudioError::InvalidParams(format!(
                 "With base frequency of {:.1}Hz, maximum number of harmonics possible is {} (limited by Nyquist frequency of {:.1}Hz)",
-                self.base_freq, max_harmonics, nyquist
+                base_freq, max_harmonics, nyquist
             )));
         }
 
@@ -0,0 +1,244 @@
+use super::types::WindowFunction;
+use std::f64::consts::PI;
+
+/// Number of steps per unpadded bin in the table of a window's response
+const RESPONSE_STEPS: usize = 256;
+
+/// Window applied to analyzed frames before they are zero-padded and transformed.
+///
+/// Besides windowing the frames, this refines the peaks of their spectra using the
+/// shape of the window's own spectrum.
+pub(super) struct FrameWindow {
+    function: WindowFunction,
+    coefficients: Vec<f32>,
+    padding: usize,
+    /// Magnitude of the window's spectrum relative to its center, every
+    /// `1 / RESPONSE_STEPS` unpadded bins from the center up to two unpadded bins away
+    response: Vec<f32>,
+}
+
+impl FrameWindow {
+    /// Creates a window for frames of a given length.
+    ///
+    /// # Arguments
+    /// * `function` - Window function to use
+    /// * `len` - Number of samples in each frame
+    /// * `padding` - Factor the frames are lengthened by with zeros
+    pub(super) fn new(function: WindowFunction, len: usize, padding: usize) -> Self {
+        let coefficients = coefficients(function, len);
+        let padding = padding.max(1);
+        let response = (0..=2 * RESPONSE_STEPS)
+            .map(|step| {
+                let offset = step as f64 / RESPONSE_STEPS as f64;
+                spectrum_at(&coefficients, offset / len as f64)
+            })
+            .collect();
+        Self {
+            function,
+            coefficients,
+            padding,
+            response,
+        }
+    }
+
+    /// Number of FFT bins per bin of the unpadded frame
+    pub(super) fn padding(&self) -> usize {
+        self.padding
+    }
+
+    /// Sum of the window's coefficients.
+    ///
+    /// A sine wave of amplitude `A` at the center of a bin has a magnitude of
+    /// `A / 2` times this sum.
+    pub(super) fn sum(&self) -> f32 {
+        self.coefficients.iter().sum()
+    }
+
+    /// Number of bins on each side of a peak covered by the window's main lobe,
+    /// rounded up to cover peaks falling between bins.
+    pub(super) fn main_lobe_bins(&self) -> usize {
+        let half_width = match self.function {
+            WindowFunction::Hann | WindowFunction::Hamming => 2.0,
+            WindowFunction::BlackmanHarris => 4.0,
+            WindowFunction::FlatTop => 5.0,
+            WindowFunction::Kaiser { beta } => (1.0 + (beta / std::f32::consts::PI).powi(2)).sqrt(),
+        };
+        (half_width * self.padding as f32).ceil() as usize + 1
+    }
+
+    /// Multiplies a frame by the window and pads it with zeros.
+    ///
+    /// # Arguments
+    /// * `samples` - Samples of the frame, as many as the window was created for
+    ///
+    /// # Returns
+    /// * `Vec<f32>` - Windowed samples followed by zeros
+    pub(super) fn apply(&self, samples: &[f32]) -> Vec<f32> {
+        let mut windowed = Vec::with_capacity(samples.len() * self.padding);
+        windowed.extend(
+            samples
+                .iter()
+                .zip(&self.coefficients)
+                .map(|(sample, window)| sample * window),
+        );
+        windowed.resize(samples.len() * self.padding, 0.0);
+        windowed
+    }
+
+    /// Refines the position and magnitude of a spectral peak.
+    ///
+    /// A sine wave between two bins shows up in both of them, in proportions set by
+    /// the shape of the window's spectrum. The offset of the peak is the one at which
+    /// the window's spectrum has the same ratio between the loudest bin and its
+    /// louder neighbour, which is exact for a lone sine wave under any window. When
+    /// zero-padded, the neighbours are an unpadded bin away, as the bins in between
+    /// barely differ under wide windows. The magnitude is then divided by the
+    /// window's response at that offset, undoing the loss from the peak falling
+    /// between bins.
+    ///
+    /// # Arguments
+    /// * `spectrum` - Magnitudes of the FFT spectrum
+    /// * `bin` - Loudest bin of the peak, which must not be the first or last bin
+    ///
+    /// # Returns
+    /// * `(f32, f32)` - Offset of the peak from the bin, and its magnitude
+    pub(super) fn interpolate(&self, spectrum: &[f32], bin: usize) -> (f32, f32) {
+        let distance = self.padding.min(bin).min(spectrum.len() - 1 - bin).max(1);
+        let (before, peak, after) = (
+            spectrum[bin - distance],
+            spectrum[bin],
+            spectrum[bin + distance],
+        );
+        if peak <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let (direction, ratio) = if after > before {
+            (1.0, after / peak)
+        } else {
+            (-1.0, before / peak)
+        };
+
+        // The ratio grows as the peak moves halfway towards the neighbour. This is
+        // searched in full, as the loudest padded bin may not be the one nearest the
+        // peak under windows whose main lobe is flat
+        let (mut low, mut high) = (0.0f32, distance as f32 / 2.0);
+        for _ in 0..24 {
+            let offset = (low + high) / 2.0;
+            if self.response(distance as f32 - offset) / self.response(offset) < ratio {
+                low = offset;
+            } else {
+                high = offset;
+            }
+        }
+        let offset = (low + high) / 2.0;
+
+        (direction * offset, peak / self.response(offset))
+    }
+
+    /// Magnitude of the window's spectrum at an offset from its center, relative
+    /// to the magnitude at its center.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset in FFT bins, up to two unpadded bins away
+    fn response(&self, offset: f32) -> f32 {
+        let position = (offset.abs() / self.padding as f32 * RESPONSE_STEPS as f32)
+            .min((2 * RESPONSE_STEPS) as f32);
+        let step = (position as usize).min(2 * RESPONSE_STEPS - 1);
+        let fraction = position - step as f32;
+        let value = self.response[step] * (1.0 - fraction) + self.response[step + 1] * fraction;
+        value.max(f32::MIN_POSITIVE)
+    }
+}
+
+/// Computes the magnitude of a window's spectrum relative to its center.
+///
+/// # Arguments
+/// * `coefficients` - Coefficients of the window, which must be symmetric
+/// * `frequency` - Frequency in cycles per sample
+fn spectrum_at(coefficients: &[f32], frequency: f64) -> f32 {
+    let center = (coefficients.len() as f64 - 1.0) / 2.0;
+    let step = 2.0 * PI * frequency;
+    let (mut response, mut sum) = (0.0, 0.0);
+    for (n, &coefficient) in coefficients.iter().enumerate() {
+        response += coefficient as f64 * (step * (n as f64 - center)).cos();
+        sum += coefficient as f64;
+    }
+    if sum == 0.0 {
+        return 1.0;
+    }
+    (response / sum).abs() as f32
+}
+
+/// Computes the coefficients of a window function.
+///
+/// The cosine-sum windows (Hann, Hamming, Blackman-Harris, and flat-top) are
+/// `w(n) = a0 - a1*cos(2πn/(N-1)) + a2*cos(4πn/(N-1)) - ...`, and the Kaiser window
+/// is `w(n) = I0(β*sqrt(1 - (2n/(N-1) - 1)²)) / I0(β)`.
+///
+/// # Arguments
+/// * `function` - Window function to compute
+/// * `len` - Number of coefficients
+///
+/// # Returns
+/// * `Vec<f32>` - Coefficients of the window
+fn coefficients(function: WindowFunction, len: usize) -> Vec<f32> {
+    if len <= 1 {
+        return vec![1.0; len];
+    }
+    let span = (len - 1) as f64;
+
+    let cosine_sum = |terms: &[f64]| -> Vec<f32> {
+        (0..len)
+            .map(|n| {
+                terms
+                    .iter()
+                    .enumerate()
+                    .map(|(k, a)| {
+                        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                        sign * a * (2.0 * PI * k as f64 * n as f64 / span).cos()
+                    })
+                    .sum::<f64>() as f32
+            })
+            .collect()
+    };
+
+    match function {
+        WindowFunction::Hann => cosine_sum(&[0.5, 0.5]),
+        WindowFunction::Hamming => cosine_sum(&[0.54, 0.46]),
+        WindowFunction::BlackmanHarris => cosine_sum(&[0.35875, 0.48829, 0.14128, 0.01168]),
+        WindowFunction::FlatTop => cosine_sum(&[
+            0.21557895,
+            0.41663158,
+            0.277263158,
+            0.083578947,
+            0.006947368,
+        ]),
+        WindowFunction::Kaiser { beta } => {
+            let beta = beta as f64;
+            let scale = bessel_i0(beta);
+            (0..len)
+                .map(|n| {
+                    let x = 2.0 * n as f64 / span - 1.0;
+                    (bessel_i0(beta * (1.0 - x * x).max(0.0).sqrt()) / scale) as f32
+                })
+                .collect()
+        }
+    }
+}
+
+/// Computes the zeroth-order modified Bessel function of the first kind.
+///
+/// The power series is summed until its terms no longer change the result.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..500 {
+        term *= (half / k as f64).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
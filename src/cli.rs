@@ -1,14 +1,16 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use clipboard::{ClipboardContext, ClipboardProvider};
 use std::io::{self, Write};
 use std::path::Path;
 use std::process;
 
-mod midi;
-use midi::{MidiError, MidiProcessor};
-
-use desmos_midi::audio::{self, AnalysisConfig, AudioError};
+use desmos_midi::audio::{self, AnalysisConfig, AudioError, Averaging, WindowFunction};
 use desmos_midi::config;
+use desmos_midi::midi::{
+    self, Encoding, Envelope, MidiError, MidiProcessor, ProcessingOptions, Rearticulation,
+    RenderOptions, SoundfontMapping, SoundfontSlot, VoiceStacking, VoiceStealing,
+};
+use std::collections::HashMap;
 
 /// Desmos MIDI and Audio Analysis Tool
 #[derive(Parser)]
@@ -25,6 +27,9 @@ enum Commands {
 
     /// Analyze WAV files to create soundfonts
     Audio(AudioArgs),
+
+    /// Render MIDI files or formulas to WAV files
+    Render(RenderArgs),
 }
 
 /// Convert MIDI files to Desmos formulas
@@ -38,13 +43,353 @@ struct MidiArgs {
     #[arg(short, long)]
     copy: bool,
 
+    /// Output format: the A/B/C formulas, a Desmos graph state (JSON), or a standalone HTML player
+    #[arg(long, value_enum, default_value_t = FormatArg::Formula)]
+    format: FormatArg,
+
+    /// Desmos API key the HTML player loads the calculator with (default: the Desmos demo key,
+    /// which is only meant for trying out the page)
+    #[arg(long, value_name = "KEY")]
+    desmos_api_key: Option<String>,
+
     /// Show MIDI channel information and exit
     #[arg(short, long)]
     info: bool,
 
-    /// Soundfont files to use (in order of MIDI channels)
+    #[command(flatten)]
+    conversion: ConversionArgs,
+
+    /// How the notes are written in the formula
+    #[arg(long, value_enum, default_value_t = EncodingArg::Piecewise)]
+    encoding: EncodingArg,
+
+    /// Print the size of the formula in every encoding, so the shorter one can be picked
+    #[arg(long)]
+    compare_encodings: bool,
+
+    /// Shorten the formula by merging repeated pieces, trimming trailing zeros,
+    /// and timing sections from their start
+    #[arg(long)]
+    optimize: bool,
+}
+
+/// Options controlling how a MIDI file is turned into notes
+#[derive(Args)]
+struct ConversionArgs {
+    /// Soundfont files to use (in the order listed by --info)
     #[arg(short, long = "soundfonts", value_delimiter = ' ', num_args = 1.., value_name = "FILE")]
     soundfonts: Vec<String>,
+
+    /// Assign soundfonts per channel, per program on each channel, per program, or per track
+    #[arg(long, value_enum, default_value_t = MappingArg::Channel)]
+    map_by: MappingArg,
+
+    /// Step size that pitch bends are rounded to (semitones, 0 for no rounding)
+    #[arg(long, default_value_t = 0.1, value_name = "SEMITONES")]
+    bend_resolution: f32,
+
+    /// Minimum length of a segment created by a pitch bend or volume change (milliseconds)
+    #[arg(long, default_value_t = 20, value_name = "MS")]
+    segment_interval: u64,
+
+    /// Scale velocities by channel volume (CC7) and expression (CC11)
+    #[arg(long)]
+    channel_volume: bool,
+
+    /// Step size that volume-scaled velocities are rounded to
+    #[arg(long, default_value_t = 1, value_name = "STEP")]
+    volume_resolution: u8,
+
+    /// Ignore the sustain pedal (CC64) and end notes at their note-off
+    #[arg(long)]
+    no_sustain: bool,
+
+    /// Ignore the sostenuto pedal (CC66)
+    #[arg(long)]
+    no_sostenuto: bool,
+
+    /// Which instance of a re-triggered note a note-off ends
+    #[arg(long, value_enum, default_value_t = StackingArg::Fifo)]
+    voice_stacking: StackingArg,
+
+    /// Keep notes struck again right as they end audible with a silent gap or a velocity dip
+    #[arg(long, value_enum, default_value_t = RearticulationArg::None)]
+    rearticulate: RearticulationArg,
+
+    /// Length of the re-articulation gap or velocity dip (milliseconds)
+    #[arg(long, default_value_t = 30, value_name = "MS")]
+    rearticulation_time: u64,
+
+    /// Fraction of the velocity that re-attacked notes start at when dipping
+    #[arg(long, default_value_t = 0.5, value_name = "FRACTION")]
+    dip_level: f32,
+
+    /// Velocity envelope as attack,decay,sustain,release (ms, ms, 0-1, ms),
+    /// optionally prefixed with a channel number and a colon to apply to one channel only
+    #[arg(long = "envelope", value_name = "[CHANNEL:]A,D,S,R")]
+    envelopes: Vec<String>,
+
+    /// Time between the velocity steps that envelopes are sampled into (milliseconds)
+    #[arg(long, default_value_t = 50, value_name = "MS")]
+    envelope_resolution: u64,
+
+    /// Number of decimal places of the times written to the formula (seconds)
+    #[arg(
+        long,
+        default_value_t = 3,
+        value_name = "DIGITS",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new()
+            .range(0..=midi::MAX_TIME_PRECISION as u64)
+    )]
+    time_precision: usize,
+
+    /// Start the song at a time in seconds (e.g. 12.5), a bar and beat (e.g. 17:1),
+    /// or a tick (e.g. 7680t), clipping notes that sound there
+    #[arg(long, value_name = "POINT")]
+    trim_start: Option<String>,
+
+    /// End the song at a time in seconds, a bar and beat, or a tick, clipping
+    /// notes that sound there
+    #[arg(long, value_name = "POINT")]
+    trim_end: Option<String>,
+
+    /// Keep the silence before the first note
+    #[arg(long)]
+    keep_leading_silence: bool,
+
+    /// Move note starts and ends towards a grid of milliseconds (e.g. 25ms) or of
+    /// note values following the tempo (e.g. 1/16, or 1/8t for triplets)
+    #[arg(long, value_name = "GRID")]
+    quantize: Option<String>,
+
+    /// How far notes are moved towards the quantization grid (percent)
+    #[arg(long, default_value_t = 100.0, value_name = "PERCENT", value_parser = parse_percent)]
+    quantize_strength: f32,
+
+    /// Largest number of notes that sound at once
+    #[arg(
+        long,
+        value_name = "NOTES",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_polyphony: Option<usize>,
+
+    /// Which note gives up its voice when the polyphony limit is reached
+    #[arg(long, value_enum, default_value_t = StealingArg::Quietest)]
+    voice_stealing: StealingArg,
+
+    /// Apply the polyphony limit to each channel separately
+    #[arg(long)]
+    polyphony_per_channel: bool,
+}
+
+/// Render songs to WAV files the way the player graph plays them
+#[derive(Parser)]
+struct RenderArgs {
+    /// Path to the input MIDI file, or to a .txt file with formulas written by the midi command
+    #[arg(required = true)]
+    input_file: String,
+
+    /// Path of the WAV file to write (default: the input file with a .wav extension)
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<String>,
+
+    #[command(flatten)]
+    conversion: ConversionArgs,
+
+    /// Number of samples per second
+    #[arg(long, default_value_t = 44100, value_parser = clap::value_parser!(u32).range(1..))]
+    sample_rate: u32,
+
+    /// Frequency of A4 (Hz)
+    #[arg(long, default_value_t = 440.0)]
+    hertz: f64,
+
+    /// Detune of the secondary tone (cents)
+    #[arg(long, default_value_t = 5.0)]
+    detune: f64,
+
+    /// Global volume (0-1)
+    #[arg(long, default_value_t = 0.5)]
+    volume: f64,
+
+    /// Global pitch shift (semitones)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    transpose: f64,
+}
+
+/// What the converted song is written as
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    /// Formulas to paste into the player graph
+    Formula,
+    /// Desmos calculator state for the Desmos API `setState`, including the player
+    DesmosState,
+    /// Standalone HTML page that plays the song in the browser
+    Html,
+}
+
+/// How the notes are written in the formula
+#[derive(Clone, Copy, ValueEnum)]
+enum EncodingArg {
+    /// A piecewise function listing the active notes of every time window
+    Piecewise,
+    /// Parallel lists of every note's start, end, note, velocity, and soundfont
+    EventList,
+}
+
+impl From<EncodingArg> for Encoding {
+    fn from(arg: EncodingArg) -> Self {
+        match arg {
+            EncodingArg::Piecewise => Encoding::Piecewise,
+            EncodingArg::EventList => Encoding::EventList,
+        }
+    }
+}
+
+/// How soundfonts given on the command line are assigned to notes
+#[derive(Clone, Copy, ValueEnum)]
+enum MappingArg {
+    /// One soundfont per channel
+    Channel,
+    /// One soundfont per program used on each channel
+    ChannelProgram,
+    /// One soundfont per program, shared by all channels
+    Program,
+    /// One soundfont per track
+    Track,
+}
+
+impl From<MappingArg> for SoundfontMapping {
+    fn from(arg: MappingArg) -> Self {
+        match arg {
+            MappingArg::Channel => SoundfontMapping::Channel,
+            MappingArg::ChannelProgram => SoundfontMapping::ChannelProgram,
+            MappingArg::Program => SoundfontMapping::Program,
+            MappingArg::Track => SoundfontMapping::Track,
+        }
+    }
+}
+
+/// Order in which stacked instances of the same note are ended
+#[derive(Clone, Copy, ValueEnum)]
+enum StackingArg {
+    /// A note-off ends the oldest sounding instance
+    Fifo,
+    /// A note-off ends the most recently started instance
+    Lifo,
+}
+
+impl From<StackingArg> for VoiceStacking {
+    fn from(arg: StackingArg) -> Self {
+        match arg {
+            StackingArg::Fifo => VoiceStacking::Fifo,
+            StackingArg::Lifo => VoiceStacking::Lifo,
+        }
+    }
+}
+
+/// Which note gives up its voice when the polyphony limit is reached
+#[derive(Clone, Copy, ValueEnum)]
+enum StealingArg {
+    /// Steal the quietest note
+    Quietest,
+    /// Steal the oldest note
+    Oldest,
+    /// Keep the highest and lowest notes, stealing the oldest note between them
+    Inner,
+}
+
+impl From<StealingArg> for VoiceStealing {
+    fn from(arg: StealingArg) -> Self {
+        match arg {
+            StealingArg::Quietest => VoiceStealing::Quietest,
+            StealingArg::Oldest => VoiceStealing::Oldest,
+            StealingArg::Inner => VoiceStealing::Inner,
+        }
+    }
+}
+
+/// How the spectra of several analyzed frames are combined
+#[derive(Clone, Copy, ValueEnum)]
+enum AveragingArg {
+    /// Mean magnitude of each frequency
+    Mean,
+    /// Median magnitude of each frequency, which ignores brief noises
+    Median,
+}
+
+impl From<AveragingArg> for Averaging {
+    fn from(arg: AveragingArg) -> Self {
+        match arg {
+            AveragingArg::Mean => Averaging::Mean,
+            AveragingArg::Median => Averaging::Median,
+        }
+    }
+}
+
+/// Window function applied to analyzed samples
+#[derive(Clone, Copy, ValueEnum)]
+enum WindowArg {
+    /// Hann window, a good balance of frequency resolution and leakage
+    Hann,
+    /// Hamming window, with a narrow main lobe but more distant leakage
+    Hamming,
+    /// Blackman-Harris window, with very little leakage
+    BlackmanHarris,
+    /// Flat-top window, with the most accurate amplitudes but the least resolution
+    FlatTop,
+    /// Kaiser window, shaped by --kaiser-beta
+    Kaiser,
+}
+
+impl WindowArg {
+    /// Returns the window function, using a beta for the Kaiser window.
+    ///
+    /// A beta selects the Kaiser window if no window is given, and is rejected
+    /// with any other window.
+    fn function(
+        window: Option<WindowArg>,
+        kaiser_beta: Option<f32>,
+    ) -> Result<WindowFunction, AudioError> {
+        let window = window.unwrap_or(match kaiser_beta {
+            Some(_) => WindowArg::Kaiser,
+            None => WindowArg::Hann,
+        });
+        match (window, kaiser_beta) {
+            (WindowArg::Kaiser, beta) => Ok(WindowFunction::Kaiser {
+                beta: beta.unwrap_or(audio::DEFAULT_KAISER_BETA),
+            }),
+            (_, Some(_)) => Err(AudioError::InvalidParams(
+                "--kaiser-beta only applies to the Kaiser window".to_string(),
+            )),
+            (WindowArg::Hann, None) => Ok(WindowFunction::Hann),
+            (WindowArg::Hamming, None) => Ok(WindowFunction::Hamming),
+            (WindowArg::BlackmanHarris, None) => Ok(WindowFunction::BlackmanHarris),
+            (WindowArg::FlatTop, None) => Ok(WindowFunction::FlatTop),
+        }
+    }
+}
+
+/// How repeated notes are kept audible
+#[derive(Clone, Copy, ValueEnum)]
+enum RearticulationArg {
+    /// Emit repeated notes unchanged
+    None,
+    /// Shorten the earlier note to leave a silent gap
+    Gap,
+    /// Start the repeated note at a reduced velocity
+    Dip,
+}
+
+impl From<RearticulationArg> for Rearticulation {
+    fn from(arg: RearticulationArg) -> Self {
+        match arg {
+            RearticulationArg::None => Rearticulation::None,
+            RearticulationArg::Gap => Rearticulation::Gap,
+            RearticulationArg::Dip => Rearticulation::Dip,
+        }
+    }
 }
 
 /// Analyze WAV files to create soundfonts
@@ -62,9 +407,9 @@ struct AudioArgs {
     #[arg(long, default_value_t = 0.0)]
     start_time: f32,
 
-    /// Fundamental frequency to analyze (Hz)
-    #[arg(long, default_value_t = 440.0)]
-    base_freq: f32,
+    /// Fundamental frequency to analyze (Hz), detected from the audio if not given
+    #[arg(long)]
+    base_freq: Option<f32>,
 
     /// Number of harmonics to extract
     #[arg(long, default_value_t = 16)]
@@ -74,6 +419,42 @@ struct AudioArgs {
     #[arg(long, default_value_t = 1.0)]
     boost: f32,
 
+    /// Average overlapping frames from the start time up to this time (seconds)
+    /// instead of analyzing a single frame
+    #[arg(long)]
+    end_time: Option<f32>,
+
+    /// Fraction of each averaged frame overlapped by the next one
+    #[arg(long, default_value_t = 0.5, requires = "end_time")]
+    overlap: f32,
+
+    /// How the spectra of the averaged frames are combined
+    #[arg(long, value_enum, default_value_t = AveragingArg::Mean, requires = "end_time")]
+    averaging: AveragingArg,
+
+    /// Window function applied to the analyzed samples (default: hann, or kaiser if
+    /// --kaiser-beta is given)
+    #[arg(long, value_enum)]
+    window: Option<WindowArg>,
+
+    /// Shape of the Kaiser window, where higher values trade resolution for less leakage
+    /// (default: 8.6)
+    #[arg(long, value_name = "BETA")]
+    kaiser_beta: Option<f32>,
+
+    /// Factor the analyzed samples are lengthened by with zeros, for finer frequency resolution
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new()
+            .range(1..=audio::MAX_ZERO_PADDING as u64)
+    )]
+    zero_padding: usize,
+
+    /// Output the full analysis of every harmonic as JSON instead of the soundfont weights
+    #[arg(long)]
+    json: bool,
+
     /// Copy output to clipboard instead of console
     #[arg(short, long)]
     copy: bool,
@@ -90,7 +471,7 @@ fn process_soundfont_name(name: &str) -> String {
     }
 }
 
-fn print_channel_info(song: &midi::ProcessedSong) {
+fn print_channel_info(song: &midi::ProcessedSong, mapping: SoundfontMapping) {
     println!("MIDI Channel Information:");
     println!("------------------------");
     for channel in &song.channels {
@@ -100,7 +481,119 @@ fn print_channel_info(song: &midi::ProcessedSong) {
             if channel.is_drum { "[DRUMS] " } else { "" },
             midi::get_instrument_name(channel.instrument, channel.is_drum)
         );
+        // List every program the channel plays notes with when it switches programs
+        if channel.programs.len() > 1 {
+            for &program in &channel.programs {
+                println!(
+                    "  Program {}: {}",
+                    program,
+                    midi::get_instrument_name(program, channel.is_drum)
+                );
+            }
+        }
+    }
+
+    println!();
+    println!("MIDI Track Information:");
+    println!("----------------------");
+    for track in &song.tracks {
+        println!("{}", describe_track(track));
     }
+
+    if mapping != SoundfontMapping::Channel {
+        println!();
+        println!("Soundfont Order:");
+        println!("----------------");
+        for (index, slot) in mapping.slots(song).iter().enumerate() {
+            println!("{}. {}", index + 1, describe_slot(slot, song));
+        }
+    }
+}
+
+/// Describes a track with its name and channels for display
+fn describe_track(track: &midi::Track) -> String {
+    let channels = track
+        .channels
+        .iter()
+        .map(|channel| (channel + 1).to_string()) // MIDI channels are 1-based in display
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!(
+        "Track {}: {} ({} {})",
+        track.index + 1,
+        track.name.as_deref().unwrap_or("[unnamed]"),
+        if track.channels.len() == 1 {
+            "channel"
+        } else {
+            "channels"
+        },
+        channels
+    )
+}
+
+/// Describes a soundfont slot for display
+fn describe_slot(slot: &SoundfontSlot, song: &midi::ProcessedSong) -> String {
+    match *slot {
+        SoundfontSlot::Channel(channel) => format!("Channel {}", channel + 1),
+        SoundfontSlot::ChannelProgram(channel, program) => format!(
+            "Channel {}, Program {}: {}",
+            channel + 1,
+            program,
+            midi::get_instrument_name(program, slot.is_drum(song))
+        ),
+        SoundfontSlot::Program(program) => format!(
+            "Program {}: {}",
+            program,
+            midi::get_instrument_name(program, false)
+        ),
+        SoundfontSlot::Track(index) => song
+            .tracks
+            .iter()
+            .find(|track| track.index == index)
+            .map(describe_track)
+            .unwrap_or_else(|| format!("Track {}", index + 1)),
+    }
+}
+
+/// Parses the envelope arguments into the envelope of each channel.
+///
+/// Envelopes without a channel apply to every channel that isn't given its own.
+fn parse_channel_envelopes(specs: &[String]) -> Result<HashMap<u8, Envelope>, MidiError> {
+    let mut default = None;
+    let mut envelopes = HashMap::new();
+    for spec in specs {
+        match spec.split_once(':') {
+            Some((channel, envelope)) => {
+                let channel = channel
+                    .trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|channel| (1..=16).contains(channel))
+                    .ok_or_else(|| {
+                        MidiError::InvalidEnvelope(format!("Invalid channel: {}", channel))
+                    })?;
+                // MIDI channels are 1-based on the command line
+                envelopes.insert(channel - 1, envelope.parse()?);
+            }
+            None => default = Some(spec.parse()?),
+        }
+    }
+
+    if let Some(envelope) = default {
+        for channel in 0..16 {
+            envelopes.entry(channel).or_insert(envelope);
+        }
+    }
+    Ok(envelopes)
+}
+
+/// Parses a percentage between 0 and 100
+fn parse_percent(value: &str) -> Result<f32, String> {
+    let percent: f32 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("{} is not between 0 and 100", value));
+    }
+    Ok(percent)
 }
 
 /// Custom conversion from clipboard error to MidiError
@@ -108,6 +601,109 @@ fn clipboard_error<E: std::fmt::Display>(err: E) -> MidiError {
     MidiError::ClipboardError(err.to_string())
 }
 
+/// Creates a MidiProcessor with the conversion options and the soundfont
+/// directory from config.toml
+fn midi_processor(
+    args: &ConversionArgs,
+    encoding: Encoding,
+    optimize: bool,
+) -> Result<MidiProcessor, MidiError> {
+    let soundfonts_dir = config::get_soundfonts_dir();
+    let options = ProcessingOptions {
+        pitch_bend_resolution: args.bend_resolution,
+        segment_interval: args.segment_interval,
+        channel_volume: args.channel_volume,
+        volume_resolution: args.volume_resolution,
+        sustain_pedal: !args.no_sustain,
+        sostenuto_pedal: !args.no_sostenuto,
+        voice_stacking: args.voice_stacking.into(),
+        rearticulation: args.rearticulate.into(),
+        rearticulation_time: args.rearticulation_time,
+        rearticulation_dip: args.dip_level,
+        channel_envelopes: parse_channel_envelopes(&args.envelopes)?,
+        envelope_resolution: args.envelope_resolution,
+        time_precision: args.time_precision,
+        trim_start: args.trim_start.as_deref().map(str::parse).transpose()?,
+        trim_end: args.trim_end.as_deref().map(str::parse).transpose()?,
+        strip_leading_silence: !args.keep_leading_silence,
+        quantize: args.quantize.as_deref().map(str::parse).transpose()?,
+        quantize_strength: args.quantize_strength / 100.0,
+        max_polyphony: args.max_polyphony,
+        voice_stealing: args.voice_stealing.into(),
+        polyphony_per_channel: args.polyphony_per_channel,
+        encoding,
+        optimize,
+    };
+    Ok(
+        MidiProcessor::with_soundfont_dir(soundfonts_dir.to_str().unwrap_or("soundfonts"))
+            .with_options(options)
+            .with_soundfont_mapping(args.map_by.into()),
+    )
+}
+
+/// Processes a MIDI file with the soundfonts given on the command line, or the
+/// default soundfont for every slot that isn't a drum channel
+fn process_song(
+    processor: &MidiProcessor,
+    midi_file: &str,
+    args: &ConversionArgs,
+) -> Result<midi::ProcessedSong, MidiError> {
+    let soundfonts = if args.soundfonts.is_empty() {
+        // First get channel info to identify drum channels
+        let info = processor.process_info(midi_file)?;
+
+        // Create soundfont list with "-" for drum channels and default.txt for others
+        SoundfontMapping::from(args.map_by)
+            .slots(&info)
+            .iter()
+            .map(|slot| {
+                if slot.is_drum(&info) {
+                    "-".to_string()
+                } else {
+                    "default.txt".to_string()
+                }
+            })
+            .collect()
+    } else {
+        // Process each soundfont name to ensure .txt extension
+        let soundfonts: Vec<String> = args
+            .soundfonts
+            .iter()
+            .map(|s| process_soundfont_name(s))
+            .collect();
+
+        // Verify all soundfonts exist before proceeding
+        processor.verify_soundfonts(&soundfonts)?;
+        soundfonts
+    };
+
+    processor.process_with_soundfonts(midi_file, soundfonts)
+}
+
+/// Reports stacked re-triggered notes and notes stolen by the polyphony limit on
+/// stderr to keep the output clean
+fn report_stats(song: &midi::ProcessedSong, args: &ConversionArgs) {
+    if song.stats.stacked_notes > 0 {
+        eprintln!(
+            "Stacked {} re-triggered notes (up to {} instances of one note at once)",
+            song.stats.stacked_notes, song.stats.max_stacked
+        );
+    }
+    if let Some(limit) = args.max_polyphony {
+        eprintln!(
+            "Polyphony limit of {} notes{}: truncated {} notes and dropped {} notes",
+            limit,
+            if args.polyphony_per_channel {
+                " per channel"
+            } else {
+                ""
+            },
+            song.stats.truncated_notes,
+            song.stats.dropped_notes
+        );
+    }
+}
+
 fn run_midi_command(args: &MidiArgs) -> Result<(), MidiError> {
     // Check if MIDI file exists with a clear error message
     if !Path::new(&args.midi_file).exists() {
@@ -117,50 +713,52 @@ fn run_midi_command(args: &MidiArgs) -> Result<(), MidiError> {
         )));
     }
 
-    // Create MidiProcessor using the directory from config.toml
-    let soundfonts_dir = config::get_soundfonts_dir();
-    let processor =
-        MidiProcessor::with_soundfont_dir(soundfonts_dir.to_str().unwrap_or("soundfonts"));
+    let processor = midi_processor(&args.conversion, args.encoding.into(), args.optimize)?;
 
     // Process MIDI file
     let song = if args.info {
         processor.process_info(&args.midi_file)?
     } else {
-        let soundfonts = if args.soundfonts.is_empty() {
-            // First get channel info to identify drum channels
-            let info = processor.process_info(&args.midi_file)?;
-
-            // Create soundfont list with "-" for drum channels and default.txt for others
-            info.channels
-                .iter()
-                .map(|ch| {
-                    if ch.is_drum {
-                        "-".to_string()
-                    } else {
-                        "default.txt".to_string()
-                    }
-                })
-                .collect()
-        } else {
-            // Process each soundfont name to ensure .txt extension
-            let soundfonts: Vec<String> = args
-                .soundfonts
-                .iter()
-                .map(|s| process_soundfont_name(s))
-                .collect();
-
-            // Verify all soundfonts exist before proceeding
-            processor.verify_soundfonts(&soundfonts)?;
-            soundfonts
-        };
-
-        processor.process_with_soundfonts(&args.midi_file, soundfonts)?
+        process_song(&processor, &args.midi_file, &args.conversion)?
     };
 
     if args.info {
-        print_channel_info(&song);
+        print_channel_info(&song, args.conversion.map_by.into());
     } else {
-        let formula = song.to_piecewise_function();
+        report_stats(&song, &args.conversion);
+
+        if args.compare_encodings {
+            eprintln!(
+                "Formula size: {} bytes as piecewise, {} bytes as event-list",
+                song.formula_size(Encoding::Piecewise),
+                song.formula_size(Encoding::EventList)
+            );
+        }
+        let expressions = song.to_expressions();
+        midi::validate_expressions(&expressions)?;
+        for (name, length) in midi::oversized_lists(&expressions) {
+            eprintln!(
+                "Warning: {} is a list of {} elements, but Desmos lists hold at most {}",
+                name,
+                length,
+                midi::MAX_LIST_LENGTH
+            );
+        }
+        if args.optimize {
+            eprintln!(
+                "Optimized the formula, saving {} bytes",
+                song.optimization_savings()
+            );
+        }
+
+        let formula = match args.format {
+            FormatArg::Formula => midi::format_latex(&expressions),
+            FormatArg::DesmosState => song.to_desmos_state()?,
+            FormatArg::Html => song.to_html_player(
+                &midi::song_title(&args.midi_file),
+                args.desmos_api_key.as_deref(),
+            )?,
+        };
         if args.copy {
             // Copy to clipboard
             ClipboardContext::new()
@@ -197,17 +795,46 @@ fn run_audio_command(args: &AudioArgs) -> Result<(), AudioError> {
         base_freq: args.base_freq,
         num_harmonics: args.harmonics,
         boost: args.boost,
+        end_time: args.end_time,
+        overlap: args.overlap,
+        averaging: args.averaging.into(),
+        window: WindowArg::function(args.window, args.kaiser_beta)?,
+        zero_padding: args.zero_padding,
     };
 
     // Analyze harmonics
-    let harmonics = audio::analyze_harmonics(&wav_data, &config)?;
+    let analysis = audio::analyze_harmonics_detailed(&wav_data, &config)?;
 
-    // Format the harmonics as a comma-separated string
-    let output = harmonics
-        .iter()
-        .map(|h| h.to_string())
-        .collect::<Vec<String>>()
-        .join(",");
+    let output = if args.json {
+        serde_json::to_string_pretty(&analysis)
+            .map_err(|e| AudioError::ProcessingError(e.to_string()))?
+    } else {
+        // Report the detected base frequency and how stable the harmonics are across
+        // averaged frames, apart from the weights
+        if let Some(confidence) = analysis.pitch_confidence {
+            eprintln!(
+                "Detected base frequency: {:.2} Hz (confidence {:.2})",
+                analysis.base_freq, confidence
+            );
+        }
+        if config.end_time.is_some() {
+            let variance = analysis
+                .harmonics
+                .iter()
+                .map(|h| format!("{:.5}", h.variance))
+                .collect::<Vec<String>>()
+                .join(",");
+            eprintln!("Variance across {} frames: {}", analysis.frames, variance);
+        }
+
+        // Format the harmonics as a comma-separated string
+        analysis
+            .soundfont()
+            .iter()
+            .map(|h| h.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    };
 
     if args.copy {
         // Copy to clipboard
@@ -217,19 +844,58 @@ fn run_audio_command(args: &AudioArgs) -> Result<(), AudioError> {
             .map_err(|e| AudioError::ProcessingError(e.to_string()))?;
         println!("Successfully copied soundfont to clipboard!");
     } else {
-        // Output directly to console, just the weights
+        // Output directly to console, just the weights or analysis
         io::stdout().write_all(output.as_bytes())?;
     }
 
     Ok(())
 }
 
+fn run_render_command(args: &RenderArgs) -> Result<(), MidiError> {
+    let input_path = Path::new(&args.input_file);
+    if !input_path.exists() {
+        return Err(MidiError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Input file not found: {}", args.input_file),
+        )));
+    }
+
+    // Formulas are rendered as written, and MIDI files as they would be converted
+    let song = if input_path.extension().is_some_and(|ext| ext == "txt") {
+        midi::decode_formula(&std::fs::read_to_string(input_path)?)?
+    } else {
+        let processor = midi_processor(&args.conversion, Encoding::default(), false)?;
+        let song = process_song(&processor, &args.input_file, &args.conversion)?;
+        report_stats(&song, &args.conversion);
+        song
+    };
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        input_path
+            .with_extension("wav")
+            .to_string_lossy()
+            .into_owned()
+    });
+    let options = RenderOptions {
+        sample_rate: args.sample_rate,
+        hertz: args.hertz,
+        detune: args.detune,
+        volume: args.volume,
+        transpose: args.transpose,
+    };
+    midi::render_wav(&song, Path::new(&output), &options)?;
+    println!("Rendered {}", output);
+
+    Ok(())
+}
+
 fn run() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     match &cli.command {
         Commands::Midi(args) => run_midi_command(args)?,
         Commands::Audio(args) => run_audio_command(args)?,
+        Commands::Render(args) => run_render_command(args)?,
     }
 
     Ok(())
@@ -24,6 +24,9 @@ pub struct ServerConfig {
     pub file_expiration_minutes: u64,
     pub file_refresh_threshold_minutes: u64,
     pub max_file_size_mb: u64,
+    /// Desmos API key used by downloaded player pages, instead of the Desmos demo key
+    #[serde(default)]
+    pub desmos_api_key: Option<String>,
     pub limits: AnalysisLimits,
 }
 
@@ -59,6 +62,7 @@ impl Default for ServerConfig {
             file_expiration_minutes: 10,
             file_refresh_threshold_minutes: 5,
             max_file_size_mb: 80,
+            desmos_api_key: None,
             limits: AnalysisLimits::default(),
         }
     }
@@ -0,0 +1,449 @@
+use super::desmos::{parse_latex, Assignment, Expression, Number};
+use super::types::{
+    Encoding, MidiError, MidiNote, Note, NoteEvent, ProcessedSong, ProcessingOptions,
+    ProcessingStats, SoundFontMap, Timestamp, Velocity, MAX_TIME_PRECISION,
+};
+use std::collections::HashMap;
+
+/// Number of microseconds in a second
+const US_PER_SECOND: f64 = 1_000_000.0;
+/// MIDI note number of A4, which relative notes count from
+const A4: f64 = 69.0;
+
+/// Window of a piecewise formula, in which the same notes play
+struct Window {
+    /// Time in seconds when the window ends
+    end: f64,
+    /// Number of decimal places the end is written with
+    decimals: usize,
+    /// Relative note, velocity, and soundfont index of each note
+    notes: Vec<[f64; 3]>,
+}
+
+/// Decodes generated Desmos formulas back into a song.
+///
+/// See [`decode_expressions`] for what is recovered.
+///
+/// # Arguments
+/// * `text` - Newline-separated `A`, `B`, and `C` formulas, as written by
+///   [`ProcessedSong::to_piecewise_function`]
+///
+/// # Returns
+/// * `ProcessedSong` - Song with the notes and soundfonts of the formulas
+///
+/// # Errors
+/// * If the text is not a set of formulas
+/// * If a formula is missing or not in the form the converter writes it in
+pub fn decode_formula(text: &str) -> Result<ProcessedSong, MidiError> {
+    decode_expressions(&parse_latex(text)?)
+}
+
+/// Decodes the expressions of generated Desmos formulas back into a song.
+///
+/// Both encodings are recognized, along with split and optimized formulas. Notes
+/// that continue from one piece of a piecewise formula into the next are joined
+/// into one note, unless the two pieces are the same, which only happens when
+/// notes are struck again. Everything the formulas do not hold, such as channels,
+/// programs, and envelopes, is left empty or at its default, and the time precision
+/// is the largest number of decimal places of the note times, up to
+/// [`MAX_TIME_PRECISION`]. Encoding the decoded song again gives the same formulas,
+/// except that instances of the same note playing at once may be listed in a
+/// different order.
+///
+/// # Arguments
+/// * `assignments` - Formulas `A` (and the formulas it is built from), `B`, and `C`
+///
+/// # Returns
+/// * `ProcessedSong` - Song with the notes and soundfonts of the formulas
+///
+/// # Errors
+/// * If a formula is missing or not in the form the converter writes it in
+/// * If a note, velocity, or soundfont index is out of range
+pub fn decode_expressions(assignments: &[Assignment]) -> Result<ProcessedSong, MidiError> {
+    // Optimized sections are functions of the time, named like `A_{2}\left(t\right)`
+    let formulas: HashMap<&str, &Expression> = assignments
+        .iter()
+        .map(|assignment| {
+            let name = assignment.name.as_str();
+            (
+                name.strip_suffix("\\left(t\\right)").unwrap_or(name),
+                &assignment.value,
+            )
+        })
+        .collect();
+    let formula = |name: &str| {
+        formulas
+            .get(name)
+            .copied()
+            .ok_or_else(|| MidiError::InvalidFormula(format!("Missing formula {}", name)))
+    };
+
+    let soundfonts = decode_soundfonts(formula("B")?, formula("C")?)?;
+    let defaults = ProcessingOptions::default();
+    let (encoding, (note_changes, precision)) = match formulas.contains_key("E_{start}") {
+        true => (Encoding::EventList, decode_event_list(&formula)?),
+        false => (Encoding::Piecewise, decode_piecewise(&formula)?),
+    };
+
+    for note in note_changes.iter().flat_map(|event| &event.notes) {
+        if note.soundfont >= soundfonts.fonts.len() {
+            return Err(MidiError::InvalidFormula(format!(
+                "Soundfont index {} is out of range for {} soundfonts",
+                note.soundfont,
+                soundfonts.fonts.len()
+            )));
+        }
+    }
+
+    Ok(ProcessedSong {
+        note_changes,
+        channels: Vec::new(),
+        tracks: Vec::new(),
+        soundfonts,
+        stats: ProcessingStats::default(),
+        envelope_resolution: defaults.envelope_resolution,
+        time_precision: precision
+            .unwrap_or(defaults.time_precision)
+            .min(MAX_TIME_PRECISION),
+        encoding,
+        optimize: false,
+    })
+}
+
+/// Decodes the soundfont array (B) and size (C) formulas.
+///
+/// # Arguments
+/// * `weights` - Formula `B`, the harmonic weights of all soundfonts
+/// * `size` - Formula `C`, the number of weights of each soundfont
+///
+/// # Returns
+/// * `SoundFontMap` - Soundfonts of the song
+///
+/// # Errors
+/// * If `B` is not a list of numbers or `C` is not a number
+/// * If the number of weights is not a multiple of the soundfont size
+fn decode_soundfonts(weights: &Expression, size: &Expression) -> Result<SoundFontMap, MidiError> {
+    let weights = numbers(weights, "B")?;
+    let Expression::Number(size) = size else {
+        return Err(MidiError::InvalidFormula("C is not a number".to_string()));
+    };
+    let size = size.value() as usize;
+
+    let fonts = match size {
+        0 if weights.is_empty() => Vec::new(),
+        0 => {
+            return Err(MidiError::InvalidFormula(
+                "B has weights but C is 0".to_string(),
+            ))
+        }
+        _ if weights.len() % size != 0 => {
+            return Err(MidiError::InvalidFormula(format!(
+                "B has {} weights, which is not a multiple of C ({})",
+                weights.len(),
+                size
+            )))
+        }
+        _ => weights
+            .chunks(size)
+            .map(|font| font.iter().map(|&weight| weight as f32).collect())
+            .collect(),
+    };
+    Ok(SoundFontMap::new(fonts))
+}
+
+/// Decodes the notes of a piecewise formula `A`.
+///
+/// # Arguments
+/// * `formula` - Looks up a formula by name
+///
+/// # Returns
+/// * `(Vec<NoteEvent>, Option<usize>)` - Note events, and the largest number of
+///   decimal places of a note time, if there are notes
+///
+/// # Errors
+/// * If `A` or one of its sections is missing or not a piecewise formula of note lists
+fn decode_piecewise<'a>(
+    formula: &impl Fn(&str) -> Result<&'a Expression, MidiError>,
+) -> Result<(Vec<NoteEvent>, Option<usize>), MidiError> {
+    let mut windows = Vec::new();
+    collect_windows(formula("A")?, "A", 0.0, formula, &mut windows)?;
+
+    // The end of the song is rounded to the same precision as the note times
+    let precision = windows.iter().map(|window| window.decimals).max();
+
+    let mut note_changes: Vec<NoteEvent> = Vec::new();
+    // Event and note index of the notes playing in the previous window, with their values
+    let mut playing: Vec<(usize, usize, [f64; 3])> = Vec::new();
+    let mut previous: Option<&[[f64; 3]]> = None;
+    let mut start = 0.0;
+    for window in &windows {
+        let (window_start, window_end) = (micros(start), micros(window.end));
+        start = window.end;
+
+        // The same notes in two windows in a row are struck again
+        let mut continuing = match previous {
+            Some(notes) if notes != window.notes.as_slice() => std::mem::take(&mut playing),
+            _ => Vec::new(),
+        };
+        playing.clear();
+        previous = Some(&window.notes);
+
+        let mut struck = Vec::new();
+        for values in &window.notes {
+            let matching = continuing
+                .iter()
+                .position(|(_, _, playing)| playing == values);
+            match matching {
+                Some(index) => {
+                    let (event, note, values) = continuing.remove(index);
+                    note_changes[event].notes[note].end = window_end;
+                    playing.push((event, note, values));
+                }
+                None => struck.push((decode_note(values, window_start, window_end)?, *values)),
+            }
+        }
+
+        if !struck.is_empty() {
+            let event = note_changes.len();
+            let (struck, values): (Vec<Note>, Vec<[f64; 3]>) = struck.into_iter().unzip();
+            playing.extend(
+                values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(note, values)| (event, note, values)),
+            );
+            note_changes.push(NoteEvent {
+                timestamp: window_start,
+                notes: struck,
+            });
+        }
+    }
+    Ok((note_changes, precision))
+}
+
+/// Collects the windows of a piecewise formula, following the sections it selects.
+///
+/// # Arguments
+/// * `expression` - Piecewise formula
+/// * `name` - Name of the formula, for errors
+/// * `offset` - Number of seconds that the formula's times are relative to
+/// * `formula` - Looks up a formula by name
+/// * `windows` - Windows collected so far
+///
+/// # Errors
+/// * If the formula or one of its sections is not a piecewise formula of note lists
+fn collect_windows<'a>(
+    expression: &Expression,
+    name: &str,
+    offset: f64,
+    formula: &impl Fn(&str) -> Result<&'a Expression, MidiError>,
+    windows: &mut Vec<Window>,
+) -> Result<(), MidiError> {
+    let Expression::Piecewise(pieces) = expression else {
+        return Err(MidiError::InvalidFormula(format!(
+            "{} is not a piecewise formula",
+            name
+        )));
+    };
+
+    for piece in pieces {
+        match &piece.value {
+            Expression::List(_) => {
+                let values = numbers(&piece.value, name)?;
+                if values.len() % 3 != 0 {
+                    return Err(MidiError::InvalidFormula(format!(
+                        "{} has a list of {} values, which is not a multiple of 3",
+                        name,
+                        values.len()
+                    )));
+                }
+                windows.push(Window {
+                    end: offset + piece.end.value(),
+                    decimals: piece.end.decimals(),
+                    notes: values
+                        .chunks(3)
+                        .map(|note| [note[0], note[1], note[2]])
+                        .collect(),
+                });
+            }
+            Expression::Variable(section) => {
+                collect_windows(formula(section)?, section, 0.0, formula, windows)?;
+            }
+            Expression::Latex(call) => {
+                let (section, section_offset) = parse_section_call(call).ok_or_else(|| {
+                    MidiError::InvalidFormula(format!("{} selects an unknown value {}", name, call))
+                })?;
+                collect_windows(formula(section)?, section, section_offset, formula, windows)?;
+            }
+            _ => {
+                return Err(MidiError::InvalidFormula(format!(
+                    "{} has a piece that is not a list of notes",
+                    name
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a call of an optimized section, such as `A_{2}\left(t-150\right)`.
+///
+/// # Returns
+/// * `Option<(&str, f64)>` - Name of the section and the number of seconds its
+///   times are relative to
+fn parse_section_call(call: &str) -> Option<(&str, f64)> {
+    let (section, rest) = call.split_once("\\left(t-")?;
+    let offset = rest.strip_suffix("\\right)")?.parse().ok()?;
+    Some((section, offset))
+}
+
+/// Decodes the notes of the event-list encoding.
+///
+/// # Arguments
+/// * `formula` - Looks up a formula by name
+///
+/// # Returns
+/// * `(Vec<NoteEvent>, Option<usize>)` - Note events, and the largest number of
+///   decimal places of a note time, if there are notes
+///
+/// # Errors
+/// * If one of the lists is missing, not a list of numbers, or of a different length
+fn decode_event_list<'a>(
+    formula: &impl Fn(&str) -> Result<&'a Expression, MidiError>,
+) -> Result<(Vec<NoteEvent>, Option<usize>), MidiError> {
+    let list = |name: &str| -> Result<Vec<Number>, MidiError> {
+        match formula(name)? {
+            Expression::Call(function, sections) if function == "join" => {
+                let mut joined = Vec::new();
+                for section in sections {
+                    let Expression::Variable(section) = section else {
+                        return Err(MidiError::InvalidFormula(format!(
+                            "{} joins a value that is not a list",
+                            name
+                        )));
+                    };
+                    joined.extend(number_list(formula(section)?, section)?);
+                }
+                Ok(joined)
+            }
+            expression => number_list(expression, name),
+        }
+    };
+    let starts = list("E_{start}")?;
+    let ends = list("E_{end}")?;
+    let notes = list("E_{note}")?;
+    let velocities = list("E_{velocity}")?;
+    let fonts = list("E_{font}")?;
+    if [&ends, &notes, &velocities, &fonts]
+        .iter()
+        .any(|column| column.len() != starts.len())
+    {
+        return Err(MidiError::InvalidFormula(
+            "The event lists have different lengths".to_string(),
+        ));
+    }
+
+    let precision = starts.iter().chain(&ends).map(Number::decimals).max();
+    let mut note_changes: Vec<NoteEvent> = Vec::new();
+    for i in 0..starts.len() {
+        let start = micros(starts[i].value());
+        let values = [notes[i].value(), velocities[i].value(), fonts[i].value()];
+        let note = decode_note(&values, start, micros(ends[i].value()))?;
+        match note_changes.last_mut() {
+            Some(event) if event.timestamp == start => event.notes.push(note),
+            _ => note_changes.push(NoteEvent {
+                timestamp: start,
+                notes: vec![note],
+            }),
+        }
+    }
+    note_changes.sort_by_key(|event| event.timestamp);
+    Ok((note_changes, precision))
+}
+
+/// Builds a note from its relative note, velocity, and soundfont index.
+///
+/// Relative notes between semitones are decoded as the nearest note with a pitch bend.
+///
+/// # Arguments
+/// * `values` - Relative note, velocity, and soundfont index
+/// * `start` - Time in microseconds when the note starts
+/// * `end` - Time in microseconds when the note ends
+///
+/// # Errors
+/// * If the note, velocity, or soundfont index is out of range
+fn decode_note(values: &[f64; 3], start: Timestamp, end: Timestamp) -> Result<Note, MidiError> {
+    let [relative, velocity, soundfont] = *values;
+    let note = relative.round();
+    if !(0.0..=127.0).contains(&(note + A4)) {
+        return Err(MidiError::InvalidFormula(format!(
+            "Relative note {} is out of the MIDI range",
+            relative
+        )));
+    }
+    if !(0.0..=Velocity::MAX as f64).contains(&velocity) || velocity.fract() != 0.0 {
+        return Err(MidiError::InvalidFormula(format!(
+            "Velocity {} is not a whole number between 0 and {}",
+            velocity,
+            Velocity::MAX
+        )));
+    }
+    if soundfont < 0.0 || soundfont.fract() != 0.0 {
+        return Err(MidiError::InvalidFormula(format!(
+            "Soundfont index {} is not a whole number",
+            soundfont
+        )));
+    }
+
+    Ok(Note {
+        note: (note + A4) as MidiNote,
+        bend: (relative - note) as f32,
+        velocity: velocity as Velocity,
+        soundfont: soundfont as usize,
+        channel: 0,
+        program: 0,
+        track: 0,
+        attack: true,
+        onset: start as i64,
+        releases: true,
+        envelope: None,
+        end,
+    })
+}
+
+/// Returns the numbers of a list.
+///
+/// # Arguments
+/// * `expression` - List of numbers
+/// * `name` - Name of the formula, for errors
+///
+/// # Errors
+/// * If the expression is not a list of numbers
+fn number_list(expression: &Expression, name: &str) -> Result<Vec<Number>, MidiError> {
+    let Expression::List(items) = expression else {
+        return Err(MidiError::InvalidFormula(format!("{} is not a list", name)));
+    };
+    items
+        .iter()
+        .map(|item| match item {
+            Expression::Number(number) => Ok(*number),
+            _ => Err(MidiError::InvalidFormula(format!(
+                "{} has a value that is not a number",
+                name
+            ))),
+        })
+        .collect()
+}
+
+/// Returns the values of a list of numbers.
+fn numbers(expression: &Expression, name: &str) -> Result<Vec<f64>, MidiError> {
+    Ok(number_list(expression, name)?
+        .iter()
+        .map(Number::value)
+        .collect())
+}
+
+/// Converts a time in seconds to microseconds.
+fn micros(seconds: f64) -> Timestamp {
+    (seconds * US_PER_SECOND).round().max(0.0) as Timestamp
+}
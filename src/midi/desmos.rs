@@ -0,0 +1,465 @@
+use super::types::MidiError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Maximum number of elements in a Desmos list
+pub const MAX_LIST_LENGTH: usize = 10000;
+
+/// Number in a Desmos expression
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    /// Written with the fewest digits that represent the value (e.g. `-8.5`, `100`)
+    Shortest(f64),
+    /// Written with a fixed number of decimal places (e.g. `1.000`)
+    Fixed(f64, usize),
+}
+
+impl Number {
+    /// Returns the value of the number.
+    pub fn value(&self) -> f64 {
+        match *self {
+            Number::Shortest(value) | Number::Fixed(value, _) => value,
+        }
+    }
+
+    /// Returns the number of decimal places the number is written with.
+    pub fn decimals(&self) -> usize {
+        match *self {
+            Number::Shortest(_) => {
+                let written = self.to_string();
+                written
+                    .find('.')
+                    .map_or(0, |point| written.len() - point - 1)
+            }
+            Number::Fixed(_, decimals) => decimals,
+        }
+    }
+}
+
+impl From<f32> for Number {
+    /// Keeps the shortest decimal form of the `f32`, rather than the extra digits
+    /// it gains when widened to an `f64`.
+    fn from(value: f32) -> Self {
+        Number::Shortest(value.to_string().parse().unwrap_or(value as f64))
+    }
+}
+
+impl From<u8> for Number {
+    fn from(value: u8) -> Self {
+        Number::Shortest(value as f64)
+    }
+}
+
+impl From<usize> for Number {
+    fn from(value: usize) -> Self {
+        Number::Shortest(value as f64)
+    }
+}
+
+/// Piece of a piecewise expression, selected while `t` is below its end time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Piece {
+    /// Time in seconds until which the piece is selected
+    pub end: Number,
+    /// Value of the expression during the piece
+    pub value: Expression,
+}
+
+/// Expression on the right-hand side of a Desmos assignment
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// A single number
+    Number(Number),
+    /// A list of expressions
+    List(Vec<Expression>),
+    /// Conditions on `t`, checked in order; the first piece whose end is after `t` is used
+    Piecewise(Vec<Piece>),
+    /// Reference to another assignment, written in Desmos LaTeX (e.g. `A_{1}`)
+    Variable(String),
+    /// Call of a built-in Desmos function by name (e.g. `join`)
+    Call(String, Vec<Expression>),
+    /// Desmos LaTeX written as is, for expressions such as list filters that the
+    /// other variants do not describe
+    Latex(String),
+}
+
+/// Desmos expression assigned to a variable, such as `A=\left[1,2\right]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    /// Name of the variable in Desmos LaTeX (e.g. `A` or `A_{1}`)
+    pub name: String,
+    /// Value assigned to the variable
+    pub value: Expression,
+}
+
+impl Assignment {
+    /// Creates an assignment.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the variable in Desmos LaTeX
+    /// * `value` - Value assigned to the variable
+    pub fn new<S: Into<String>>(name: S, value: Expression) -> Self {
+        Self {
+            name: name.into(),
+            value,
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            // Adding zero turns -0 into 0
+            Number::Shortest(value) => write!(f, "{}", value + 0.0),
+            Number::Fixed(value, decimals) => write!(f, "{:.*}", decimals, value),
+        }
+    }
+}
+
+impl fmt::Display for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "t<{}:{}", self.end, self.value)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Number(number) => write!(f, "{}", number),
+            Expression::List(items) => {
+                f.write_str("\\left[")?;
+                write_joined(f, items)?;
+                f.write_str("\\right]")
+            }
+            Expression::Piecewise(pieces) => {
+                f.write_str("\\left\\{")?;
+                write_joined(f, pieces)?;
+                f.write_str("\\right\\}")
+            }
+            Expression::Variable(name) => f.write_str(name),
+            Expression::Call(function, arguments) => {
+                write!(f, "\\operatorname{{{}}}\\left(", function)?;
+                write_joined(f, arguments)?;
+                f.write_str("\\right)")
+            }
+            Expression::Latex(latex) => f.write_str(latex),
+        }
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Writes items separated by commas.
+fn write_joined<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(",")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+/// Serializes assignments to Desmos LaTeX, one per line.
+///
+/// # Arguments
+/// * `assignments` - Assignments to serialize
+///
+/// # Returns
+/// * `String` - Newline-separated Desmos formulas
+pub fn format_latex(assignments: &[Assignment]) -> String {
+    assignments
+        .iter()
+        .map(Assignment::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Checks that assignments form a valid set of Desmos formulas.
+///
+/// # Arguments
+/// * `assignments` - Assignments to check
+///
+/// # Errors
+/// * If a variable is assigned more than once
+/// * If an expression refers to a variable that is not assigned
+/// * If a number is not finite
+/// * If the end times of a piecewise expression are not increasing
+pub fn validate_expressions(assignments: &[Assignment]) -> Result<(), MidiError> {
+    let mut names = HashSet::new();
+    for assignment in assignments {
+        if !names.insert(assignment.name.as_str()) {
+            return Err(MidiError::InvalidExpression(format!(
+                "{} is assigned more than once",
+                assignment.name
+            )));
+        }
+    }
+
+    for assignment in assignments {
+        validate_expression(&assignment.value, &names).map_err(|message| {
+            MidiError::InvalidExpression(format!("{}: {}", assignment.name, message))
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks a single expression and the expressions nested in it.
+///
+/// # Arguments
+/// * `expression` - Expression to check
+/// * `names` - Names of all assigned variables
+///
+/// # Returns
+/// * `Result<(), String>` - Description of the first problem found
+fn validate_expression(expression: &Expression, names: &HashSet<&str>) -> Result<(), String> {
+    match expression {
+        Expression::Number(number) => validate_number(number),
+        Expression::List(items) => items
+            .iter()
+            .try_for_each(|item| validate_expression(item, names)),
+        Expression::Piecewise(pieces) => {
+            for (i, piece) in pieces.iter().enumerate() {
+                validate_number(&piece.end)?;
+                if i > 0 && piece.end.value() <= pieces[i - 1].end.value() {
+                    return Err(format!(
+                        "piece ending at {} does not come after the piece ending at {}",
+                        piece.end,
+                        pieces[i - 1].end
+                    ));
+                }
+                validate_expression(&piece.value, names)?;
+            }
+            Ok(())
+        }
+        Expression::Variable(name) if !names.contains(name.as_str()) => {
+            Err(format!("{} is not assigned", name))
+        }
+        Expression::Variable(_) => Ok(()),
+        Expression::Call(_, arguments) => arguments
+            .iter()
+            .try_for_each(|argument| validate_expression(argument, names)),
+        // Written as is, so there is nothing to check
+        Expression::Latex(_) => Ok(()),
+    }
+}
+
+/// Finds the assignments whose lists are longer than Desmos allows.
+///
+/// Lists joined with `join` are measured as a whole, since splitting a formula
+/// into sections does not lift the limit on the joined list. A piecewise
+/// expression is measured by its longest piece.
+///
+/// # Arguments
+/// * `assignments` - Assignments to check
+///
+/// # Returns
+/// * `Vec<(String, usize)>` - Name and length of every list longer than [`MAX_LIST_LENGTH`]
+pub fn oversized_lists(assignments: &[Assignment]) -> Vec<(String, usize)> {
+    let values: HashMap<&str, &Expression> = assignments
+        .iter()
+        .map(|assignment| (assignment.name.as_str(), &assignment.value))
+        .collect();
+    assignments
+        .iter()
+        .filter_map(|assignment| {
+            list_length(&assignment.value, &values, assignments.len())
+                .filter(|&length| length > MAX_LIST_LENGTH)
+                .map(|length| (assignment.name.clone(), length))
+        })
+        .collect()
+}
+
+/// Returns the number of elements of the list an expression evaluates to.
+///
+/// # Arguments
+/// * `expression` - Expression to measure
+/// * `values` - Values of all assigned variables
+/// * `depth` - Number of variables that may still be followed, so that
+///   variables referring to each other are not followed forever
+///
+/// # Returns
+/// * `Option<usize>` - Length of the list, or `None` if the expression is not a
+///   list or its length cannot be told without evaluating it
+fn list_length(
+    expression: &Expression,
+    values: &HashMap<&str, &Expression>,
+    depth: usize,
+) -> Option<usize> {
+    match expression {
+        Expression::List(items) => Some(items.len()),
+        Expression::Piecewise(pieces) => pieces
+            .iter()
+            .filter_map(|piece| list_length(&piece.value, values, depth))
+            .max(),
+        Expression::Variable(name) if depth > 0 => values
+            .get(name.as_str())
+            .and_then(|value| list_length(value, values, depth - 1)),
+        Expression::Call(function, arguments) if function == "join" => arguments
+            .iter()
+            .map(|argument| match argument {
+                Expression::Number(_) => Some(1),
+                _ => list_length(argument, values, depth),
+            })
+            .sum(),
+        _ => None,
+    }
+}
+
+/// Checks that a number can be written in a formula.
+fn validate_number(number: &Number) -> Result<(), String> {
+    match number.value().is_finite() {
+        true => Ok(()),
+        false => Err(format!("{} is not a finite number", number)),
+    }
+}
+
+/// Parses Desmos formulas, one per line, into assignments.
+///
+/// This is the inverse of [`format_latex`]: numbers, lists, piecewise expressions
+/// of `t`, variables, and calls of built-in functions are parsed into their
+/// variants, and values the other variants do not describe (such as list filters)
+/// are kept as [`Expression::Latex`]. Blank lines are skipped.
+///
+/// # Arguments
+/// * `text` - Newline-separated Desmos formulas
+///
+/// # Returns
+/// * `Vec<Assignment>` - Parsed formulas, in order
+///
+/// # Errors
+/// * If a line is not an assignment
+pub fn parse_latex(text: &str) -> Result<Vec<Assignment>, MidiError> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, value) = line.split_once('=').ok_or_else(|| {
+                MidiError::InvalidExpression(format!("Expected an assignment but got \"{}\"", line))
+            })?;
+            let value = match parse_value(value) {
+                Some((expression, "")) => expression,
+                _ => Expression::Latex(value.to_string()),
+            };
+            Ok(Assignment::new(name, value))
+        })
+        .collect()
+}
+
+/// Parses the value at the start of some LaTeX.
+///
+/// # Returns
+/// * `Option<(Expression, &str)>` - Value and the text after it, or `None` if no value
+///   starts the text
+fn parse_value(text: &str) -> Option<(Expression, &str)> {
+    if let Some(rest) = text.strip_prefix("\\left\\{") {
+        let (pieces, rest) = parse_items(rest, "\\right\\}", parse_piece)?;
+        return Some((Expression::Piecewise(pieces), rest));
+    }
+    if let Some(rest) = text.strip_prefix("\\left[") {
+        let (items, rest) = parse_items(rest, "\\right]", parse_value)?;
+        return Some((Expression::List(items), rest));
+    }
+    if let Some(rest) = text.strip_prefix("\\operatorname{") {
+        let (function, rest) = rest.split_once('}')?;
+        let rest = rest.strip_prefix("\\left(")?;
+        let (arguments, rest) = parse_items(rest, "\\right)", parse_value)?;
+        return Some((Expression::Call(function.to_string(), arguments), rest));
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') {
+        let (number, rest) = parse_number(text)?;
+        return Some((Expression::Number(number), rest));
+    }
+    if text.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Some(parse_variable(text));
+    }
+    None
+}
+
+/// Parses a piece written as `t<END:VALUE`.
+fn parse_piece(text: &str) -> Option<(Piece, &str)> {
+    let (end, rest) = parse_number(text.strip_prefix("t<")?)?;
+    let (value, rest) = parse_value(rest.strip_prefix(':')?)?;
+    Some((Piece { end, value }, rest))
+}
+
+/// Parses comma-separated items up to a closing delimiter.
+///
+/// # Arguments
+/// * `text` - Text after the opening delimiter
+/// * `close` - Closing delimiter
+/// * `parse_item` - Parses one item and returns the text after it
+///
+/// # Returns
+/// * `Option<(Vec<T>, &str)>` - Items and the text after the closing delimiter
+fn parse_items<'a, T>(
+    mut text: &'a str,
+    close: &str,
+    parse_item: impl Fn(&'a str) -> Option<(T, &'a str)>,
+) -> Option<(Vec<T>, &'a str)> {
+    let mut items = Vec::new();
+    if let Some(rest) = text.strip_prefix(close) {
+        return Some((items, rest));
+    }
+    loop {
+        let (item, rest) = parse_item(text)?;
+        items.push(item);
+        if let Some(rest) = rest.strip_prefix(close) {
+            return Some((items, rest));
+        }
+        text = rest.strip_prefix(',')?;
+    }
+}
+
+/// Parses the number at the start of some LaTeX.
+///
+/// Numbers that are not written in their shortest form (e.g. `1.000`) are parsed
+/// as [`Number::Fixed`], so they are written the same way again.
+fn parse_number(text: &str) -> Option<(Number, &str)> {
+    let length = text
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (c == '-' && i == 0)))
+        .map_or(text.len(), |(i, _)| i);
+    let (written, rest) = text.split_at(length);
+    let value: f64 = written.parse().ok()?;
+
+    let shortest = Number::Shortest(value);
+    let number = match written.split_once('.') {
+        Some((_, decimals)) if shortest.to_string() != written => {
+            Number::Fixed(value, decimals.len())
+        }
+        _ => shortest,
+    };
+    Some((number, rest))
+}
+
+/// Parses the variable at the start of some LaTeX, such as `A` or `A_{1}`.
+///
+/// A variable applied to arguments, such as `A_{2}\left(t-150\right)`, is kept
+/// as LaTeX up to the closing parenthesis.
+fn parse_variable(text: &str) -> (Expression, &str) {
+    let mut length = text
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(text.len());
+    if text[length..].starts_with("_{") {
+        length = text[length..]
+            .find('}')
+            .map_or(text.len(), |close| length + close + 1);
+    }
+    let (name, rest) = text.split_at(length);
+
+    if rest.starts_with("\\left(") {
+        if let Some(close) = rest.find("\\right)") {
+            let length = length + close + "\\right)".len();
+            return (
+                Expression::Latex(text[..length].to_string()),
+                &text[length..],
+            );
+        }
+    }
+    (Expression::Variable(name.to_string()), rest)
+}
@@ -0,0 +1,269 @@
+use super::desmos::Assignment;
+use super::types::MidiError;
+use serde::Serialize;
+use std::path::Path;
+
+/// Version of the Desmos calculator state format
+const STATE_VERSION: u32 = 11;
+
+/// Complete Desmos calculator state, as accepted by the Desmos API `setState`
+#[derive(Serialize)]
+struct GraphState {
+    version: u32,
+    graph: GraphSettings,
+    expressions: ExpressionList,
+}
+
+/// Settings of the graph paper
+#[derive(Serialize)]
+struct GraphSettings {
+    viewport: Viewport,
+}
+
+/// Visible region of the graph paper
+#[derive(Serialize)]
+struct Viewport {
+    xmin: f64,
+    ymin: f64,
+    xmax: f64,
+    ymax: f64,
+}
+
+/// Expressions of the graph along with its ticker
+#[derive(Serialize)]
+struct ExpressionList {
+    list: Vec<StateItem>,
+    ticker: Ticker,
+}
+
+/// Ticker that advances the song time while it is playing
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Ticker {
+    handler_latex: String,
+    min_step_latex: String,
+    open: bool,
+    playing: bool,
+}
+
+/// Entry in the expression list
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StateItem {
+    Folder {
+        id: String,
+        title: String,
+        collapsed: bool,
+    },
+    Expression {
+        id: String,
+        #[serde(rename = "folderId", skip_serializing_if = "Option::is_none")]
+        folder_id: Option<String>,
+        latex: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        slider: Option<Slider>,
+    },
+}
+
+/// Slider bounds of a setting
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Slider {
+    hard_min: bool,
+    hard_max: bool,
+    min: String,
+    max: String,
+    step: String,
+}
+
+/// Player settings shown as sliders: (variable, default, min, max, step)
+///
+/// - `velocity`: Animation phase speed of the waveform
+/// - `scale`: Visual amplitude of the waveform
+/// - `hertz`: Frequency of A4
+/// - `detune`: Detune of the secondary tone in cents
+/// - `volume`: Global volume
+/// - `transpose`: Global pitch shift in semitones
+const SETTINGS: [(&str, &str, &str, &str, &str); 6] = [
+    ("v_{elocity}", "1", "0", "10", "0.1"),
+    ("s_{cale}", "1", "0", "5", "0.1"),
+    ("h_{ertz}", "440", "400", "480", "1"),
+    ("d_{etune}", "5", "0", "100", "1"),
+    ("v_{olume}", "0.5", "0", "1", "0.01"),
+    ("t_{ranspose}", "0", "-24", "24", "1"),
+];
+
+/// Expressions that play the song from the generated A/B/C formulas.
+///
+/// `A` holds a flat list of (relative note, velocity, soundfont index) triples,
+/// `B` the harmonic weights of every soundfont, and `C` the size of each soundfont.
+/// `A` is padded with silent notes up to `N_{voices}`, and one tone is played for
+/// every harmonic of every voice, along with a detuned copy, each at half the gain.
+const PLAYER: [&str; 9] = [
+    "a_{notes}=\\operatorname{join}\\left(A,0\\cdot\\left[1...3N_{voices}\\right]\\right)",
+    "k=\\left[0...N_{voices}C-1\\right]",
+    "i_{voice}=\\operatorname{floor}\\left(\\frac{k}{C}\\right)+1",
+    "h_{armonic}=\\operatorname{mod}\\left(k,C\\right)+1",
+    "f_{req}=h_{ertz}h_{armonic}\\cdot2^{\\frac{a_{notes}\\left[3i_{voice}-2\\right]+t_{ranspose}}{12}}",
+    "g_{ain}=\\frac{v_{olume}a_{notes}\\left[3i_{voice}-1\\right]}{254}B\\left[Ca_{notes}\\left[3i_{voice}\\right]+h_{armonic}\\right]",
+    "\\operatorname{tone}\\left(f_{req},g_{ain}\\right)",
+    "\\operatorname{tone}\\left(f_{req}\\cdot2^{\\frac{d_{etune}}{1200}},g_{ain}\\right)",
+    "y=s_{cale}\\operatorname{total}\\left(g_{ain}\\sin\\left(2\\pi f_{req}\\frac{x+v_{elocity}t}{1000}\\right)\\right)",
+];
+
+/// Creates a Desmos calculator state that plays a song.
+///
+/// The state contains the song time `t` with actions to start and stop the song,
+/// a ticker that advances `t`, the player settings and expressions, and the
+/// song's formulas.
+///
+/// # Arguments
+/// * `song` - Formulas of the song (`A` and the formulas it is built from, `B`, and `C`)
+/// * `voices` - Largest number of notes that play at once
+///
+/// # Returns
+/// * `String` - Calculator state as JSON
+///
+/// # Errors
+/// * If the state cannot be serialized
+pub fn graph_state(song: &[Assignment], voices: usize) -> Result<String, MidiError> {
+    let mut list = vec![
+        expression("time", None, "t=0"),
+        expression("play", None, "t\\to0"),
+        expression("stop", None, "t\\to\\infty"),
+        folder("settings", "Settings"),
+    ];
+    for (name, value, min, max, step) in SETTINGS {
+        list.push(StateItem::Expression {
+            id: name.replace(['_', '{', '}'], ""),
+            folder_id: Some("settings".to_string()),
+            latex: format!("{}={}", name, value),
+            slider: Some(Slider {
+                hard_min: true,
+                hard_max: true,
+                min: min.to_string(),
+                max: max.to_string(),
+                step: step.to_string(),
+            }),
+        });
+    }
+
+    list.push(folder("player", "Player"));
+    list.push(expression(
+        "voices",
+        Some("player"),
+        &format!("N_{{voices}}={}", voices.max(1)),
+    ));
+    for (i, latex) in PLAYER.iter().enumerate() {
+        list.push(expression(&format!("player{}", i), Some("player"), latex));
+    }
+
+    list.push(folder("song", "Song"));
+    for assignment in song {
+        let id = assignment.name.replace(['_', '{', '}'], "");
+        list.push(expression(&id, Some("song"), &assignment.to_string()));
+    }
+
+    let state = GraphState {
+        version: STATE_VERSION,
+        graph: GraphSettings {
+            viewport: Viewport {
+                xmin: -10.0,
+                ymin: -6.0,
+                xmax: 10.0,
+                ymax: 6.0,
+            },
+        },
+        expressions: ExpressionList {
+            list,
+            ticker: Ticker {
+                handler_latex: "t\\to t+\\frac{dt}{1000}".to_string(),
+                min_step_latex: "0".to_string(),
+                open: true,
+                playing: true,
+            },
+        },
+    };
+    Ok(serde_json::to_string(&state)?)
+}
+
+/// Creates a collapsed folder.
+fn folder(id: &str, title: &str) -> StateItem {
+    StateItem::Folder {
+        id: id.to_string(),
+        title: title.to_string(),
+        collapsed: true,
+    }
+}
+
+/// Creates an expression without a slider.
+fn expression(id: &str, folder_id: Option<&str>, latex: &str) -> StateItem {
+    StateItem::Expression {
+        id: id.to_string(),
+        folder_id: folder_id.map(str::to_string),
+        latex: latex.to_string(),
+        slider: None,
+    }
+}
+
+/// Page that plays a calculator state, with `{{title}}`, `{{api_key}}`, and `{{state}}`
+/// placeholders
+const PLAYER_PAGE: &str = include_str!("player.html");
+
+/// Demo API key from the Desmos API documentation, used by player pages when no
+/// key is given.
+///
+/// Desmos only allows this key for trying out the API, so pages that are shared
+/// should be created with a key obtained from Desmos instead.
+pub const DESMOS_DEMO_API_KEY: &str = "dcb31709b452b1cf9dc26972add0fda6";
+
+/// Creates a standalone HTML page that plays a song.
+///
+/// The page loads the Desmos API and the state created by [`graph_state`], and
+/// has play and stop buttons that set the song time `t`, so the song can be
+/// played by opening the file in a browser.
+///
+/// # Arguments
+/// * `song` - Formulas of the song (`A` and the formulas it is built from, `B`, and `C`)
+/// * `voices` - Largest number of notes that play at once
+/// * `title` - Title shown on the page
+/// * `api_key` - Desmos API key the page loads the calculator with
+///
+/// # Returns
+/// * `String` - HTML document
+///
+/// # Errors
+/// * If the state cannot be serialized
+pub fn html_player(
+    song: &[Assignment],
+    voices: usize,
+    title: &str,
+    api_key: &str,
+) -> Result<String, MidiError> {
+    // Keep the JSON from closing the script element it is embedded in
+    let state = graph_state(song, voices)?.replace("</", "<\\/");
+    Ok(PLAYER_PAGE
+        .replace("{{state}}", &state)
+        .replace("{{api_key}}", &escape_html(api_key))
+        .replace("{{title}}", &escape_html(title)))
+}
+
+/// Returns the title of a song, taken from the name of its MIDI file without the
+/// extension.
+///
+/// # Arguments
+/// * `midi_path` - Path or name of the MIDI file
+pub fn song_title(midi_path: &str) -> String {
+    Path::new(midi_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| midi_path.to_string())
+}
+
+/// Escapes text for use in HTML content.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
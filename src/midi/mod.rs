@@ -1,16 +1,39 @@
+mod decoder;
+mod desmos;
+mod graph;
+mod optimize;
 mod parser;
+mod render;
 mod soundfonts;
 mod timing;
 mod types;
 
-pub use soundfonts::{get_instrument_name, parse_soundfont_file, soundfont_exists};
+pub use decoder::{decode_expressions, decode_formula};
+pub use desmos::{
+    format_latex, oversized_lists, parse_latex, validate_expressions, Assignment, Expression,
+    Number, Piece, MAX_LIST_LENGTH,
+};
+pub use graph::{song_title, DESMOS_DEMO_API_KEY};
+pub use optimize::optimize_expressions;
+pub use render::{render_samples, render_wav};
+pub use soundfonts::{
+    get_instrument_name, parse_soundfont_file, parse_soundfont_with_envelope, soundfont_exists,
+};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
-pub use types::{MidiError, ProcessedSong};
+use types::SoundFontMap;
+pub use types::{
+    Encoding, Envelope, MidiError, Note, NoteEvent, ProcessedSong, ProcessingOptions,
+    ProcessingStats, QuantizeGrid, Rearticulation, RenderOptions, SoundfontMapping, SoundfontSlot,
+    Track, TrimPoint, VoiceStacking, VoiceStealing, MAX_TIME_PRECISION,
+};
 
 /// MIDI processor for handling MIDI file processing
 pub struct MidiProcessor {
     soundfont_dir: Option<String>,
+    options: ProcessingOptions,
+    mapping: SoundfontMapping,
 }
 
 impl MidiProcessor {
@@ -18,6 +41,8 @@ impl MidiProcessor {
     pub fn new() -> Self {
         Self {
             soundfont_dir: None,
+            options: ProcessingOptions::default(),
+            mapping: SoundfontMapping::default(),
         }
     }
 
@@ -25,9 +50,30 @@ impl MidiProcessor {
     pub fn with_soundfont_dir<P: Into<String>>(soundfont_dir: P) -> Self {
         Self {
             soundfont_dir: Some(soundfont_dir.into()),
+            options: ProcessingOptions::default(),
+            mapping: SoundfontMapping::default(),
         }
     }
 
+    /// Sets the options used when processing note events.
+    ///
+    /// # Arguments
+    /// * `options` - Options controlling note processing
+    pub fn with_options(mut self, options: ProcessingOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets how soundfonts are assigned to notes.
+    ///
+    /// # Arguments
+    /// * `mapping` - Whether soundfonts are given per channel, per channel program, per program,
+    ///   or per track
+    pub fn with_soundfont_mapping(mut self, mapping: SoundfontMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
     /// Parses a MIDI file and returns channel information.
     ///
     /// This is a lightweight parse that only extracts channel and instrument information,
@@ -40,86 +86,98 @@ impl MidiProcessor {
     /// * `ProcessedSong` - Song information with only channel data (no notes)
     ///
     /// # Errors
+    /// * If the processing options are invalid
     /// * If the file cannot be read
     /// * If the MIDI file is invalid
     pub fn process_info(&self, midi_path: &str) -> Result<ProcessedSong, MidiError> {
+        self.options.validate()?;
         let midi_data = fs::read(midi_path)?;
-        parser::parse_midi(&midi_data, true)
+        parser::parse_midi(&midi_data, true, &self.options)
     }
 
     /// Processes a MIDI file with soundfont information.
     ///
     /// This function:
     /// 1. Reads the MIDI file
-    /// 2. Extracts channel information
-    /// 3. Validates the number of soundfonts matches channels
-    /// 4. Maps channels to soundfonts
+    /// 2. Extracts channel, program, and track information
+    /// 3. Validates the number of soundfonts matches the slots of the soundfont mapping
+    /// 4. Maps slots to soundfonts
     /// 5. Processes note events with soundfont assignments
     ///
     /// # Arguments
     /// * `midi_path` - Path to the MIDI file
-    /// * `soundfont_files` - Vector of soundfont filenames to use
-    ///                     If only one is provided, it's used for all channels
+    /// * `soundfont_files` - Vector of soundfont filenames to use, in the order given by
+    ///   [`SoundfontMapping::slots`]. If only one is provided, it's used for all slots
     ///
     /// # Returns
     /// * `ProcessedSong` - Fully processed song with notes and soundfonts
     ///
     /// # Errors
+    /// * If the processing options are invalid
     /// * If the file cannot be read
     /// * If the MIDI file is invalid
-    /// * If the number of soundfonts doesn't match the number of channels
+    /// * If the number of soundfonts doesn't match the number of slots
     /// * If any soundfont file cannot be read or is invalid
     pub fn process_with_soundfonts(
         &self,
         midi_path: &str,
         mut soundfont_files: Vec<String>,
     ) -> Result<ProcessedSong, MidiError> {
+        self.options.validate()?;
         let midi_data = fs::read(midi_path)?;
 
         // First parse MIDI to get channel info
-        let info_song = parser::parse_midi(&midi_data, true)?;
-        let channel_count = info_song.channels.len();
+        let info_song = parser::parse_midi(&midi_data, true, &self.options)?;
+        let slots = self.mapping.slots(&info_song);
+        let slot_count = slots.len();
 
-        // Validate soundfont count matches channel count
+        // Validate soundfont count matches slot count
         if soundfont_files.len() == 1 {
-            // If only one soundfont provided, duplicate it for all channels
+            // If only one soundfont provided, duplicate it for all slots
             let default_font = soundfont_files[0].clone();
-            soundfont_files = vec![default_font; channel_count];
-        } else if soundfont_files.len() < channel_count {
+            soundfont_files = vec![default_font; slot_count];
+        } else if soundfont_files.len() < slot_count {
             return Err(MidiError::SoundfontMismatch(format!(
-                "Not enough soundfonts provided. Need {} for channels, got {}",
-                channel_count,
+                "Not enough soundfonts provided. Need {} for {}, got {}",
+                slot_count,
+                self.mapping.describe(),
                 soundfont_files.len()
             )));
-        } else if soundfont_files.len() > channel_count {
+        } else if soundfont_files.len() > slot_count {
             return Err(MidiError::SoundfontMismatch(format!(
-                "Too many soundfonts provided. Need {} for channels, got {}",
-                channel_count,
+                "Too many soundfonts provided. Need {} for {}, got {}",
+                slot_count,
+                self.mapping.describe(),
                 soundfont_files.len()
             )));
         }
 
-        // Create a mapping of channel ID to soundfont index
-        let mut channel_to_index = vec![None; 16]; // MIDI has 16 possible channels
-        let mut active_soundfonts = Vec::with_capacity(channel_count);
+        // Create a mapping of slot to soundfont index
+        let mut slot_to_index = HashMap::with_capacity(slot_count);
+        let mut active_soundfonts = Vec::with_capacity(slot_count);
+        let mut envelopes = Vec::with_capacity(slot_count);
 
-        // Load all soundfonts and create channel mapping
+        // Load all soundfonts and create slot mapping
         let soundfont_dir = self.soundfont_dir.as_ref().map(Path::new);
 
-        for (_idx, (channel, soundfont_file)) in info_song
-            .channels
-            .iter()
-            .zip(soundfont_files.iter())
-            .enumerate()
-        {
-            if let Some(soundfont) = parse_soundfont_file(soundfont_file, soundfont_dir)? {
-                channel_to_index[channel.id as usize] = Some(active_soundfonts.len());
+        for (slot, soundfont_file) in slots.into_iter().zip(soundfont_files.iter()) {
+            if let Some((soundfont, envelope)) =
+                parse_soundfont_with_envelope(soundfont_file, soundfont_dir)?
+            {
+                slot_to_index.insert(slot, active_soundfonts.len());
                 active_soundfonts.push(soundfont);
+                envelopes.push(envelope);
             }
         }
 
-        // Now parse MIDI with soundfonts and channel mapping
-        parser::parse_midi_with_soundfonts(&midi_data, active_soundfonts, channel_to_index)
+        // Now parse MIDI with soundfonts and slot mapping
+        parser::parse_midi_with_soundfonts(
+            &midi_data,
+            SoundFontMap::new(active_soundfonts).with_envelopes(envelopes),
+            self.mapping,
+            slot_to_index,
+            &self.options,
+        )
     }
 
     /// Verifies that all soundfont files exist.
@@ -143,6 +201,12 @@ impl MidiProcessor {
     }
 }
 
+impl Default for MidiProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Legacy functions for backward compatibility
 /// Parses a MIDI file and returns channel information.
 #[allow(dead_code)]
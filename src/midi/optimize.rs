@@ -0,0 +1,134 @@
+use super::desmos::{Assignment, Expression, Number, Piece};
+
+/// Shortens formulas without changing what they play.
+///
+/// The optimization:
+/// 1. Merges consecutive pieces with the same value into the later one
+/// 2. Writes every number without trailing zeros (e.g. `1` instead of `1.000`)
+/// 3. Turns sections of a split formula into functions of the time since a whole
+///    second before they start, wherever that makes the formulas shorter, so their
+///    piece ends are written with fewer digits (e.g. `A_{2}\left(t-156\right)`
+///    with `t<1.316` instead of `t<157.316`)
+///
+/// # Arguments
+/// * `assignments` - Formulas to optimize
+///
+/// # Returns
+/// * `Vec<Assignment>` - Optimized formulas, in the same order
+pub fn optimize_expressions(assignments: Vec<Assignment>) -> Vec<Assignment> {
+    let mut assignments: Vec<Assignment> = assignments
+        .into_iter()
+        .map(|assignment| Assignment::new(assignment.name, shorten(assignment.value)))
+        .collect();
+    offset_sections(&mut assignments);
+    assignments
+}
+
+/// Merges equal consecutive pieces and trims the numbers of an expression.
+fn shorten(expression: Expression) -> Expression {
+    match expression {
+        Expression::Number(number) => Expression::Number(trim_number(number)),
+        Expression::List(items) => Expression::List(items.into_iter().map(shorten).collect()),
+        Expression::Piecewise(pieces) => {
+            let mut merged: Vec<Piece> = Vec::with_capacity(pieces.len());
+            for piece in pieces {
+                let piece = Piece {
+                    end: trim_number(piece.end),
+                    value: shorten(piece.value),
+                };
+                // The later piece starts where the earlier one did
+                match merged.last_mut() {
+                    Some(last) if last.value == piece.value => *last = piece,
+                    _ => merged.push(piece),
+                }
+            }
+            Expression::Piecewise(merged)
+        }
+        Expression::Call(function, arguments) => {
+            Expression::Call(function, arguments.into_iter().map(shorten).collect())
+        }
+        expression @ (Expression::Variable(_) | Expression::Latex(_)) => expression,
+    }
+}
+
+/// Writes a number with the fewest digits that keep the value it is written as.
+fn trim_number(number: Number) -> Number {
+    match number {
+        Number::Fixed(..) => Number::Shortest(number.to_string().parse().unwrap_or(number.value())),
+        Number::Shortest(_) => number,
+    }
+}
+
+/// Rewrites the sections selected by a main piecewise formula as functions of the
+/// time since their start, wherever that makes the formulas shorter.
+///
+/// A main formula is a piecewise formula whose pieces are all variables, each of
+/// which is another piecewise formula. Every section after the first is offset by
+/// the whole seconds before the end of the previous section.
+///
+/// # Arguments
+/// * `assignments` - Formulas to rewrite in place
+fn offset_sections(assignments: &mut [Assignment]) {
+    for main_index in 0..assignments.len() {
+        let Expression::Piecewise(main_pieces) = &assignments[main_index].value else {
+            continue;
+        };
+        let sections: Option<Vec<(String, f64)>> = main_pieces
+            .iter()
+            .enumerate()
+            .map(|(i, piece)| match &piece.value {
+                Expression::Variable(name) => {
+                    let start = i
+                        .checked_sub(1)
+                        .map_or(0.0, |previous| main_pieces[previous].end.value().floor());
+                    Some((name.clone(), start))
+                }
+                _ => None,
+            })
+            .collect();
+        let Some(sections) = sections else {
+            continue;
+        };
+
+        for (piece_index, (name, offset)) in sections.into_iter().enumerate() {
+            let Some(section_index) = assignments.iter().position(|a| a.name == name) else {
+                continue;
+            };
+            let Expression::Piecewise(pieces) = &assignments[section_index].value else {
+                continue;
+            };
+            if offset <= 0.0 {
+                continue;
+            }
+
+            let shifted = Assignment::new(
+                format!("{}\\left(t\\right)", name),
+                Expression::Piecewise(
+                    pieces
+                        .iter()
+                        .map(|piece| Piece {
+                            end: shift(&piece.end, offset),
+                            value: piece.value.clone(),
+                        })
+                        .collect(),
+                ),
+            );
+            let call = Expression::Latex(format!("{}\\left(t-{}\\right)", name, offset));
+
+            let original_length = assignments[section_index].to_string().len() + name.len();
+            let shifted_length = shifted.to_string().len() + call.to_string().len();
+            if shifted_length < original_length {
+                assignments[section_index] = shifted;
+                if let Expression::Piecewise(main_pieces) = &mut assignments[main_index].value {
+                    main_pieces[piece_index].value = call;
+                }
+            }
+        }
+    }
+}
+
+/// Moves a piece end earlier by an offset, keeping the decimal places it is written with.
+fn shift(end: &Number, offset: f64) -> Number {
+    let shifted = Number::Fixed(end.value() - offset, end.decimals());
+    trim_number(shifted)
+}
@@ -1,18 +1,45 @@
-use super::timing::ticks_to_ms;
+use super::timing::{bar_beat_to_ticks, grid_line_time, nearest_grid_line, ticks_to_us};
 use super::types::{
-    Channel, MidiError, MidiNote, NoteEvent, ProcessedSong, SoundFontMap, TempoChange, TempoMap,
-    Timestamp, Velocity,
+    Channel, MidiError, MidiNote, Note, NoteEvent, ProcessedSong, ProcessingOptions,
+    ProcessingStats, QuantizeGrid, Rearticulation, SoundFontMap, SoundfontMapping, SoundfontSlot,
+    TempoChange, TempoMap, TempoTable, TimeSignatureChange, Timestamp, Track, TrimPoint, Velocity,
+    VoiceStacking, VoiceStealing, DRUM_CHANNEL, US_PER_MS,
 };
 use midly::{Smf, TrackEventKind};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-const DRUM_CHANNEL: u8 = 9; // MIDI channel 10 (0-based)
+/// A MIDI message with its absolute time in ticks, its track index, and its channel
+type TimedMidiEvent = (u64, usize, u8, midly::MidiMessage);
+
+/// A MIDI message with its time in microseconds, its track index, and its channel
+type TimestampedMidiEvent = (Timestamp, usize, u8, midly::MidiMessage);
+
+/// Sounding instances of each (note, channel), oldest first
+type ActiveNotes = HashMap<(MidiNote, u8), Vec<ActiveNote>>;
+
+/// Position of a note segment in the note changes: (start, index in its group, end)
+type SegmentLocation = (Timestamp, usize, Timestamp);
+
+/// Note struck once, made of the segments between its pitch bends and volume changes
+struct Voice {
+    /// Position of every segment: (index of its note event, index in the event)
+    segments: Vec<(usize, usize)>,
+    /// Time in microseconds when the note is struck
+    start: Timestamp,
+    /// Time in microseconds when the note stops sounding
+    end: Timestamp,
+    /// MIDI channel the note was played on
+    channel: u8,
+    /// Pitch of the note in semitones, including its pitch bend
+    pitch: f32,
+}
 
 /// Parses a MIDI file and extracts note events and channel information.
 ///
 /// # Arguments
 /// * `midi_data` - Raw MIDI file data
 /// * `info_only` - If true, only parse channel information and return empty note events
+/// * `options` - Options controlling note processing
 ///
 /// # Returns
 /// * `ProcessedSong` - Parsed MIDI data including notes, channels, and a dummy soundfont
@@ -20,89 +47,148 @@ const DRUM_CHANNEL: u8 = 9; // MIDI channel 10 (0-based)
 /// # Errors
 /// * If the MIDI file is invalid
 /// * If the timing format is unsupported
-pub fn parse_midi(midi_data: &[u8], info_only: bool) -> Result<ProcessedSong, MidiError> {
+/// * If the options measure time in note values or bars but the file uses SMPTE timecode
+/// * If a trim point is past the end of its bar, or the end does not come after the start
+pub fn parse_midi(
+    midi_data: &[u8],
+    info_only: bool,
+    options: &ProcessingOptions,
+) -> Result<ProcessedSong, MidiError> {
     // Parse the MIDI file
     let smf = Smf::parse(midi_data)?;
 
-    // Get the ticks per quarter note from the MIDI header
-    let ticks_per_quarter = extract_ticks_per_quarter(&smf)?;
+    // Create the tempo map from the timing format in the MIDI header
+    let tempo_map = create_tempo_map(&smf)?;
 
-    // Extract tempo changes and channel information
-    let (tempo_map, channels, all_events) = extract_midi_metadata(&smf, ticks_per_quarter)?;
+    // Extract tempo changes, channel, and track information
+    let (tempo_map, channels, tracks, all_events) = extract_midi_metadata(&smf, tempo_map)?;
 
     // If we only need channel info, return early with empty notes
     if info_only {
         return Ok(ProcessedSong {
             note_changes: Vec::new(),
             channels,
+            tracks,
             soundfonts: SoundFontMap::new(vec![vec![1.0]]), // Dummy soundfont
+            stats: ProcessingStats::default(),
+            envelope_resolution: options.envelope_resolution,
+            time_precision: options.time_precision,
+            encoding: options.encoding,
+            optimize: options.optimize,
         });
     }
 
+    // Note values and bars have no length in timecode files, which have no tempo
+    check_timecode_options(&tempo_map, options)?;
+
     // Process note events
-    let note_events = process_note_events(all_events, &tempo_map);
+    let (mut note_events, stats) = process_note_events(all_events, &tempo_map, options);
+
+    // Keep only the notes between the trim points
+    if options.trim_start.is_some() || options.trim_end.is_some() {
+        let start = match options.trim_start {
+            Some(point) => trim_point_time(point, &tempo_map)?,
+            None => 0,
+        };
+        let end = match options.trim_end {
+            Some(point) => Some(trim_point_time(point, &tempo_map)?),
+            None => None,
+        };
+        if end.is_some_and(|end| end <= start) {
+            return Err(MidiError::InvalidTrim(
+                "The end of the song must come after its start".to_string(),
+            ));
+        }
+        note_events = trim_notes(note_events, start, end);
+    }
 
     Ok(ProcessedSong {
         note_changes: note_events,
         channels,
+        tracks,
         soundfonts: SoundFontMap::new(vec![vec![1.0]]), // Will be replaced by parse_midi_with_soundfonts
+        stats,
+        envelope_resolution: options.envelope_resolution,
+        time_precision: options.time_precision,
+        encoding: options.encoding,
+        optimize: options.optimize,
     })
 }
 
-/// Extracts the ticks per quarter note from the MIDI file header
+/// Creates an initial tempo map from the timing format in the MIDI file header
+///
+/// Metrical files get a default 120 BPM map that is later filled with tempo changes.
+/// SMPTE timecode files get a fixed map derived from the frame rate and subframes.
 ///
 /// # Arguments
 /// * `smf` - Parsed MIDI file
 ///
 /// # Returns
-/// * `u32` - Ticks per quarter note
+/// * `TempoMap` - Tempo map for the file
 ///
 /// # Errors
-/// * If the timing format is unsupported
-fn extract_ticks_per_quarter(smf: &Smf) -> Result<u32, MidiError> {
+/// * If the timing format has a zero time division
+fn create_tempo_map(smf: &Smf) -> Result<TempoMap, MidiError> {
     match smf.header.timing {
-        midly::Timing::Metrical(ticks) => Ok(ticks.as_int() as u32),
+        midly::Timing::Metrical(ticks) if ticks.as_int() > 0 => {
+            Ok(TempoMap::new(ticks.as_int() as u32))
+        }
+        midly::Timing::Timecode(fps, subframes) if subframes > 0 => {
+            Ok(TempoMap::from_timecode(fps, subframes))
+        }
         _ => Err(MidiError::UnsupportedTimingFormat),
     }
 }
 
-/// Extracts tempo changes, channel information, and note events from a MIDI file
+/// Extracts tempo changes, channel and track information, and note events from a MIDI file
 ///
 /// # Arguments
 /// * `smf` - Parsed MIDI file
-/// * `ticks_per_quarter` - Ticks per quarter note
+/// * `tempo_map` - Initial tempo map created from the file header
 ///
 /// # Returns
-/// * `(TempoMap, Vec<Channel>, Vec<(u64, u8, midly::MidiMessage)>)` -
-///   Tempo map, channels, and note events
+/// * `(TempoMap, Vec<Channel>, Vec<Track>, Vec<TimedMidiEvent>)` - Tempo map, channels,
+///   tracks, and note events
+#[allow(clippy::type_complexity)]
 fn extract_midi_metadata(
     smf: &Smf,
-    ticks_per_quarter: u32,
-) -> Result<(TempoMap, Vec<Channel>, Vec<(u64, u8, midly::MidiMessage)>), MidiError> {
+    mut tempo_map: TempoMap,
+) -> Result<(TempoMap, Vec<Channel>, Vec<Track>, Vec<TimedMidiEvent>), MidiError> {
     // Initialize data structures
-    let mut tempo_map = TempoMap::new(ticks_per_quarter);
     let mut all_events = Vec::new();
     let mut tempo_changes = Vec::new();
+    let mut time_signatures = Vec::new();
     let mut channels = HashMap::new();
+    let mut tracks = Vec::new();
     let mut channel_instruments = HashMap::new();
 
     // First pass: collect all events and tempo changes with absolute timestamps
     collect_events_from_tracks(
         smf,
         &mut tempo_changes,
+        &mut time_signatures,
         &mut all_events,
         &mut channels,
+        &mut tracks,
         &mut channel_instruments,
     );
 
-    // Sort and process tempo changes
-    process_tempo_changes(&mut tempo_map, &mut tempo_changes);
+    // Sort and process tempo changes (timecode files have a fixed tempo)
+    if !tempo_map.fixed_tempo {
+        process_tempo_changes(&mut tempo_map, &mut tempo_changes);
+    }
+    time_signatures.sort_by_key(|change| change.tick);
+    tempo_map.time_signatures = time_signatures;
+
+    // Sort events by time and record the programs each channel plays notes with
+    all_events.sort_by_key(|(time, _, _, _)| *time);
+    collect_channel_programs(&all_events, &mut channels);
 
     // Convert channels HashMap to Vec, sorted by channel ID
     let mut channel_vec: Vec<Channel> = channels.into_values().collect();
     channel_vec.sort_by_key(|c| c.id);
 
-    Ok((tempo_map, channel_vec, all_events))
+    Ok((tempo_map, channel_vec, tracks, all_events))
 }
 
 /// Collects events from all tracks in the MIDI file
@@ -110,40 +196,65 @@ fn extract_midi_metadata(
 /// # Arguments
 /// * `smf` - Parsed MIDI file
 /// * `tempo_changes` - Collection to store tempo changes
+/// * `time_signatures` - Collection to store time signature changes
 /// * `all_events` - Collection to store MIDI events
 /// * `channels` - Collection to store channel information
+/// * `tracks` - Collection to store information on tracks with MIDI messages
 /// * `channel_instruments` - Collection to track instruments for each channel
 fn collect_events_from_tracks(
     smf: &Smf,
     tempo_changes: &mut Vec<TempoChange>,
-    all_events: &mut Vec<(u64, u8, midly::MidiMessage)>,
+    time_signatures: &mut Vec<TimeSignatureChange>,
+    all_events: &mut Vec<TimedMidiEvent>,
     channels: &mut HashMap<u8, Channel>,
+    tracks: &mut Vec<Track>,
     channel_instruments: &mut HashMap<u8, u8>,
 ) {
-    for track in smf.tracks.iter() {
+    for (index, track) in smf.tracks.iter().enumerate() {
         let mut track_time: u64 = 0;
+        let mut track_info = Track {
+            index,
+            name: None,
+            channels: Vec::new(),
+        };
         for event in track {
             track_time += u64::from(event.delta.as_int());
             match event.kind {
+                // Only the first non-empty name counts, later ones are usually markers
+                TrackEventKind::Meta(midly::MetaMessage::TrackName(name))
+                    if track_info.name.is_none() =>
+                {
+                    let name = String::from_utf8_lossy(name).trim().to_string();
+                    track_info.name = Some(name).filter(|name| !name.is_empty());
+                }
                 TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo_val)) => {
                     tempo_changes.push(TempoChange {
                         tick: track_time,
                         tempo: tempo_val.as_int(),
                     });
                 }
+                TrackEventKind::Meta(midly::MetaMessage::TimeSignature(
+                    numerator,
+                    denominator,
+                    _,
+                    _,
+                )) => {
+                    // The denominator is stored as a power of two
+                    time_signatures.push(TimeSignatureChange {
+                        tick: track_time,
+                        numerator,
+                        denominator: 1u32.checked_shl(denominator.into()).unwrap_or(4),
+                    });
+                }
                 TrackEventKind::Midi { channel, message } => {
                     let ch = channel.as_int();
                     // Record any channel that has MIDI messages
-                    if !channels.contains_key(&ch) {
-                        channels.insert(
-                            ch,
-                            Channel {
-                                id: ch,
-                                instrument: *channel_instruments.get(&ch).unwrap_or(&0), // Default to piano
-                                is_drum: ch == DRUM_CHANNEL,
-                            },
-                        );
-                    }
+                    channels.entry(ch).or_insert_with(|| Channel {
+                        id: ch,
+                        instrument: *channel_instruments.get(&ch).unwrap_or(&0), // Default to piano
+                        programs: Vec::new(),
+                        is_drum: ch == DRUM_CHANNEL,
+                    });
                     // Update instrument if we see a program change
                     if let midly::MidiMessage::ProgramChange { program } = message {
                         channel_instruments.insert(ch, program.as_int());
@@ -151,11 +262,48 @@ fn collect_events_from_tracks(
                             channel.instrument = program.as_int();
                         }
                     }
-                    all_events.push((track_time, ch, message));
+                    if !track_info.channels.contains(&ch) {
+                        track_info.channels.push(ch);
+                    }
+                    all_events.push((track_time, index, ch, message));
                 }
                 _ => {}
             }
         }
+
+        // Only tracks that send MIDI messages can be assigned a soundfont
+        if !track_info.channels.is_empty() {
+            track_info.channels.sort();
+            tracks.push(track_info);
+        }
+    }
+}
+
+/// Records the programs that are active when notes start on each channel
+///
+/// Channels that play notes before any program change use program 0 for those notes.
+///
+/// # Arguments
+/// * `all_events` - MIDI events sorted by time
+/// * `channels` - Collection of channel information to update
+fn collect_channel_programs(all_events: &[TimedMidiEvent], channels: &mut HashMap<u8, Channel>) {
+    let mut programs = [0u8; 16];
+
+    for (_, _, channel, message) in all_events {
+        match message {
+            midly::MidiMessage::ProgramChange { program } => {
+                programs[*channel as usize] = program.as_int();
+            }
+            midly::MidiMessage::NoteOn { vel, .. } if vel.as_int() > 0 => {
+                let program = programs[*channel as usize];
+                if let Some(info) = channels.get_mut(channel) {
+                    if !info.programs.contains(&program) {
+                        info.programs.push(program);
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 }
 
@@ -164,13 +312,13 @@ fn collect_events_from_tracks(
 /// # Arguments
 /// * `tempo_map` - Tempo map to update
 /// * `tempo_changes` - Collection of tempo changes to process
-fn process_tempo_changes(tempo_map: &mut TempoMap, tempo_changes: &mut Vec<TempoChange>) {
+fn process_tempo_changes(tempo_map: &mut TempoMap, tempo_changes: &mut [TempoChange]) {
     // Sort tempo changes by time
     tempo_changes.sort_by_key(|change| change.tick);
 
     // Merge tempo changes that occur at the same tick, keeping the last one
     let mut last_tick = None;
-    for change in tempo_changes.clone() {
+    for change in tempo_changes.iter() {
         match last_tick {
             Some(tick) if tick == change.tick => {
                 // Replace the last tempo change at this tick
@@ -186,54 +334,242 @@ fn process_tempo_changes(tempo_map: &mut TempoMap, tempo_changes: &mut Vec<Tempo
     }
 }
 
+/// Registered parameter number for pitch bend sensitivity (MSB, LSB)
+const RPN_PITCH_BEND_SENSITIVITY: (u8, u8) = (0, 0);
+/// Value of a deselected (null) registered parameter number
+const RPN_NULL: (u8, u8) = (127, 127);
+/// Controller number of the channel volume
+const CC_VOLUME: u8 = 7;
+/// Controller number of the expression controller
+const CC_EXPRESSION: u8 = 11;
+/// Controller number of the sustain (damper) pedal
+const CC_SUSTAIN: u8 = 64;
+/// Controller number of the sostenuto pedal
+const CC_SOSTENUTO: u8 = 66;
+
+/// Controller state tracked for each MIDI channel while processing note events
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+    /// Raw pitch bend value, centered at 0 (-8192 to 8191)
+    pitch_bend: i16,
+    /// Pitch bend sensitivity in semitones (set through RPN 0)
+    bend_range: f32,
+    /// Currently selected registered parameter number (MSB, LSB)
+    rpn: (u8, u8),
+    /// Pitch bend offset in semitones currently applied to the channel's notes
+    bend_offset: f32,
+    /// Current program
+    program: u8,
+    /// Channel volume (CC7)
+    volume: u8,
+    /// Expression (CC11)
+    expression: u8,
+    /// Whether the sustain pedal is down
+    sustain: bool,
+    /// Whether the sostenuto pedal is down
+    sostenuto: bool,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            pitch_bend: 0,
+            bend_range: 2.0, // General MIDI default of +/- 2 semitones
+            rpn: RPN_NULL,
+            bend_offset: 0.0,
+            program: 0,      // Default to piano
+            volume: 100,     // General MIDI default channel volume
+            expression: 127, // Full expression
+            sustain: false,
+            sostenuto: false,
+        }
+    }
+}
+
+impl ChannelState {
+    /// Calculates the pitch bend offset in semitones, rounded to the given resolution.
+    ///
+    /// # Arguments
+    /// * `resolution` - Step size in semitones; 0 disables rounding
+    ///
+    /// # Returns
+    /// * `f32` - Pitch offset in semitones
+    fn bend_semitones(&self, resolution: f32) -> f32 {
+        let semitones = self.pitch_bend as f32 / 8192.0 * self.bend_range;
+        if resolution > 0.0 {
+            (semitones / resolution).round() * resolution
+        } else {
+            semitones
+        }
+    }
+
+    /// Scales a note velocity by the channel volume and expression.
+    ///
+    /// If channel volume handling is disabled, the velocity is returned unchanged.
+    /// Otherwise the scaled velocity is rounded to the configured step size. A note
+    /// is only silenced when the volume or expression is 0, and otherwise never
+    /// rounds down below 1, so that a quiet note is not mistaken for a note off.
+    ///
+    /// # Arguments
+    /// * `velocity` - Note-on velocity
+    /// * `options` - Options controlling channel volume handling
+    ///
+    /// # Returns
+    /// * `Velocity` - Velocity to emit for the note
+    fn scale_velocity(&self, velocity: Velocity, options: &ProcessingOptions) -> Velocity {
+        if !options.channel_volume {
+            return velocity;
+        }
+
+        let gain = (self.volume as f32 / 127.0) * (self.expression as f32 / 127.0);
+        let scaled = velocity as f32 * gain;
+        if gain == 0.0 {
+            return 0;
+        }
+        let step = options.volume_resolution.max(1) as f32;
+        ((scaled / step).round() * step).clamp(1.0, 127.0) as Velocity
+    }
+
+    /// Applies a controller message that affects the pitch or loudness of notes.
+    ///
+    /// Handles channel volume, expression, and pitch bend sensitivity (RPN 0).
+    /// Data entry for any other registered parameter is ignored.
+    ///
+    /// # Arguments
+    /// * `controller` - Controller number
+    /// * `value` - Controller value
+    fn handle_controller(&mut self, controller: u8, value: u8) {
+        match controller {
+            CC_VOLUME => self.volume = value,
+            CC_EXPRESSION => self.expression = value,
+            // RPN MSB / LSB select a registered parameter
+            101 => self.rpn.0 = value,
+            100 => self.rpn.1 = value,
+            // NRPN selection deselects any registered parameter
+            98 | 99 => self.rpn = RPN_NULL,
+            // Data entry MSB sets whole semitones
+            6 if self.rpn == RPN_PITCH_BEND_SENSITIVITY => {
+                self.bend_range = value as f32 + self.bend_range.fract();
+            }
+            // Data entry LSB sets cents
+            38 if self.rpn == RPN_PITCH_BEND_SENSITIVITY => {
+                self.bend_range = self.bend_range.trunc() + value.min(99) as f32 / 100.0;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A note that is currently sounding, tracked until its note-off
+#[derive(Debug, Clone, Copy)]
+struct ActiveNote {
+    /// Note-on velocity
+    velocity: Velocity,
+    /// Velocity of the current segment, scaled by channel volume and expression
+    level: Velocity,
+    /// Program active when the note started
+    program: u8,
+    /// Track that started the note
+    track: usize,
+    /// Start time of the note's current segment in microseconds
+    start: Timestamp,
+    /// Time in microseconds when the note was struck
+    onset: Timestamp,
+    /// Pitch bend offset in semitones for the current segment
+    bend: f32,
+    /// Whether the note's key was released while a pedal kept it sounding
+    released: bool,
+    /// Whether the note is held by the sostenuto pedal
+    sostenuto: bool,
+}
+
+impl ActiveNote {
+    /// Checks whether a pedal on the note's channel keeps the note sounding.
+    ///
+    /// # Arguments
+    /// * `state` - Controller state of the note's channel
+    fn is_held_by_pedal(&self, state: &ChannelState) -> bool {
+        state.sustain || (self.sostenuto && state.sostenuto)
+    }
+}
+
 /// Processes note events and converts them to NoteEvent structures.
 ///
 /// This function:
 /// 1. Tracks active notes and their start times
-/// 2. Converts MIDI ticks to milliseconds
-/// 3. Groups notes that start at the same time
-/// 4. Sorts events chronologically
+/// 2. Tracks per-channel pitch bend, splitting bent notes into segments
+/// 3. Scales velocities by channel volume and expression, if enabled
+/// 4. Extends released notes while the sustain or sostenuto pedal holds them
+/// 5. Stacks re-triggered notes so that overlapping instances are all kept
+/// 6. Marks the boundary of repeated notes, if enabled
+/// 7. Converts MIDI ticks to microseconds
+/// 8. Groups notes that start at the same time
+/// 9. Sorts events chronologically
 ///
 /// # Arguments
-/// * `all_events` - Collection of MIDI events
+/// * `all_events` - Collection of MIDI events sorted by time
 /// * `tempo_map` - Tempo map for timing conversion
+/// * `options` - Options controlling note processing
 ///
 /// # Returns
-/// * `Vec<NoteEvent>` - Processed note events
+/// * `(Vec<NoteEvent>, ProcessingStats)` - Processed note events and statistics
 fn process_note_events(
-    all_events: Vec<(u64, u8, midly::MidiMessage)>,
+    all_events: Vec<TimedMidiEvent>,
     tempo_map: &TempoMap,
-) -> Vec<NoteEvent> {
-    // Active notes being tracked: (Note, Channel) -> (Velocity, Start Time)
-    let mut active_notes: HashMap<(MidiNote, u8), (Velocity, Timestamp)> = HashMap::new();
+    options: &ProcessingOptions,
+) -> (Vec<NoteEvent>, ProcessingStats) {
+    // Active notes being tracked: (Note, Channel) -> Stacked instances of the note
+    let mut active_notes: ActiveNotes = HashMap::new();
+
+    // Statistics on stacked notes
+    let mut stats = ProcessingStats::default();
+
+    // Note changes grouped by start time: Start Time -> Vec<Note>
+    let mut note_changes: HashMap<Timestamp, Vec<Note>> = HashMap::new();
+
+    // Controller state for each of the 16 MIDI channels
+    let mut channel_states = [ChannelState::default(); 16];
 
-    // Note changes grouped by start time: Start Time -> Vec<(Note, Velocity, Channel, End Time)>
-    let mut note_changes: HashMap<Timestamp, Vec<(MidiNote, Velocity, usize, Timestamp)>> =
-        HashMap::new();
+    // Cumulative tempo table for converting event times
+    let tempo_table = TempoTable::new(tempo_map);
 
-    // Sort events by time
-    let mut sorted_events = all_events;
-    sorted_events.sort_by_key(|(time, _, _)| *time);
+    // Events were sorted by time when they were collected, and quantization keeps
+    // them sorted
+    let sorted_events: Vec<TimestampedMidiEvent> = match options.quantize {
+        Some(grid) => {
+            quantize_note_times(all_events, grid, options.quantize_strength, &tempo_table)
+        }
+        None => all_events
+            .into_iter()
+            .map(|(ticks, track, channel, message)| {
+                (ticks_to_us(ticks, &tempo_table), track, channel, message)
+            })
+            .collect(),
+    };
 
     // Find the last MIDI event time for proper song duration
     let last_event_time = sorted_events
         .last()
-        .map(|(time, _, _)| ticks_to_ms(*time, tempo_map))
+        .map(|(time, _, _, _)| *time)
         .unwrap_or(0);
 
     // Process each MIDI event
-    for (track_time, channel, message) in sorted_events {
-        let current_time = ticks_to_ms(track_time, tempo_map);
+    for (current_time, track, channel, message) in sorted_events {
+        let state = &mut channel_states[channel as usize];
 
         match message {
             midly::MidiMessage::NoteOn { key, vel } => {
                 handle_note_on(
                     key.as_int(),
                     vel.as_int(),
+                    track,
                     channel,
                     current_time,
+                    state,
+                    options,
                     &mut active_notes,
                     &mut note_changes,
+                    &mut stats,
                 );
             }
             midly::MidiMessage::NoteOff { key, .. } => {
@@ -241,18 +577,85 @@ fn process_note_events(
                     key.as_int(),
                     channel,
                     current_time,
+                    state,
+                    options,
+                    &mut active_notes,
+                    &mut note_changes,
+                );
+            }
+            midly::MidiMessage::ProgramChange { program } => {
+                // Sounding notes keep the program they started with
+                state.program = program.as_int();
+            }
+            midly::MidiMessage::PitchBend { bend } => {
+                state.pitch_bend = bend.as_int();
+                handle_channel_change(
+                    channel,
+                    current_time,
+                    state,
+                    options,
                     &mut active_notes,
                     &mut note_changes,
                 );
             }
+            midly::MidiMessage::Controller { controller, value } => {
+                let pressed = value.as_int() >= 64;
+                match controller.as_int() {
+                    CC_SUSTAIN if options.sustain_pedal => {
+                        state.sustain = pressed;
+                        release_pedalled_notes(
+                            channel,
+                            current_time,
+                            state,
+                            &mut active_notes,
+                            &mut note_changes,
+                        );
+                    }
+                    CC_SOSTENUTO if options.sostenuto_pedal => {
+                        handle_sostenuto_pedal(
+                            channel,
+                            pressed,
+                            current_time,
+                            state,
+                            &mut active_notes,
+                            &mut note_changes,
+                        );
+                    }
+                    controller => {
+                        // Volume, expression, and bend range changes affect sounding notes
+                        state.handle_controller(controller, value.as_int());
+                        handle_channel_change(
+                            channel,
+                            current_time,
+                            state,
+                            options,
+                            &mut active_notes,
+                            &mut note_changes,
+                        );
+                    }
+                }
+            }
             _ => {}
         }
     }
 
     // Handle any still-active notes by ending them at the last event time
-    for ((note, channel), (velocity, start_time)) in active_notes {
-        let changes = note_changes.entry(start_time).or_default();
-        changes.push((note, velocity, channel as usize, last_event_time));
+    for ((note, channel), stack) in active_notes {
+        for active in &stack {
+            finish_segment(
+                note,
+                channel,
+                active,
+                last_event_time,
+                true,
+                &mut note_changes,
+            );
+        }
+    }
+
+    // Keep repeated notes from merging into one long note
+    if options.rearticulation != Rearticulation::None {
+        apply_rearticulation(&mut note_changes, options);
     }
 
     // Convert the note_changes map to a sorted vector of NoteEvent objects
@@ -262,70 +665,569 @@ fn process_note_events(
         .collect();
     events.sort_by_key(|event| event.timestamp);
 
+    (events, stats)
+}
+
+/// Checks that options measuring time in note values or bars can be used with a file.
+///
+/// Files using SMPTE timecode are timed in video frames rather than in beats, so
+/// note values and bars have no length in them.
+///
+/// # Arguments
+/// * `tempo_map` - Tempo map of the file
+/// * `options` - Options controlling note processing
+///
+/// # Errors
+/// * If the file uses SMPTE timecode and notes are quantized to a grid of note values
+/// * If the file uses SMPTE timecode and is trimmed at a bar and beat
+fn check_timecode_options(
+    tempo_map: &TempoMap,
+    options: &ProcessingOptions,
+) -> Result<(), MidiError> {
+    if !tempo_map.fixed_tempo {
+        return Ok(());
+    }
+    if let Some(QuantizeGrid::Note { .. }) = options.quantize {
+        return Err(MidiError::InvalidGrid(
+            "SMPTE timecode files have no tempo, so they must be quantized to a grid of \
+             milliseconds"
+                .to_string(),
+        ));
+    }
+    let trim_points = [options.trim_start, options.trim_end];
+    if trim_points
+        .iter()
+        .any(|point| matches!(point, Some(TrimPoint::BarBeat { .. })))
+    {
+        return Err(MidiError::InvalidTrim(
+            "SMPTE timecode files have no bars, so they must be trimmed in seconds or ticks"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves note-ons and note-offs towards the nearest line of a quantization grid.
+///
+/// A note-off that would reach the grid line of the latest note-on of the same
+/// note, or one before it, is moved towards the line after it instead, so notes
+/// keep some length. Other events, such as pitch bends, pedals, and program
+/// changes, keep their time but stay between the note events of their channel
+/// around them, so notes are still played with the controllers set before them.
+///
+/// # Arguments
+/// * `all_events` - Collection of MIDI events sorted by time
+/// * `grid` - Quantization grid
+/// * `strength` - How far note times are moved towards the grid, between 0 and 1
+/// * `tempo_table` - Tempo changes throughout the song with their elapsed times
+///
+/// # Returns
+/// * `Vec<TimestampedMidiEvent>` - Events with their times in microseconds, sorted
+///   by time and otherwise in their original order
+fn quantize_note_times(
+    all_events: Vec<TimedMidiEvent>,
+    grid: QuantizeGrid,
+    strength: f32,
+    tempo_table: &TempoTable,
+) -> Vec<TimestampedMidiEvent> {
+    let strength = strength as f64;
+
+    // Grid line of the latest note-on of each (note, channel)
+    let mut onsets: HashMap<(MidiNote, u8), u64> = HashMap::new();
+    let mut events: Vec<(TimestampedMidiEvent, bool)> = all_events
+        .into_iter()
+        .map(|(ticks, track, channel, message)| {
+            let time = ticks_to_us(ticks, tempo_table);
+            let line = match message {
+                midly::MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                    let line = nearest_grid_line(grid, ticks, time, tempo_table);
+                    onsets.insert((key.as_int(), channel), line);
+                    Some(line)
+                }
+                midly::MidiMessage::NoteOn { key, .. }
+                | midly::MidiMessage::NoteOff { key, .. } => {
+                    let line = nearest_grid_line(grid, ticks, time, tempo_table);
+                    match onsets.get(&(key.as_int(), channel)) {
+                        Some(&onset) if line <= onset => Some(onset + 1),
+                        _ => Some(line),
+                    }
+                }
+                _ => None,
+            };
+
+            let time = line.map_or(time, |line| {
+                let target = grid_line_time(grid, line, tempo_table) as f64;
+                (time as f64 + (target - time as f64) * strength).round() as Timestamp
+            });
+            ((time, track, channel, message), line.is_some())
+        })
+        .collect();
+
+    // Keep the other events after the note events of their channel before them...
+    let mut latest_note: HashMap<u8, Timestamp> = HashMap::new();
+    for ((time, _, channel, _), is_note) in events.iter_mut() {
+        match (*is_note, latest_note.get(channel)) {
+            (true, _) => {
+                latest_note.insert(*channel, *time);
+            }
+            (false, Some(&latest)) => *time = (*time).max(latest),
+            (false, None) => {}
+        }
+    }
+    // ...and before the note events of their channel after them
+    let mut next_note: HashMap<u8, Timestamp> = HashMap::new();
+    for ((time, _, channel, _), is_note) in events.iter_mut().rev() {
+        match (*is_note, next_note.get(channel)) {
+            (true, _) => {
+                next_note.insert(*channel, *time);
+            }
+            (false, Some(&next)) => *time = (*time).min(next),
+            (false, None) => {}
+        }
+    }
+
+    // The sort is stable, so events at the same time stay in file order
+    let mut events: Vec<TimestampedMidiEvent> =
+        events.into_iter().map(|(event, _)| event).collect();
+    events.sort_by_key(|(time, _, _, _)| *time);
     events
 }
 
+/// Converts a trim point to a time in microseconds.
+///
+/// # Arguments
+/// * `point` - Trim point
+/// * `tempo_map` - Tempo map with the tempo and time signature changes of the song
+///
+/// # Returns
+/// * `Timestamp` - Time in microseconds
+///
+/// # Errors
+/// * If the beat of the trim point is past the end of its bar
+fn trim_point_time(point: TrimPoint, tempo_map: &TempoMap) -> Result<Timestamp, MidiError> {
+    Ok(match point {
+        TrimPoint::Seconds(seconds) => (seconds * 1_000_000.0).round() as Timestamp,
+        TrimPoint::BarBeat { bar, beat } => ticks_to_us(
+            bar_beat_to_ticks(bar, beat, tempo_map)?,
+            &TempoTable::new(tempo_map),
+        ),
+        TrimPoint::Ticks(ticks) => ticks_to_us(ticks, &TempoTable::new(tempo_map)),
+    })
+}
+
+/// Keeps the notes between two times, and times them from the first.
+///
+/// Notes sounding at the start are clipped to start there, without their attack,
+/// and notes sounding at the end are cut off there, without their release.
+///
+/// # Arguments
+/// * `note_events` - Note events sorted by time
+/// * `start` - Time in microseconds where the kept notes start
+/// * `end` - Time in microseconds where the kept notes end, if they are cut off
+///
+/// # Returns
+/// * `Vec<NoteEvent>` - Kept note events sorted by time
+fn trim_notes(
+    note_events: Vec<NoteEvent>,
+    start: Timestamp,
+    end: Option<Timestamp>,
+) -> Vec<NoteEvent> {
+    let end = end.unwrap_or(Timestamp::MAX);
+    let mut trimmed: BTreeMap<Timestamp, Vec<Note>> = BTreeMap::new();
+    for event in note_events {
+        for mut note in event.notes {
+            if note.end <= start || event.timestamp >= end {
+                continue;
+            }
+
+            let mut timestamp = event.timestamp;
+            if timestamp < start {
+                timestamp = start;
+                note.attack = false;
+            }
+            if note.sounding_end() > end {
+                note.end = note.end.min(end);
+                note.releases = false;
+            }
+
+            // Notes struck before the start keep the phase of their envelopes
+            note.onset -= start as i64;
+            note.end -= start;
+            trimmed.entry(timestamp - start).or_default().push(note);
+        }
+    }
+
+    trimmed
+        .into_iter()
+        .map(|(timestamp, notes)| NoteEvent { timestamp, notes })
+        .collect()
+}
+
 /// Handles a note-on event
 ///
+/// If the note is already sounding on the channel, the new instance is stacked on
+/// top of the existing ones rather than replacing them.
+///
 /// # Arguments
 /// * `note` - MIDI note number
 /// * `velocity` - Note velocity
+/// * `track` - Index of the track the event came from
 /// * `channel` - MIDI channel
-/// * `current_time` - Current time in milliseconds
+/// * `current_time` - Current time in microseconds
+/// * `state` - Controller state of the channel
+/// * `options` - Options controlling note processing
 /// * `active_notes` - Collection of active notes being tracked
 /// * `note_changes` - Collection of note changes grouped by start time
+/// * `stats` - Statistics on stacked notes
+#[allow(clippy::too_many_arguments)]
 fn handle_note_on(
     note: u8,
     velocity: u8,
+    track: usize,
     channel: u8,
     current_time: u64,
-    active_notes: &mut HashMap<(MidiNote, u8), (Velocity, Timestamp)>,
-    note_changes: &mut HashMap<Timestamp, Vec<(MidiNote, Velocity, usize, Timestamp)>>,
+    state: &ChannelState,
+    options: &ProcessingOptions,
+    active_notes: &mut ActiveNotes,
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
+    stats: &mut ProcessingStats,
 ) {
     let note_key = (note, channel);
 
     if velocity > 0 {
+        let stack = active_notes.entry(note_key).or_default();
+
+        // Striking a key again ends the instances the pedal was holding
+        stack.retain(|held| {
+            if held.released {
+                finish_segment(note, channel, held, current_time, true, note_changes);
+            }
+            !held.released
+        });
+
+        // Any instance still sounding is kept, and the new one stacks on top of it
+        if !stack.is_empty() {
+            stats.stacked_notes += 1;
+        }
+
         // Note on - record start time
-        active_notes.insert(note_key, (velocity, current_time));
+        stack.push(ActiveNote {
+            velocity,
+            level: state.scale_velocity(velocity, options),
+            program: state.program,
+            track,
+            start: current_time,
+            onset: current_time,
+            bend: state.bend_offset,
+            released: false,
+            sostenuto: false,
+        });
+        stats.max_stacked = stats.max_stacked.max(stack.len());
     } else {
         // Note on with velocity 0 is equivalent to note off
-        handle_note_off(note, channel, current_time, active_notes, note_changes);
+        handle_note_off(
+            note,
+            channel,
+            current_time,
+            state,
+            options,
+            active_notes,
+            note_changes,
+        );
     }
 }
 
 /// Handles a note-off event
 ///
+/// The note-off ends one stacked instance of the note whose key is still down,
+/// chosen by the voice stacking order. If a pedal is holding the note, it keeps
+/// sounding and is only marked as released.
+///
 /// # Arguments
 /// * `note` - MIDI note number
 /// * `channel` - MIDI channel
-/// * `current_time` - Current time in milliseconds
+/// * `current_time` - Current time in microseconds
+/// * `state` - Controller state of the channel
+/// * `options` - Options controlling voice stacking
 /// * `active_notes` - Collection of active notes being tracked
 /// * `note_changes` - Collection of note changes grouped by start time
 fn handle_note_off(
     note: u8,
     channel: u8,
     current_time: u64,
-    active_notes: &mut HashMap<(MidiNote, u8), (Velocity, Timestamp)>,
-    note_changes: &mut HashMap<Timestamp, Vec<(MidiNote, Velocity, usize, Timestamp)>>,
+    state: &ChannelState,
+    options: &ProcessingOptions,
+    active_notes: &mut ActiveNotes,
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
 ) {
     let note_key = (note, channel);
+    let Some(stack) = active_notes.get_mut(&note_key) else {
+        return;
+    };
+
+    // Find the instance this note-off belongs to among those whose key is down
+    let mut held = stack
+        .iter()
+        .enumerate()
+        .filter(|(_, active)| !active.released);
+    let index = match options.voice_stacking {
+        VoiceStacking::Fifo => held.next(),
+        VoiceStacking::Lifo => held.next_back(),
+    }
+    .map(|(index, _)| index);
+    let Some(index) = index else {
+        return;
+    };
+
+    // If a pedal is holding the note, let it keep sounding
+    if stack[index].is_held_by_pedal(state) {
+        stack[index].released = true;
+        return;
+    }
 
-    // If note was active, add it to changes with its duration
-    if let Some((velocity, start_time)) = active_notes.remove(&note_key) {
-        let changes = note_changes.entry(start_time).or_default();
-        changes.push((note, velocity, channel as usize, current_time));
+    // Add the instance to changes with its duration
+    let active = stack.remove(index);
+    finish_segment(note, channel, &active, current_time, true, note_changes);
+    if stack.is_empty() {
+        active_notes.remove(&note_key);
     }
 }
 
+/// Handles the sostenuto pedal being pressed or lifted.
+///
+/// Pressing the pedal holds only the notes whose keys are down at that moment.
+/// Lifting it ends any of those notes whose keys were released in the meantime.
+///
+/// # Arguments
+/// * `channel` - MIDI channel
+/// * `pressed` - Whether the pedal is down
+/// * `current_time` - Current time in microseconds
+/// * `state` - Controller state of the channel
+/// * `active_notes` - Collection of active notes being tracked
+/// * `note_changes` - Collection of note changes grouped by start time
+fn handle_sostenuto_pedal(
+    channel: u8,
+    pressed: bool,
+    current_time: u64,
+    state: &mut ChannelState,
+    active_notes: &mut ActiveNotes,
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
+) {
+    if pressed && !state.sostenuto {
+        for ((_, note_channel), stack) in active_notes.iter_mut() {
+            if *note_channel != channel {
+                continue;
+            }
+            for active in stack.iter_mut().filter(|active| !active.released) {
+                active.sostenuto = true;
+            }
+        }
+    }
+    state.sostenuto = pressed;
+
+    release_pedalled_notes(channel, current_time, state, active_notes, note_changes);
+}
+
+/// Ends every released note on a channel that is no longer held by a pedal.
+///
+/// # Arguments
+/// * `channel` - MIDI channel
+/// * `current_time` - Current time in microseconds
+/// * `state` - Controller state of the channel
+/// * `active_notes` - Collection of active notes being tracked
+/// * `note_changes` - Collection of note changes grouped by start time
+fn release_pedalled_notes(
+    channel: u8,
+    current_time: u64,
+    state: &ChannelState,
+    active_notes: &mut ActiveNotes,
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
+) {
+    active_notes.retain(|&(note, note_channel), stack| {
+        if note_channel != channel {
+            return true;
+        }
+        stack.retain_mut(|active| {
+            if !state.sostenuto {
+                active.sostenuto = false;
+            }
+            if active.released && !active.is_held_by_pedal(state) {
+                finish_segment(note, channel, active, current_time, true, note_changes);
+                return false;
+            }
+            true
+        });
+        !stack.is_empty()
+    });
+}
+
+/// Handles a change to a channel's pitch bend, bend range, volume, or expression.
+///
+/// Every active note on the channel whose rounded bend offset or scaled velocity
+/// changed ends its current segment and starts a new one with the new values.
+/// Segments shorter than the configured interval are updated in place instead, so
+/// that fast bend curves and swells don't produce a piece for every MIDI message.
+///
+/// # Arguments
+/// * `channel` - MIDI channel
+/// * `current_time` - Current time in microseconds
+/// * `state` - Controller state of the channel
+/// * `options` - Options controlling segment resolution
+/// * `active_notes` - Collection of active notes being tracked
+/// * `note_changes` - Collection of note changes grouped by start time
+fn handle_channel_change(
+    channel: u8,
+    current_time: u64,
+    state: &mut ChannelState,
+    options: &ProcessingOptions,
+    active_notes: &mut ActiveNotes,
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
+) {
+    let bend = state.bend_semitones(options.pitch_bend_resolution);
+    state.bend_offset = bend;
+
+    for ((note, note_channel), stack) in active_notes.iter_mut() {
+        if *note_channel != channel {
+            continue;
+        }
+        for active in stack.iter_mut() {
+            let level = state.scale_velocity(active.velocity, options);
+            if active.bend == bend && active.level == level {
+                continue;
+            }
+
+            if current_time > active.start
+                && current_time - active.start >= options.segment_interval * US_PER_MS
+            {
+                finish_segment(*note, channel, active, current_time, false, note_changes);
+                active.start = current_time;
+            }
+            active.bend = bend;
+            active.level = level;
+        }
+    }
+}
+
+/// Marks the boundary of every note that is struck again on the same channel.
+///
+/// A note is re-attacked when its attack falls within a segment of the same note
+/// on the same channel, or right at the end of one. Depending on the options, the
+/// segment ending at the re-attack is shortened to leave a silent gap (by at most
+/// half its length), or the re-attacked note starts with a short dip in velocity.
+///
+/// # Arguments
+/// * `note_changes` - Collection of note changes grouped by start time
+/// * `options` - Options controlling re-articulation
+fn apply_rearticulation(
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
+    options: &ProcessingOptions,
+) {
+    // Locate every segment by note and channel
+    let mut segments: HashMap<(MidiNote, u8), Vec<SegmentLocation>> = HashMap::new();
+    for (&start, notes) in note_changes.iter() {
+        for (index, note) in notes.iter().enumerate() {
+            segments
+                .entry((note.note, note.channel))
+                .or_default()
+                .push((start, index, note.end));
+        }
+    }
+
+    let duration = options.rearticulation_time * US_PER_MS;
+    for locations in segments.values() {
+        for &(time, index, end) in locations {
+            if !note_changes[&time][index].attack {
+                continue;
+            }
+            let previous: Vec<(Timestamp, usize)> = locations
+                .iter()
+                .filter(|&&(start, _, prev_end)| start < time && time <= prev_end)
+                .map(|&(start, index, _)| (start, index))
+                .collect();
+            if previous.is_empty() {
+                continue;
+            }
+
+            match options.rearticulation {
+                Rearticulation::Gap => {
+                    // Only notes ending at the re-attack can leave room for a gap
+                    for (start, prev_index) in previous {
+                        let prev = &mut note_changes.get_mut(&start).unwrap()[prev_index];
+                        if prev.end == time {
+                            prev.end = time - duration.min((time - start) / 2);
+                        }
+                    }
+                }
+                Rearticulation::Dip => {
+                    let note = &mut note_changes.get_mut(&time).unwrap()[index];
+                    let dipped = (note.velocity as f32 * options.rearticulation_dip).round();
+                    let velocity = dipped.clamp(0.0, 127.0) as Velocity;
+                    if end <= time + duration {
+                        note.velocity = velocity;
+                        continue;
+                    }
+
+                    // Split the note so that only its first moments are dipped
+                    let rest = Note {
+                        attack: false,
+                        ..note.clone()
+                    };
+                    note.velocity = velocity;
+                    note.end = time + duration;
+                    note.releases = false;
+                    note_changes.entry(time + duration).or_default().push(rest);
+                }
+                Rearticulation::None => {}
+            }
+        }
+    }
+}
+
+/// Ends the current segment of an active note and records it in the note changes.
+///
+/// # Arguments
+/// * `note` - MIDI note number
+/// * `channel` - MIDI channel
+/// * `active` - State of the active note
+/// * `end_time` - Time in microseconds when the segment ends
+/// * `releases` - Whether the note is released at the end of the segment
+/// * `note_changes` - Collection of note changes grouped by start time
+fn finish_segment(
+    note: u8,
+    channel: u8,
+    active: &ActiveNote,
+    end_time: Timestamp,
+    releases: bool,
+    note_changes: &mut HashMap<Timestamp, Vec<Note>>,
+) {
+    let changes = note_changes.entry(active.start).or_default();
+    changes.push(Note {
+        note,
+        bend: active.bend,
+        velocity: active.level,
+        soundfont: channel as usize,
+        channel,
+        program: active.program,
+        track: active.track,
+        attack: active.start == active.onset,
+        onset: active.onset as i64,
+        releases,
+        envelope: None,
+        end: end_time,
+    });
+}
+
 /// Parses a MIDI file with soundfont information.
 ///
 /// This function first parses the MIDI file normally, then updates the note events
-/// to use the provided soundfonts based on the channel mapping.
+/// to use the provided soundfonts based on the slot of each note.
 ///
 /// # Arguments
 /// * `midi_data` - Raw MIDI file data
-/// * `soundfonts` - Vector of soundfonts to use
-/// * `channel_to_index` - Mapping from channel numbers to soundfont indices
+/// * `soundfonts` - Soundfonts to use, with the envelopes they carry
+/// * `mapping` - How notes are assigned to soundfont slots
+/// * `slot_to_index` - Mapping from slots to soundfont indices
+/// * `options` - Options controlling note processing
 ///
 /// # Returns
 /// * `ProcessedSong` - Parsed MIDI data with soundfont information
@@ -335,13 +1237,27 @@ fn handle_note_off(
 /// * If the timing format is unsupported
 pub fn parse_midi_with_soundfonts(
     midi_data: &[u8],
-    soundfonts: Vec<Vec<f32>>,
-    channel_to_index: Vec<Option<usize>>,
+    soundfonts: SoundFontMap,
+    mapping: SoundfontMapping,
+    slot_to_index: HashMap<SoundfontSlot, usize>,
+    options: &ProcessingOptions,
 ) -> Result<ProcessedSong, MidiError> {
-    let mut song = parse_midi(midi_data, false)?;
+    let mut song = parse_midi(midi_data, false, options)?;
 
     // Update song with soundfont information
-    update_song_with_soundfonts(&mut song, soundfonts, channel_to_index);
+    update_song_with_soundfonts(&mut song, soundfonts, mapping, &slot_to_index, options);
+
+    // Start with the first note that is played
+    if options.strip_leading_silence {
+        if let Some(first) = song.note_changes.first().map(|event| event.timestamp) {
+            song.note_changes = trim_notes(std::mem::take(&mut song.note_changes), first, None);
+        }
+    }
+
+    // Only notes that are played count towards the polyphony limit
+    if let Some(limit) = options.max_polyphony {
+        limit_polyphony(&mut song, limit, options);
+    }
 
     Ok(song)
 }
@@ -350,25 +1266,36 @@ pub fn parse_midi_with_soundfonts(
 ///
 /// This function:
 /// 1. Updates soundfont indices in note events
-/// 2. Removes notes for channels without soundfonts
-/// 3. Removes empty note events
-/// 4. Updates the song's soundfont map
+/// 2. Assigns velocity envelopes from the channel settings or the soundfonts
+/// 3. Removes notes for slots without soundfonts
+/// 4. Removes empty note events
+/// 5. Updates the song's soundfont map
 ///
 /// # Arguments
 /// * `song` - Song to update
-/// * `soundfonts` - Vector of soundfonts to use
-/// * `channel_to_index` - Mapping from channel numbers to soundfont indices
+/// * `soundfonts` - Soundfonts to use, with the envelopes they carry
+/// * `mapping` - How notes are assigned to soundfont slots
+/// * `slot_to_index` - Mapping from slots to soundfont indices
+/// * `options` - Options with the per-channel envelopes
 fn update_song_with_soundfonts(
     song: &mut ProcessedSong,
-    soundfonts: Vec<Vec<f32>>,
-    channel_to_index: Vec<Option<usize>>,
+    soundfonts: SoundFontMap,
+    mapping: SoundfontMapping,
+    slot_to_index: &HashMap<SoundfontSlot, usize>,
+    options: &ProcessingOptions,
 ) {
-    // Update the soundfont indices in note events using the channel mapping
-    // and filter out notes for channels without soundfonts
+    // Update the soundfont indices in note events using the slot mapping
+    // and filter out notes for slots without soundfonts
     for event in &mut song.note_changes {
-        event.notes.retain_mut(|(_, _, soundfont_idx, _)| {
-            if let Some(new_idx) = channel_to_index[*soundfont_idx] {
-                *soundfont_idx = new_idx;
+        event.notes.retain_mut(|note| {
+            if let Some(&new_idx) = slot_to_index.get(&mapping.slot(note)) {
+                note.soundfont = new_idx;
+                // Channel settings take precedence over the soundfont's envelope
+                note.envelope = options
+                    .channel_envelopes
+                    .get(&note.channel)
+                    .copied()
+                    .or(soundfonts.envelopes[new_idx]);
                 true
             } else {
                 false
@@ -379,5 +1306,179 @@ fn update_song_with_soundfonts(
     // Remove any events that have no notes after filtering
     song.note_changes.retain(|event| !event.notes.is_empty());
 
-    song.soundfonts = SoundFontMap::new(soundfonts);
+    song.soundfonts = soundfonts;
+}
+
+/// Limits the number of notes that sound at once by stealing voices.
+///
+/// Notes are considered in the order they start. When a note starts while the
+/// limit is reached, the voice stealing policy picks a note among the sounding
+/// notes and the new one. A sounding note is cut short where the new note starts
+/// and loses its release, and a new note is left out, along with notes cut short
+/// where they start.
+///
+/// # Arguments
+/// * `song` - Song to limit, with the numbers of truncated and dropped notes
+///   added to its statistics
+/// * `limit` - Largest number of notes that sound at once
+/// * `options` - Options with the voice stealing policy and whether the limit
+///   applies to each channel separately
+fn limit_polyphony(song: &mut ProcessedSong, limit: usize, options: &ProcessingOptions) {
+    // Join the segments of every note, in the order the notes start. Instances of a
+    // note struck at the same time are told apart by their order in each event.
+    let mut voices: Vec<Voice> = Vec::new();
+    let mut instances: HashMap<(u8, MidiNote, usize, i64), Vec<usize>> = HashMap::new();
+    for (event_index, event) in song.note_changes.iter().enumerate() {
+        let mut occurrences: HashMap<(u8, MidiNote, usize, i64), usize> = HashMap::new();
+        for (note_index, note) in event.notes.iter().enumerate() {
+            let key = (note.channel, note.note, note.track, note.onset);
+            let occurrence = occurrences.entry(key).or_default();
+            let voice_indices = instances.entry(key).or_default();
+            if *occurrence == voice_indices.len() {
+                voice_indices.push(voices.len());
+                voices.push(Voice {
+                    segments: Vec::new(),
+                    start: event.timestamp,
+                    end: event.timestamp,
+                    channel: note.channel,
+                    pitch: note.note as f32 + note.bend,
+                });
+            }
+            let voice = &mut voices[voice_indices[*occurrence]];
+            *occurrence += 1;
+            voice.segments.push((event_index, note_index));
+            voice.end = voice.end.max(note.sounding_end());
+        }
+    }
+
+    // Time each stolen note is cut off at
+    let mut cuts: Vec<(usize, Timestamp)> = Vec::new();
+    let mut sounding: Vec<usize> = Vec::new();
+    for new in 0..voices.len() {
+        let start = voices[new].start;
+        sounding.retain(|&voice| voices[voice].end > start);
+
+        let mut candidates: Vec<usize> = sounding
+            .iter()
+            .copied()
+            .filter(|&voice| {
+                !options.polyphony_per_channel || voices[voice].channel == voices[new].channel
+            })
+            .collect();
+        if candidates.len() < limit {
+            sounding.push(new);
+            continue;
+        }
+
+        candidates.push(new);
+        let victim = steal_voice(song, &voices, &candidates, start, options.voice_stealing);
+        cuts.push((victim, start));
+        if victim != new {
+            voices[victim].end = start;
+            sounding.retain(|&voice| voice != victim);
+            sounding.push(new);
+        }
+    }
+
+    // Mark the segments to keep, and shorten the segments sounding at each cut
+    let mut keep: Vec<Vec<bool>> = song
+        .note_changes
+        .iter()
+        .map(|event| vec![true; event.notes.len()])
+        .collect();
+    for &(victim, time) in &cuts {
+        if voices[victim].start == time {
+            song.stats.dropped_notes += 1;
+        } else {
+            song.stats.truncated_notes += 1;
+        }
+        for &(event_index, note_index) in &voices[victim].segments {
+            let event = &mut song.note_changes[event_index];
+            let note = &mut event.notes[note_index];
+            if event.timestamp >= time {
+                keep[event_index][note_index] = false;
+            } else if note.sounding_end() > time {
+                note.end = note.end.min(time);
+                note.releases = false;
+            }
+        }
+    }
+
+    for (event, keep) in song.note_changes.iter_mut().zip(keep) {
+        let mut keep = keep.into_iter();
+        event.notes.retain(|_| keep.next().unwrap_or(true));
+    }
+    song.note_changes.retain(|event| !event.notes.is_empty());
+}
+
+/// Picks the note that gives up its voice.
+///
+/// # Arguments
+/// * `song` - Song the notes are part of
+/// * `voices` - All notes of the song
+/// * `candidates` - Indices of the notes that can be stolen, oldest first
+/// * `time` - Time in microseconds when the voice is needed
+/// * `policy` - Which note is stolen
+///
+/// # Returns
+/// * `usize` - Index of the stolen note
+fn steal_voice(
+    song: &ProcessedSong,
+    voices: &[Voice],
+    candidates: &[usize],
+    time: Timestamp,
+    policy: VoiceStealing,
+) -> usize {
+    // The first of the candidates with the lowest priority is stolen
+    let priority = |voice: usize| -> f32 {
+        match policy {
+            VoiceStealing::Quietest => loudness(song, &voices[voice], time) as f32,
+            VoiceStealing::Oldest => 0.0,
+            VoiceStealing::Inner => {
+                let pitch = voices[voice].pitch;
+                let pitches = candidates.iter().map(|&other| voices[other].pitch);
+                if pitches.clone().all(|other| other <= pitch) {
+                    2.0
+                } else if pitches.clone().all(|other| other >= pitch) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    };
+
+    candidates
+        .iter()
+        .copied()
+        .reduce(|stolen, voice| {
+            if priority(voice) < priority(stolen) {
+                voice
+            } else {
+                stolen
+            }
+        })
+        .unwrap_or(candidates[0])
+}
+
+/// Returns the velocity of a note at a time, shaped by its envelope.
+///
+/// # Arguments
+/// * `song` - Song the note is part of
+/// * `voice` - Note to measure
+/// * `time` - Time in microseconds
+///
+/// # Returns
+/// * `Velocity` - Velocity of the segment sounding at the time
+fn loudness(song: &ProcessedSong, voice: &Voice, time: Timestamp) -> Velocity {
+    voice
+        .segments
+        .iter()
+        .map(|&(event_index, note_index)| {
+            let event = &song.note_changes[event_index];
+            (event.timestamp, &event.notes[note_index])
+        })
+        .rev()
+        .find(|&(start, _)| start <= time)
+        .map_or(0, |(_, note)| note.velocity_at(time as f64))
 }
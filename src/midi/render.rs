@@ -0,0 +1,183 @@
+use super::types::{MidiError, ProcessedSong, RenderOptions};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::f64::consts::TAU;
+use std::ops::Range;
+use std::path::Path;
+
+/// Number of samples synthesized at a time when writing a WAV file
+const CHUNK_SAMPLES: usize = 1 << 16;
+
+/// Synthesizes a song the way the player graph plays it.
+///
+/// Every note is played as one tone per harmonic of its soundfont, along with a
+/// copy detuned by the detune setting. The gain of both tones is the volume times
+/// the note's velocity over 254 times the harmonic's weight, and the frequency of
+/// harmonic `h` of relative note `n` is `hertz * h * 2^((n + transpose) / 12)`.
+/// Notes start and end at their times as written to the formula, and tones are
+/// timed from the start of the song, so notes continuing across pieces keep their
+/// phase. Harmonics above half the sample rate are left out.
+///
+/// # Arguments
+/// * `song` - Song to render
+/// * `options` - Sample rate and player settings
+///
+/// # Returns
+/// * `Vec<f32>` - Mono samples between -1 and 1
+///
+/// # Errors
+/// * If the options are invalid
+pub fn render_samples(
+    song: &ProcessedSong,
+    options: &RenderOptions,
+) -> Result<Vec<f32>, MidiError> {
+    options.validate()?;
+    let renderer = Renderer::new(song, options);
+    Ok(renderer.render(0..renderer.length))
+}
+
+/// Renders a song to a 16-bit mono WAV file.
+///
+/// See [`render_samples`] for how the song is synthesized. The song is
+/// synthesized and written a chunk at a time, so long songs are not held in
+/// memory as a whole.
+///
+/// # Arguments
+/// * `song` - Song to render
+/// * `path` - Path of the WAV file to write
+/// * `options` - Sample rate and player settings
+///
+/// # Errors
+/// * If the options are invalid
+/// * If the file cannot be written
+pub fn render_wav(
+    song: &ProcessedSong,
+    path: &Path,
+    options: &RenderOptions,
+) -> Result<(), MidiError> {
+    options.validate()?;
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: options.sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    let renderer = Renderer::new(song, options);
+    for start in (0..renderer.length).step_by(CHUNK_SAMPLES) {
+        let end = (start + CHUNK_SAMPLES).min(renderer.length);
+        for sample in renderer.render(start..end) {
+            writer.write_sample((sample * i16::MAX as f32) as i16)?;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Tones of a song, ready to be synthesized over any span of samples
+struct Renderer {
+    /// First sample, end sample, frequency in cycles per sample, and gain of each tone
+    tones: Vec<(usize, usize, f64, f64)>,
+    /// Number of samples in the song
+    length: usize,
+}
+
+impl Renderer {
+    /// Lists the tones of every note of a song.
+    ///
+    /// # Arguments
+    /// * `song` - Song to render
+    /// * `options` - Sample rate and player settings
+    fn new(song: &ProcessedSong, options: &RenderOptions) -> Self {
+        let rate = options.sample_rate as f64;
+        let detune = 2f64.powf(options.detune / 1200.0);
+        let mut tones = Vec::new();
+        let mut length = 0;
+        for row in song.event_rows() {
+            let start = sample_index(row[0].value(), rate);
+            let end = sample_index(row[1].value(), rate);
+            length = length.max(end);
+            let note = row[2].value();
+            let velocity = row[3].value();
+            let Some(font) = song.soundfonts.fonts.get(row[4].value() as usize) else {
+                continue;
+            };
+
+            let base = options.hertz * 2f64.powf((note + options.transpose) / 12.0);
+            for (harmonic, &weight) in font.iter().enumerate() {
+                let gain = options.volume * velocity / 254.0 * weight as f64;
+                if gain == 0.0 {
+                    continue;
+                }
+                let frequency = base * (harmonic + 1) as f64;
+                for frequency in [frequency, frequency * detune] {
+                    if frequency >= rate / 2.0 {
+                        continue;
+                    }
+                    tones.push((start, end, frequency / rate, gain));
+                }
+            }
+        }
+        Self { tones, length }
+    }
+
+    /// Synthesizes a span of samples of the song.
+    ///
+    /// # Arguments
+    /// * `span` - Indices of the samples to synthesize
+    ///
+    /// # Returns
+    /// * `Vec<f32>` - Mono samples between -1 and 1
+    fn render(&self, span: Range<usize>) -> Vec<f32> {
+        let mut samples = vec![0.0f64; span.len()];
+        for &(start, end, cycles, gain) in &self.tones {
+            let (start, end) = (start.max(span.start), end.min(span.end));
+            if start >= end {
+                continue;
+            }
+            add_tone(
+                &mut samples[start - span.start..end - span.start],
+                start,
+                cycles,
+                gain,
+            );
+        }
+
+        samples
+            .into_iter()
+            .map(|sample| sample.clamp(-1.0, 1.0) as f32)
+            .collect()
+    }
+}
+
+/// Returns the index of the sample at a time.
+///
+/// # Arguments
+/// * `time` - Time in seconds
+/// * `rate` - Number of samples per second
+fn sample_index(time: f64, rate: f64) -> usize {
+    (time * rate).round().max(0.0) as usize
+}
+
+/// Adds a sine tone to a span of samples.
+///
+/// The tone is rotated one sample at a time rather than evaluated at every
+/// sample, which keeps long songs with many harmonics quick to render.
+///
+/// # Arguments
+/// * `samples` - Samples to add the tone to
+/// * `offset` - Index of the first sample in the song
+/// * `cycles` - Frequency of the tone in cycles per sample
+/// * `gain` - Amplitude of the tone
+fn add_tone(samples: &mut [f64], offset: usize, cycles: f64, gain: f64) {
+    // Start at the phase the tone has at this time since the start of the song
+    let phase = (cycles * offset as f64).fract() * TAU;
+    let (mut sin, mut cos) = phase.sin_cos();
+    let (step_sin, step_cos) = (cycles * TAU).sin_cos();
+    for sample in samples {
+        *sample += gain * sin;
+        (sin, cos) = (
+            sin * step_cos + cos * step_sin,
+            cos * step_cos - sin * step_sin,
+        );
+    }
+}
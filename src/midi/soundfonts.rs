@@ -1,10 +1,13 @@
-use super::types::MidiError;
+use super::types::{Envelope, MidiError, SoundFont};
 use std::fs;
 use std::path::Path;
 
 /// Default directory for soundfont files
 const DEFAULT_SOUNDFONT_DIR: &str = "soundfonts";
 
+/// Prefix of the optional line in a soundfont file that carries a velocity envelope
+const ENVELOPE_PREFIX: &str = "envelope:";
+
 /// Parses a soundfont file from the specified directory.
 /// A soundfont file contains comma-separated floating point values representing harmonic weights.
 ///
@@ -22,23 +25,57 @@ pub fn parse_soundfont_file(
     filename: &str,
     soundfont_dir: Option<&Path>,
 ) -> Result<Option<Vec<f32>>, MidiError> {
+    parse_soundfont_with_envelope(filename, soundfont_dir)
+        .map(|soundfont| soundfont.map(|(weights, _)| weights))
+}
+
+/// Parses a soundfont file along with the velocity envelope it carries, if any.
+///
+/// Besides the harmonic weights, a soundfont file may contain a line of the form
+/// `envelope: attack,decay,sustain,release` with times in milliseconds and the
+/// sustain level between 0 and 1.
+///
+/// # Arguments
+/// * `filename` - Name of the file in the soundfonts directory
+/// * `soundfont_dir` - Optional directory path; defaults to "soundfonts" if None
+///
+/// # Returns
+/// * `Option<(SoundFont, Option<Envelope>)>` - Harmonic weights and envelope,
+///   or None if the filename is "-"
+///
+/// # Errors
+/// * If the file cannot be read
+/// * If the file contains invalid floating point numbers
+/// * If the envelope line is invalid
+pub fn parse_soundfont_with_envelope(
+    filename: &str,
+    soundfont_dir: Option<&Path>,
+) -> Result<Option<(SoundFont, Option<Envelope>)>, MidiError> {
     if filename == "-" {
         return Ok(None);
     }
 
     let dir = soundfont_dir.unwrap_or_else(|| Path::new(DEFAULT_SOUNDFONT_DIR));
     let path = dir.join(filename);
+    let content = fs::read_to_string(&path).map_err(MidiError::Io)?;
+
+    // Separate the envelope line from the harmonic weights
+    let mut envelope = None;
+    let mut weights = Vec::new();
+    for line in content.lines() {
+        match line.trim().strip_prefix(ENVELOPE_PREFIX) {
+            Some(spec) => envelope = Some(spec.parse()?),
+            None => weights.push(line),
+        }
+    }
 
-    fs::read_to_string(&path)
-        .map_err(|e| MidiError::Io(e))
-        .and_then(|content| {
-            content
-                .trim()
-                .split(',')
-                .map(|s| s.trim().parse().map_err(MidiError::Parse))
-                .collect::<Result<Vec<f32>, _>>()
-                .map(Some)
-        })
+    weights
+        .join("\n")
+        .trim()
+        .split(',')
+        .map(|s| s.trim().parse().map_err(MidiError::Parse))
+        .collect::<Result<Vec<f32>, _>>()
+        .map(|weights| Some((weights, envelope)))
 }
 
 /// Checks if a soundfont file exists in the soundfont directory.
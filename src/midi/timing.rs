@@ -1,53 +1,153 @@
-use super::types::TempoMap;
+use super::types::{MidiError, QuantizeGrid, TempoMap, TempoTable, Timestamp, US_PER_MS};
 
-/// Converts MIDI ticks to milliseconds, taking into account tempo changes.
+/// Converts MIDI ticks to microseconds, taking into account tempo changes.
 ///
 /// The conversion uses the formula:
 /// ```text
-/// ms = (ticks * tempo) / (ticks_per_quarter * 1000)
+/// us = (ticks * tempo) / ticks_per_quarter
 /// ```
 /// where:
 /// - ticks: MIDI event time in ticks
 /// - tempo: Microseconds per quarter note (default: 500000 = 120 BPM)
 /// - ticks_per_quarter: MIDI file's time division (ticks per quarter note)
 ///
-/// For better precision, calculations are done in microseconds and converted to
-/// milliseconds at the end.
+/// The tempo segment containing the tick is found with a binary search, and only
+/// the ticks since the start of that segment are converted; the time before it
+/// is precomputed by `TempoTable::new`. The result is rounded to the nearest
+/// microsecond.
+///
+/// SMPTE timecode files are handled through the same formula, using the fixed
+/// tempo map created by `TempoMap::from_timecode`.
 ///
 /// # Arguments
 /// * `ticks` - The number of MIDI ticks
-/// * `tempo_map` - Map of tempo changes throughout the song
+/// * `tempo_table` - Tempo changes throughout the song with their elapsed times
+///
+/// # Returns
+/// * `Timestamp` - Time in microseconds
+pub fn ticks_to_us(ticks: u64, tempo_table: &TempoTable) -> Timestamp {
+    // Last tempo change at or before the tick
+    let index = tempo_table
+        .segments
+        .partition_point(|segment| segment.tick <= ticks);
+    let Some(segment) = index.checked_sub(1).map(|i| &tempo_table.segments[i]) else {
+        return 0;
+    };
+
+    let ticks_per_quarter = tempo_table.ticks_per_quarter.max(1) as u128;
+    let elapsed = segment.elapsed + (ticks - segment.tick) as u128 * segment.tempo as u128;
+    ((elapsed + ticks_per_quarter / 2) / ticks_per_quarter) as Timestamp
+}
+
+/// Returns the quantization grid line nearest to a time.
+///
+/// Lines are counted from the start of the song. Grids of note values are laid
+/// out in ticks, so their lines follow the tempo changes of the song.
+///
+/// # Arguments
+/// * `grid` - Quantization grid
+/// * `ticks` - Time in MIDI ticks
+/// * `time` - The same time in microseconds
+/// * `tempo_table` - Tempo changes throughout the song with their elapsed times
+///
+/// # Returns
+/// * `u64` - Index of the nearest grid line
+pub fn nearest_grid_line(
+    grid: QuantizeGrid,
+    ticks: u64,
+    time: Timestamp,
+    tempo_table: &TempoTable,
+) -> u64 {
+    match grid {
+        QuantizeGrid::Milliseconds(milliseconds) => {
+            (time as f64 / (milliseconds.max(1) * US_PER_MS) as f64).round() as u64
+        }
+        QuantizeGrid::Note { division, triplet } => {
+            let step = grid_step_ticks(division, triplet, tempo_table.ticks_per_quarter);
+            (ticks as f64 / step).round() as u64
+        }
+    }
+}
+
+/// Returns the time of a quantization grid line.
+///
+/// # Arguments
+/// * `grid` - Quantization grid
+/// * `line` - Index of the grid line
+/// * `tempo_table` - Tempo changes throughout the song with their elapsed times
 ///
 /// # Returns
-/// * `u64` - Time in milliseconds
-pub fn ticks_to_ms(ticks: u64, tempo_map: &TempoMap) -> u64 {
-    let mut current_tick: u64 = 0;
-    let mut current_time_us: u128 = 0; // Track time in microseconds for better precision
-    let mut last_tempo_idx = 0;
-
-    // Find the total time by accumulating through each tempo segment
-    while current_tick < ticks {
-        // Find the next tempo change or use the target ticks
-        let next_tempo_change = tempo_map
-            .changes
-            .get(last_tempo_idx + 1)
-            .map(|change| change.tick)
-            .unwrap_or(ticks);
-
-        let segment_end_tick = next_tempo_change.min(ticks);
-        let tick_delta = segment_end_tick - current_tick;
-        let current_tempo = tempo_map.changes[last_tempo_idx].tempo;
-
-        // Calculate time in microseconds first
-        current_time_us +=
-            (tick_delta as u128 * current_tempo as u128) / tempo_map.ticks_per_quarter as u128;
-
-        if segment_end_tick == next_tempo_change {
-            last_tempo_idx += 1;
+/// * `Timestamp` - Time in microseconds
+pub fn grid_line_time(grid: QuantizeGrid, line: u64, tempo_table: &TempoTable) -> Timestamp {
+    match grid {
+        QuantizeGrid::Milliseconds(milliseconds) => line * milliseconds.max(1) * US_PER_MS,
+        QuantizeGrid::Note { division, triplet } => {
+            let step = grid_step_ticks(division, triplet, tempo_table.ticks_per_quarter);
+            ticks_to_us((line as f64 * step).round() as u64, tempo_table)
         }
-        current_tick = segment_end_tick;
+    }
+}
+
+/// Returns the number of ticks between the lines of a grid of note values.
+///
+/// # Arguments
+/// * `division` - Number of the notes in a whole note
+/// * `triplet` - Whether the notes are triplets
+/// * `ticks_per_quarter` - MIDI file's time division (ticks per quarter note)
+fn grid_step_ticks(division: u32, triplet: bool, ticks_per_quarter: u32) -> f64 {
+    let step = ticks_per_quarter as f64 * 4.0 / division.max(1) as f64;
+    if triplet {
+        step * 2.0 / 3.0
+    } else {
+        step
+    }
+}
+
+/// Converts a bar and beat to MIDI ticks, taking into account time signature changes.
+///
+/// Bars are counted through every time signature, and a time signature change
+/// partway through a bar starts a new bar.
+///
+/// # Arguments
+/// * `bar` - Bar number, counted from 1
+/// * `beat` - Beat in the bar, counted from 1
+/// * `tempo_map` - Tempo map with the time signature changes of the song
+///
+/// # Returns
+/// * `u64` - Time in MIDI ticks
+///
+/// # Errors
+/// * If the beat is past the end of the bar. The beat after the last one, which is
+///   the start of the next bar, is allowed.
+pub fn bar_beat_to_ticks(bar: u32, beat: f64, tempo_map: &TempoMap) -> Result<u64, MidiError> {
+    let ticks_per_quarter = tempo_map.ticks_per_quarter as f64;
+
+    // Find the time signature the bar is in, starting in 4/4
+    let (mut start_tick, mut start_bar) = (0, 1);
+    let (mut numerator, mut denominator) = (4, 4);
+    for change in &tempo_map.time_signatures {
+        let bar_ticks = ticks_per_quarter * 4.0 / denominator as f64 * numerator as f64;
+        let bars = ((change.tick - start_tick) as f64 / bar_ticks).ceil() as u32;
+        if bar < start_bar + bars {
+            break;
+        }
+        start_tick = change.tick;
+        start_bar += bars;
+        numerator = change.numerator;
+        denominator = change.denominator;
+    }
+
+    if beat > numerator as f64 + 1.0 {
+        return Err(MidiError::InvalidTrim(format!(
+            "Bar {} has {} beats, so it has no beat {}",
+            bar, numerator, beat
+        )));
     }
 
-    // Convert microseconds to milliseconds at the end to maintain precision
-    (current_time_us / 1000) as u64
+    let beat_ticks = ticks_per_quarter * 4.0 / denominator as f64;
+    let bars = bar.saturating_sub(start_bar) as f64;
+    let ticks = start_tick as f64
+        + bars * beat_ticks * numerator as f64
+        + (beat - 1.0).max(0.0) * beat_ticks;
+    Ok(ticks.round() as u64)
 }
@@ -1,6 +1,13 @@
+use super::desmos::{format_latex, validate_expressions, Assignment, Expression, Number, Piece};
+use super::graph::{graph_state, html_player, DESMOS_DEMO_API_KEY};
+use super::optimize::optimize_expressions;
+use std::collections::{BTreeMap, HashMap};
+
 // Basic MIDI types
-/// Timestamp in milliseconds
+/// Timestamp in microseconds
 pub type Timestamp = u64;
+/// Duration in milliseconds, as given in options and envelopes
+pub type Milliseconds = u64;
 /// MIDI note number (0-127)
 pub type MidiNote = u8;
 /// Note velocity (0-127)
@@ -14,6 +21,24 @@ pub type SoundFont = Vec<f32>;
 /// Maximum length of a single Desmos formula section
 /// Formulas longer than this will be split into multiple sections
 const MAX_FORMULA_LENGTH: usize = 20000;
+/// MIDI channel reserved for percussion (channel 10, 0-based)
+pub(crate) const DRUM_CHANNEL: u8 = 9;
+/// Number of microseconds in a millisecond
+pub(crate) const US_PER_MS: Timestamp = 1000;
+/// Number of microseconds in a second
+const US_PER_SECOND: f64 = 1_000_000.0;
+/// Largest number of decimal places of the times written to a formula, as times
+/// are tracked in microseconds
+pub const MAX_TIME_PRECISION: usize = 6;
+/// Subscripts of the parallel lists of the event-list encoding, which are named
+/// `E_{start}`, `E_{end}`, etc.: start time and end time in seconds, relative note,
+/// velocity, and soundfont index of each event
+const EVENT_LISTS: [&str; 5] = ["start", "end", "note", "velocity", "font"];
+/// Indices of the events of the event-list encoding that are active at `t`
+const EVENT_ACTIVE: &str = "\\left[1...\\operatorname{length}\\left(E_{start}\\right)\\right]\\left[\\left\\{E_{start}\\le t:E_{end}\\right\\}>t\\right]";
+/// Flat list of (relative note, velocity, soundfont index) triples of the active
+/// events, interleaved by sorting on their positions in the list
+const EVENT_NOTES: &str = "\\operatorname{sort}\\left(\\operatorname{join}\\left(E_{note}\\left[E_{active}\\right],E_{velocity}\\left[E_{active}\\right],E_{font}\\left[E_{active}\\right]\\right),\\operatorname{join}\\left(3\\cdot E_{active}-2,3\\cdot E_{active}-1,3\\cdot E_{active}\\right)\\right)";
 
 // Tempo handling
 /// Represents a tempo change event in a MIDI file
@@ -25,13 +50,30 @@ pub struct TempoChange {
     pub tempo: u32,
 }
 
+/// Represents a time signature change event in a MIDI file
+#[derive(Debug, Clone)]
+pub struct TimeSignatureChange {
+    /// MIDI tick at which the time signature change occurs
+    pub tick: u64,
+    /// Number of beats in a bar
+    pub numerator: u8,
+    /// Note value of a beat, as the number of the notes in a whole note
+    pub denominator: u32,
+}
+
 /// Map of tempo changes throughout a MIDI file
 #[derive(Debug, Default)]
 pub struct TempoMap {
     /// List of tempo changes in chronological order
     pub changes: Vec<TempoChange>,
+    /// List of time signature changes in chronological order.
+    /// Bars before the first change are in 4/4.
+    pub time_signatures: Vec<TimeSignatureChange>,
     /// Number of MIDI ticks per quarter note
     pub ticks_per_quarter: u32,
+    /// Whether the tempo is fixed by the file's timing format (SMPTE timecode),
+    /// in which case tempo meta events are ignored
+    pub fixed_tempo: bool,
 }
 
 impl TempoMap {
@@ -45,6 +87,80 @@ impl TempoMap {
                 tick: 0,
                 tempo: 500000,
             }], // Default 120 BPM
+            time_signatures: Vec::new(),
+            ticks_per_quarter,
+            fixed_tempo: false,
+        }
+    }
+
+    /// Creates a TempoMap for a file using SMPTE timecode timing.
+    ///
+    /// Timecode files measure time in subdivisions of a video frame rather than
+    /// of a quarter note, so tempo meta events have no effect. The map treats one
+    /// second of nominal frames as a "quarter note" with a fixed tempo, which lets
+    /// the regular tick conversion be used unchanged.
+    ///
+    /// For 29.97 drop-frame timecode, 30 nominal frames last 1.001 seconds.
+    ///
+    /// # Arguments
+    /// * `fps` - Frames per second from the MIDI header
+    /// * `subframes` - Number of ticks per frame
+    pub fn from_timecode(fps: midly::Fps, subframes: u8) -> Self {
+        let (frames, tempo) = match fps {
+            midly::Fps::Fps29 => (30, 1_001_000), // 29.97 drop-frame
+            other => (other.as_int() as u32, 1_000_000),
+        };
+        Self {
+            changes: vec![TempoChange { tick: 0, tempo }],
+            time_signatures: Vec::new(),
+            ticks_per_quarter: frames * subframes as u32,
+            fixed_tempo: true,
+        }
+    }
+}
+
+/// Tempo change with the time elapsed since the start of the song
+#[derive(Debug, Clone)]
+pub struct TempoSegment {
+    /// MIDI tick at which the tempo change occurs
+    pub tick: u64,
+    /// Tempo in microseconds per quarter note from this tick on
+    pub tempo: u32,
+    /// Time from the start of the song to this tick, in microseconds multiplied by
+    /// the ticks per quarter note, so that no fraction of a microsecond is lost
+    pub elapsed: u128,
+}
+
+/// Tempo map with cumulative times, so ticks can be converted without walking
+/// through every earlier tempo change
+#[derive(Debug)]
+pub struct TempoTable {
+    /// Tempo segments in chronological order
+    pub segments: Vec<TempoSegment>,
+    /// Number of MIDI ticks per quarter note
+    pub ticks_per_quarter: u32,
+}
+
+impl TempoTable {
+    /// Creates a TempoTable by accumulating the duration of each tempo segment.
+    ///
+    /// # Arguments
+    /// * `tempo_map` - Map of tempo changes throughout the song
+    pub fn new(tempo_map: &TempoMap) -> Self {
+        let ticks_per_quarter = tempo_map.ticks_per_quarter;
+        let mut segments: Vec<TempoSegment> = Vec::with_capacity(tempo_map.changes.len());
+        for change in &tempo_map.changes {
+            let elapsed = segments.last().map_or(0, |previous| {
+                previous.elapsed + (change.tick - previous.tick) as u128 * previous.tempo as u128
+            });
+            segments.push(TempoSegment {
+                tick: change.tick,
+                tempo: change.tempo,
+                elapsed,
+            });
+        }
+        Self {
+            segments,
             ticks_per_quarter,
         }
     }
@@ -58,19 +174,652 @@ pub struct Channel {
     pub id: u8,
     /// MIDI program/instrument number (0-127)
     pub instrument: u8,
+    /// Programs active when notes on this channel start, in order of first use
+    pub programs: Vec<u8>,
     /// Whether this is a drum channel (channel 10)
     pub is_drum: bool,
 }
 
+/// Information about a track of a MIDI file that sends MIDI messages
+#[derive(Debug, Clone)]
+pub struct Track {
+    /// Position of the track in the file (0-based)
+    pub index: usize,
+    /// Name given by the track's first TrackName meta event, if any
+    pub name: Option<String>,
+    /// Channels the track sends messages on, sorted by channel ID
+    pub channels: Vec<u8>,
+}
+
+impl Track {
+    /// Returns whether the track only plays the drum channel.
+    pub fn is_drum(&self) -> bool {
+        self.channels.iter().all(|&channel| channel == DRUM_CHANNEL)
+    }
+}
+
+// Soundfont assignment
+/// How soundfonts are assigned to the notes of a song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SoundfontMapping {
+    /// One soundfont per channel
+    #[default]
+    Channel,
+    /// One soundfont per program used on each channel
+    ChannelProgram,
+    /// One soundfont per program, shared by all channels.
+    /// Drum channels keep a soundfont of their own, since their programs select drum kits.
+    Program,
+    /// One soundfont per track, for files that put several instruments on one channel
+    Track,
+}
+
+/// Key that a single soundfont is assigned to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SoundfontSlot {
+    /// All notes on a channel
+    Channel(u8),
+    /// Notes on a channel played while a program is active
+    ChannelProgram(u8, u8),
+    /// Notes on any melodic channel played while a program is active
+    Program(u8),
+    /// All notes started by a track, by track index
+    Track(usize),
+}
+
+impl SoundfontMapping {
+    /// Returns the slot that a note belongs to.
+    ///
+    /// # Arguments
+    /// * `note` - Note to find the slot of
+    pub fn slot(self, note: &Note) -> SoundfontSlot {
+        self.channel_slot(note.channel, note.program)
+            .unwrap_or(SoundfontSlot::Track(note.track))
+    }
+
+    /// Returns the slot of a channel and program, or `None` when assigning by track.
+    fn channel_slot(self, channel: u8, program: u8) -> Option<SoundfontSlot> {
+        match self {
+            SoundfontMapping::Channel => Some(SoundfontSlot::Channel(channel)),
+            SoundfontMapping::ChannelProgram => {
+                Some(SoundfontSlot::ChannelProgram(channel, program))
+            }
+            SoundfontMapping::Program if channel == DRUM_CHANNEL => {
+                Some(SoundfontSlot::Channel(channel))
+            }
+            SoundfontMapping::Program => Some(SoundfontSlot::Program(program)),
+            SoundfontMapping::Track => None,
+        }
+    }
+
+    /// Returns the slots that need a soundfont, in the order soundfonts are given.
+    ///
+    /// Slots are ordered by channel, then by the order in which each channel first
+    /// uses a program. In channel mode every channel gets a slot, even without notes,
+    /// and in track mode every track that sends MIDI messages gets a slot.
+    ///
+    /// # Arguments
+    /// * `song` - Song information with channels and tracks
+    ///
+    /// # Returns
+    /// * `Vec<SoundfontSlot>` - Unique slots in soundfont order
+    pub fn slots(self, song: &ProcessedSong) -> Vec<SoundfontSlot> {
+        match self {
+            SoundfontMapping::Channel => song
+                .channels
+                .iter()
+                .map(|channel| SoundfontSlot::Channel(channel.id))
+                .collect(),
+            SoundfontMapping::Track => song
+                .tracks
+                .iter()
+                .map(|track| SoundfontSlot::Track(track.index))
+                .collect(),
+            _ => {
+                let mut slots = Vec::new();
+                for channel in &song.channels {
+                    for &program in &channel.programs {
+                        if let Some(slot) = self.channel_slot(channel.id, program) {
+                            if !slots.contains(&slot) {
+                                slots.push(slot);
+                            }
+                        }
+                    }
+                }
+                slots
+            }
+        }
+    }
+
+    /// Returns a plural description of the slots, for use in messages.
+    pub fn describe(self) -> &'static str {
+        match self {
+            SoundfontMapping::Channel => "channels",
+            SoundfontMapping::ChannelProgram => "channel programs",
+            SoundfontMapping::Program => "programs",
+            SoundfontMapping::Track => "tracks",
+        }
+    }
+}
+
+impl SoundfontSlot {
+    /// Returns whether the slot only plays the drum channel.
+    ///
+    /// # Arguments
+    /// * `song` - Song information with the tracks that track slots refer to
+    pub fn is_drum(&self, song: &ProcessedSong) -> bool {
+        match *self {
+            SoundfontSlot::Channel(channel) | SoundfontSlot::ChannelProgram(channel, _) => {
+                channel == DRUM_CHANNEL
+            }
+            SoundfontSlot::Program(_) => false,
+            SoundfontSlot::Track(index) => song
+                .tracks
+                .iter()
+                .any(|track| track.index == index && track.is_drum()),
+        }
+    }
+}
+
 // Note events and timing
+/// A single note, or a segment of a note whose pitch changes over time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    /// MIDI note number (0-127)
+    pub note: MidiNote,
+    /// Pitch bend offset in semitones applied to the note
+    pub bend: f32,
+    /// Note velocity (0-127)
+    pub velocity: Velocity,
+    /// Index of the soundfont used to play the note
+    /// (holds the MIDI channel until soundfonts are assigned)
+    pub soundfont: usize,
+    /// MIDI channel the note was played on
+    pub channel: u8,
+    /// Program active on the channel when the note started
+    pub program: u8,
+    /// Index of the track that started the note
+    pub track: usize,
+    /// Whether the segment starts with the note's attack,
+    /// rather than continuing it after a pitch bend or volume change
+    pub attack: bool,
+    /// Time in microseconds when the note was struck, before the start of the
+    /// song if the note was struck before the song was trimmed
+    pub onset: i64,
+    /// Whether the note is released at the end of this segment,
+    /// rather than continuing in a following segment
+    pub releases: bool,
+    /// Velocity envelope applied to the note, if any
+    pub envelope: Option<Envelope>,
+    /// Time in microseconds when this note should stop playing
+    pub end: Timestamp,
+}
+
+/// Attack/decay/sustain/release envelope shaping a note's velocity over time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    /// Time in milliseconds to rise from silence to full velocity
+    pub attack: Milliseconds,
+    /// Time in milliseconds to fall from full velocity to the sustain level
+    pub decay: Milliseconds,
+    /// Fraction of the velocity held until the note is released (0-1)
+    pub sustain: f32,
+    /// Time in milliseconds to fade to silence after the note is released
+    pub release: Milliseconds,
+}
+
+impl Envelope {
+    /// Returns the gain of a held note.
+    ///
+    /// # Arguments
+    /// * `elapsed` - Time in milliseconds since the note was struck
+    ///
+    /// # Returns
+    /// * `f64` - Gain between 0 and 1
+    pub fn held_gain(&self, elapsed: f64) -> f64 {
+        let attack = self.attack as f64;
+        let decay = self.decay as f64;
+        let sustain = self.sustain.clamp(0.0, 1.0) as f64;
+
+        if elapsed < attack {
+            elapsed / attack
+        } else if elapsed < attack + decay {
+            1.0 - (1.0 - sustain) * (elapsed - attack) / decay
+        } else {
+            sustain
+        }
+    }
+
+    /// Returns the gain of a note after it was released.
+    ///
+    /// # Arguments
+    /// * `held` - Time in milliseconds the note was held before its release
+    /// * `since_release` - Time in milliseconds since the release
+    ///
+    /// # Returns
+    /// * `f64` - Gain between 0 and 1
+    pub fn released_gain(&self, held: f64, since_release: f64) -> f64 {
+        let release = self.release as f64;
+        if since_release >= release {
+            return 0.0;
+        }
+        self.held_gain(held) * (1.0 - since_release / release)
+    }
+}
+
+impl std::str::FromStr for Envelope {
+    type Err = MidiError;
+
+    /// Parses an envelope written as `attack,decay,sustain,release`,
+    /// with times in milliseconds and the sustain level between 0 and 1.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let invalid = || {
+            MidiError::InvalidEnvelope(format!(
+                "Expected attack,decay,sustain,release but got \"{}\"",
+                s
+            ))
+        };
+        let [attack, decay, sustain, release] = parts[..] else {
+            return Err(invalid());
+        };
+
+        let sustain: f32 = sustain.parse().map_err(|_| invalid())?;
+        if !(0.0..=1.0).contains(&sustain) {
+            return Err(MidiError::InvalidEnvelope(format!(
+                "Sustain level must be between 0 and 1, got {}",
+                sustain
+            )));
+        }
+
+        Ok(Self {
+            attack: attack.parse().map_err(|_| invalid())?,
+            decay: decay.parse().map_err(|_| invalid())?,
+            sustain,
+            release: release.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Grid that note starts and ends are moved towards by quantization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizeGrid {
+    /// Lines a fixed number of milliseconds apart, from the start of the song
+    Milliseconds(Milliseconds),
+    /// Lines a note value apart, following the tempo of the song
+    Note {
+        /// Number of the notes in a whole note (e.g. 16 for sixteenth notes)
+        division: u32,
+        /// Whether the notes are triplets, two thirds as long
+        triplet: bool,
+    },
+}
+
+impl std::str::FromStr for QuantizeGrid {
+    type Err = MidiError;
+
+    /// Parses a grid written as milliseconds (`25ms`) or as a note value
+    /// (`1/16`, or `1/8t` for triplets).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            MidiError::InvalidGrid(format!(
+                "Expected milliseconds (e.g. 25ms) or a note value (e.g. 1/16 or 1/8t) but got \"{}\"",
+                s
+            ))
+        };
+
+        let grid = if let Some(milliseconds) = s.strip_suffix("ms") {
+            QuantizeGrid::Milliseconds(milliseconds.trim().parse().map_err(|_| invalid())?)
+        } else {
+            let division = s.strip_prefix("1/").ok_or_else(invalid)?;
+            let (division, triplet) = match division.strip_suffix('t') {
+                Some(division) => (division, true),
+                None => (division, false),
+            };
+            QuantizeGrid::Note {
+                division: division.parse().map_err(|_| invalid())?,
+                triplet,
+            }
+        };
+
+        match grid {
+            QuantizeGrid::Milliseconds(0) | QuantizeGrid::Note { division: 0, .. } => Err(
+                MidiError::InvalidGrid(format!("Grid lines cannot be 0 apart: \"{}\"", s)),
+            ),
+            grid => Ok(grid),
+        }
+    }
+}
+
+/// Point in a song where it is trimmed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrimPoint {
+    /// Time in seconds
+    Seconds(f64),
+    /// Bar and beat, both counted from 1, following the time signatures of the song
+    BarBeat {
+        /// Bar number
+        bar: u32,
+        /// Beat in the bar, which may be fractional (e.g. 2.5 for halfway through beat 2)
+        beat: f64,
+    },
+    /// Time in MIDI ticks
+    Ticks(u64),
+}
+
+impl std::str::FromStr for TrimPoint {
+    type Err = MidiError;
+
+    /// Parses a trim point written as seconds (`12.5` or `12.5s`), as a bar and
+    /// beat (`17:1`), or as MIDI ticks (`7680t`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            MidiError::InvalidTrim(format!(
+                "Expected seconds (e.g. 12.5), bar:beat (e.g. 17:1), or ticks (e.g. 7680t) but got \"{}\"",
+                s
+            ))
+        };
+
+        if let Some((bar, beat)) = s.split_once(':') {
+            let bar: u32 = bar.trim().parse().map_err(|_| invalid())?;
+            let beat: f64 = beat.trim().parse().map_err(|_| invalid())?;
+            if bar < 1 || beat.is_nan() || beat < 1.0 {
+                return Err(MidiError::InvalidTrim(format!(
+                    "Bars and beats are counted from 1, got \"{}\"",
+                    s
+                )));
+            }
+            Ok(TrimPoint::BarBeat { bar, beat })
+        } else if let Some(ticks) = s.strip_suffix('t') {
+            Ok(TrimPoint::Ticks(
+                ticks.trim().parse().map_err(|_| invalid())?,
+            ))
+        } else {
+            let seconds: f64 = s
+                .strip_suffix('s')
+                .unwrap_or(s)
+                .trim()
+                .parse()
+                .map_err(|_| invalid())?;
+            if !seconds.is_finite() || seconds < 0.0 {
+                return Err(invalid());
+            }
+            Ok(TrimPoint::Seconds(seconds))
+        }
+    }
+}
+
 /// Represents a group of notes that start at the same time
 #[derive(Debug)]
 pub struct NoteEvent {
-    /// Time in milliseconds when the notes start
+    /// Time in microseconds when the notes start
     pub timestamp: Timestamp,
-    /// List of (note, velocity, soundfont_index, end_time) tuples
-    /// end_time is when this specific note should stop playing (in milliseconds)
-    pub notes: Vec<(MidiNote, Velocity, usize, Timestamp)>,
+    /// List of notes starting at this time
+    pub notes: Vec<Note>,
+}
+
+// Processing options
+/// Options controlling how MIDI events are turned into notes
+#[derive(Debug, Clone)]
+pub struct ProcessingOptions {
+    /// Step size in semitones that pitch bend offsets are rounded to.
+    /// Larger steps create fewer pieces in the formula; 0 disables rounding.
+    pub pitch_bend_resolution: f32,
+    /// Minimum length in milliseconds of a segment created by a pitch bend or
+    /// volume change. Changes closer together than this are merged into one segment.
+    pub segment_interval: Milliseconds,
+    /// Whether velocities are scaled by channel volume (CC7) and expression (CC11)
+    pub channel_volume: bool,
+    /// Step size that scaled velocities are rounded to.
+    /// Larger steps create fewer pieces during swells.
+    pub volume_resolution: u8,
+    /// Whether the sustain pedal (CC64) keeps released notes sounding
+    pub sustain_pedal: bool,
+    /// Whether the sostenuto pedal (CC66) keeps notes held when it was pressed sounding
+    pub sostenuto_pedal: bool,
+    /// Which instance of a re-triggered note a note-off ends
+    pub voice_stacking: VoiceStacking,
+    /// How the boundary is marked when a note is struck again right as it ends
+    pub rearticulation: Rearticulation,
+    /// Length in milliseconds of a re-articulation gap or velocity dip
+    pub rearticulation_time: Milliseconds,
+    /// Fraction of the velocity that a re-attacked note starts at when dipping
+    pub rearticulation_dip: f32,
+    /// Velocity envelopes for notes on specific channels, overriding soundfont envelopes
+    pub channel_envelopes: HashMap<u8, Envelope>,
+    /// Time in milliseconds between the velocity steps that envelopes are sampled into.
+    /// Larger steps create fewer pieces in the formula.
+    pub envelope_resolution: Milliseconds,
+    /// Number of decimal places of the times written to the formula, in seconds,
+    /// up to [`MAX_TIME_PRECISION`]
+    pub time_precision: usize,
+    /// Point where the song starts, if it is trimmed. Notes sounding at the start
+    /// are clipped, and the song is timed from it.
+    pub trim_start: Option<TrimPoint>,
+    /// Point where the song ends, if it is trimmed. Notes sounding at the end are
+    /// clipped.
+    pub trim_end: Option<TrimPoint>,
+    /// Whether the silence before the first note is removed, so the song starts
+    /// right away
+    pub strip_leading_silence: bool,
+    /// Grid that note starts and ends are moved towards, if any.
+    /// Notes close to each other then share pieces of the formula.
+    pub quantize: Option<QuantizeGrid>,
+    /// How far notes are moved towards the quantization grid, between 0 (not at all)
+    /// and 1 (onto the grid)
+    pub quantize_strength: f32,
+    /// Largest number of notes that sound at once, if limited
+    pub max_polyphony: Option<usize>,
+    /// Which note gives up its voice when the polyphony limit is reached
+    pub voice_stealing: VoiceStealing,
+    /// Whether the polyphony limit applies to each channel separately
+    pub polyphony_per_channel: bool,
+    /// How the notes are written in the formula
+    pub encoding: Encoding,
+    /// Whether the formula is shortened by [`optimize_expressions`]
+    pub optimize: bool,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        Self {
+            pitch_bend_resolution: 0.1,
+            segment_interval: 20,
+            channel_volume: false,
+            volume_resolution: 1,
+            sustain_pedal: true,
+            sostenuto_pedal: true,
+            voice_stacking: VoiceStacking::default(),
+            rearticulation: Rearticulation::default(),
+            rearticulation_time: 30,
+            rearticulation_dip: 0.5,
+            channel_envelopes: HashMap::new(),
+            envelope_resolution: 50,
+            time_precision: 3,
+            trim_start: None,
+            trim_end: None,
+            strip_leading_silence: true,
+            quantize: None,
+            quantize_strength: 1.0,
+            max_polyphony: None,
+            voice_stealing: VoiceStealing::default(),
+            polyphony_per_channel: false,
+            encoding: Encoding::default(),
+            optimize: false,
+        }
+    }
+}
+
+impl ProcessingOptions {
+    /// Checks that the options can be used to process a song.
+    ///
+    /// Trim points given in different units can only be compared once the song's
+    /// tempo is known, so they are checked when the song is processed.
+    ///
+    /// # Errors
+    /// * If the polyphony limit is 0
+    /// * If the song is trimmed to end before it starts
+    pub fn validate(&self) -> Result<(), MidiError> {
+        if self.time_precision > MAX_TIME_PRECISION {
+            return Err(MidiError::InvalidOptions(format!(
+                "Time precision ({}) must be at most {} decimal places",
+                self.time_precision, MAX_TIME_PRECISION
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.quantize_strength) {
+            return Err(MidiError::InvalidOptions(format!(
+                "Quantization strength ({}) must be between 0 and 1",
+                self.quantize_strength
+            )));
+        }
+        if self.max_polyphony == Some(0) {
+            return Err(MidiError::InvalidOptions(
+                "Polyphony limit must be at least 1".to_string(),
+            ));
+        }
+        if let (Some(start), Some(end)) = (self.trim_start, self.trim_end) {
+            let ends_first = match (start, end) {
+                (TrimPoint::Seconds(start), TrimPoint::Seconds(end)) => end <= start,
+                (TrimPoint::Ticks(start), TrimPoint::Ticks(end)) => end <= start,
+                (
+                    TrimPoint::BarBeat { bar, beat },
+                    TrimPoint::BarBeat {
+                        bar: end_bar,
+                        beat: end_beat,
+                    },
+                ) => (end_bar, end_beat) <= (bar, beat),
+                _ => false,
+            };
+            if ends_first {
+                return Err(MidiError::InvalidTrim(
+                    "The end of the song must come after its start".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Rendering options
+/// Settings of the player that a song is rendered to audio with.
+///
+/// The defaults match the sliders of the player graph.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Number of samples per second of the rendered audio
+    pub sample_rate: u32,
+    /// Frequency of A4 in hertz
+    pub hertz: f64,
+    /// Detune of the secondary tone in cents
+    pub detune: f64,
+    /// Global volume, between 0 and 1
+    pub volume: f64,
+    /// Global pitch shift in semitones
+    pub transpose: f64,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            hertz: 440.0,
+            detune: 5.0,
+            volume: 0.5,
+            transpose: 0.0,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Checks that the options can be used to render a song.
+    ///
+    /// # Errors
+    /// * If the sample rate is 0
+    pub fn validate(&self) -> Result<(), MidiError> {
+        if self.sample_rate == 0 {
+            return Err(MidiError::InvalidOptions(
+                "Sample rate must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How the notes of a song are written in its formulas.
+///
+/// Both encodings define the same `A` for the player, a list of the notes
+/// active at time `t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// `A` is a piecewise function of `t` that lists the active notes between
+    /// every two times where a note starts, ends, or changes
+    #[default]
+    Piecewise,
+    /// Every note is listed once, in parallel lists of its start time, end time,
+    /// relative note, velocity, and soundfont index, and `A` filters the notes
+    /// active at `t`. This is shorter when notes are held under faster ones,
+    /// but Desmos lists are limited to 10000 notes, even when they are split
+    /// into sections and joined.
+    EventList,
+}
+
+/// How a note that is struck again on the same channel is kept audible.
+///
+/// Without this, a note ending exactly when the same note starts again produces
+/// adjacent pieces with the same contents, which Desmos plays as one long note.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rearticulation {
+    /// Repeated notes are emitted unchanged
+    #[default]
+    None,
+    /// The earlier note is shortened to leave a silent gap before the repeat
+    Gap,
+    /// The repeated note starts at a reduced velocity
+    Dip,
+}
+
+/// Order in which stacked instances of the same note are ended.
+///
+/// A note-on for a note that is already sounding on the same channel starts a
+/// second instance on top of the first. Each note-off then ends one instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceStacking {
+    /// A note-off ends the oldest sounding instance
+    #[default]
+    Fifo,
+    /// A note-off ends the most recently started instance
+    Lifo,
+}
+
+/// Which note gives up its voice when a note starts while the polyphony limit is reached.
+///
+/// The stolen note is picked among the sounding notes and the new note. A sounding
+/// note is cut short where the new note starts, and a new note is left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceStealing {
+    /// The quietest note is stolen, the oldest of them if several are as quiet
+    #[default]
+    Quietest,
+    /// The oldest note is stolen
+    Oldest,
+    /// The oldest note between the highest and lowest notes is stolen, keeping the
+    /// melody and the bass
+    Inner,
+}
+
+/// Statistics collected while processing note events
+#[derive(Debug, Clone, Default)]
+pub struct ProcessingStats {
+    /// Number of note-ons that started while the same note was already sounding
+    pub stacked_notes: usize,
+    /// Largest number of instances of one note sounding at once on a channel
+    pub max_stacked: usize,
+    /// Number of notes cut short by the polyphony limit
+    pub truncated_notes: usize,
+    /// Number of notes left out by the polyphony limit
+    pub dropped_notes: usize,
 }
 
 // Soundfont handling
@@ -81,6 +830,8 @@ pub struct SoundFontMap {
     pub fonts: Vec<SoundFont>,
     /// Length of the longest soundfont
     pub max_size: usize,
+    /// Velocity envelope carried by each soundfont, if any
+    pub envelopes: Vec<Option<Envelope>>,
 }
 
 impl SoundFontMap {
@@ -99,7 +850,21 @@ impl SoundFontMap {
                 f
             })
             .collect();
-        Self { fonts, max_size }
+        let envelopes = vec![None; fonts.len()];
+        Self {
+            fonts,
+            max_size,
+            envelopes,
+        }
+    }
+
+    /// Sets the velocity envelopes carried by the soundfonts.
+    ///
+    /// # Arguments
+    /// * `envelopes` - Envelope of each soundfont, in the same order as the fonts
+    pub fn with_envelopes(mut self, envelopes: Vec<Option<Envelope>>) -> Self {
+        self.envelopes = envelopes;
+        self
     }
 }
 
@@ -111,8 +876,20 @@ pub struct ProcessedSong {
     pub note_changes: Vec<NoteEvent>,
     /// List of channels used in the song
     pub channels: Vec<Channel>,
-    /// Soundfonts assigned to each channel
+    /// List of tracks that send MIDI messages, in file order
+    pub tracks: Vec<Track>,
+    /// Soundfonts assigned to the song's channels or programs
     pub soundfonts: SoundFontMap,
+    /// Statistics collected while processing note events
+    pub stats: ProcessingStats,
+    /// Time in milliseconds between the velocity steps that envelopes are sampled into
+    pub envelope_resolution: Milliseconds,
+    /// Number of decimal places of the times written to the formula, in seconds
+    pub time_precision: usize,
+    /// How the notes are written in the formula
+    pub encoding: Encoding,
+    /// Whether the formula is shortened by [`optimize_expressions`]
+    pub optimize: bool,
 }
 
 impl ProcessedSong {
@@ -129,220 +906,737 @@ impl ProcessedSong {
     /// # Returns
     /// * `String` - Newline-separated Desmos formulas
     pub fn to_piecewise_function(&self) -> String {
+        format_latex(&self.to_expressions())
+    }
+
+    /// Creates a complete Desmos calculator state that plays the song.
+    ///
+    /// Besides the formulas written by [`ProcessedSong::to_piecewise_function`],
+    /// the state contains the player expressions and settings sliders, so it can
+    /// be loaded with the Desmos API `setState` without any other graph.
+    ///
+    /// # Returns
+    /// * `String` - Calculator state as JSON
+    ///
+    /// # Errors
+    /// * If the formulas fail [`validate_expressions`]
+    /// * If the state cannot be serialized
+    pub fn to_desmos_state(&self) -> Result<String, MidiError> {
+        let expressions = self.to_expressions();
+        validate_expressions(&expressions)?;
+        graph_state(&expressions, self.max_voices(&expressions))
+    }
+
+    /// Creates a standalone HTML page that plays the song.
+    ///
+    /// The page embeds the state created by [`ProcessedSong::to_desmos_state`] in a
+    /// calculator loaded from the Desmos API, with buttons to play and stop the song.
+    ///
+    /// # Arguments
+    /// * `title` - Title shown on the page, such as the name of the MIDI file
+    /// * `api_key` - Desmos API key to load the calculator with, or `None` for
+    ///   [`DESMOS_DEMO_API_KEY`], which is only meant for trying out the page
+    ///
+    /// # Returns
+    /// * `String` - HTML document
+    ///
+    /// # Errors
+    /// * If the formulas fail [`validate_expressions`]
+    /// * If the state cannot be serialized
+    pub fn to_html_player(&self, title: &str, api_key: Option<&str>) -> Result<String, MidiError> {
+        let expressions = self.to_expressions();
+        validate_expressions(&expressions)?;
+        html_player(
+            &expressions,
+            self.max_voices(&expressions),
+            title,
+            api_key.unwrap_or(DESMOS_DEMO_API_KEY),
+        )
+    }
+
+    /// Returns the length of the formulas of the song in an encoding.
+    ///
+    /// This allows comparing the encodings to pick the shorter one.
+    ///
+    /// # Arguments
+    /// * `encoding` - How the notes are written
+    ///
+    /// # Returns
+    /// * `usize` - Length in bytes of the newline-separated formulas
+    pub fn formula_size(&self, encoding: Encoding) -> usize {
+        format_latex(&self.encoded_expressions(encoding)).len()
+    }
+
+    /// Returns the number of bytes that optimizing the formulas saves.
+    ///
+    /// # Returns
+    /// * `usize` - Difference between the lengths of the formulas before and
+    ///   after [`optimize_expressions`], in the song's encoding
+    pub fn optimization_savings(&self) -> usize {
+        let expressions = self.unoptimized_expressions(self.encoding);
+        let original = format_latex(&expressions).len();
+        original - format_latex(&optimize_expressions(expressions)).len()
+    }
+
+    /// Builds the Desmos formulas of the song as expressions.
+    ///
+    /// These are the formulas written by [`ProcessedSong::to_piecewise_function`],
+    /// before they are serialized, so they can be inspected or transformed first.
+    ///
+    /// # Returns
+    /// * `Vec<Assignment>` - Formulas `A` (and the formulas it is built from), `B`, and `C`, in order
+    pub fn to_expressions(&self) -> Vec<Assignment> {
+        self.encoded_expressions(self.encoding)
+    }
+
+    /// Builds the Desmos formulas of the song as expressions, in a given encoding.
+    ///
+    /// # Arguments
+    /// * `encoding` - How the notes are written
+    ///
+    /// # Returns
+    /// * `Vec<Assignment>` - Formulas `A` (and the formulas it is built from), `B`, and `C`, in order
+    pub fn encoded_expressions(&self, encoding: Encoding) -> Vec<Assignment> {
+        let expressions = self.unoptimized_expressions(encoding);
+        match self.optimize {
+            true => optimize_expressions(expressions),
+            false => expressions,
+        }
+    }
+
+    /// Builds the Desmos formulas of the song in a given encoding, as they are
+    /// before optimization.
+    ///
+    /// # Arguments
+    /// * `encoding` - How the notes are written
+    ///
+    /// # Returns
+    /// * `Vec<Assignment>` - Formulas `A` (and the formulas it is built from), `B`, and `C`, in order
+    fn unoptimized_expressions(&self, encoding: Encoding) -> Vec<Assignment> {
         if self.note_changes.is_empty() {
-            return "A=\\left\\{t<0:\\left[\\right]\\right\\}\nB=\\left[\\right]\nC=0".to_string();
+            return vec![
+                silent_song(),
+                Assignment::new("B", Expression::List(Vec::new())),
+                Assignment::new("C", Expression::Number(0usize.into())),
+            ];
         }
 
-        let mut formulas = Vec::new();
-        let mut current_section = Vec::new();
-        let mut current_length = 0;
-        let mut section_count = 0;
-        let mut section_names = Vec::new();
+        let mut assignments = match encoding {
+            Encoding::Piecewise => self.piecewise_assignments(),
+            Encoding::EventList => event_list_assignments(&self.event_rows()),
+        };
+
+        // Add soundfont array (B) and max size (C)
+        assignments.extend(soundfont_assignments(&self.soundfonts));
+        assignments
+    }
 
+    /// Builds the piecewise formula `A` and its sections.
+    ///
+    /// # Returns
+    /// * `Vec<Assignment>` - Main formula followed by its sections, if any
+    fn piecewise_assignments(&self) -> Vec<Assignment> {
         // Find all unique timestamps where notes start or end
         let timestamps = self.collect_all_timestamps();
 
         // For each timestamp, collect all active notes
-        self.process_timestamps(
-            &timestamps,
-            &mut formulas,
-            &mut current_section,
-            &mut current_length,
-            &mut section_count,
-            &mut section_names,
-        );
+        let pieces = self.process_timestamps(&timestamps);
+        let mut sections = split_sections(pieces);
 
         // Add an empty array at the very end
-        if let Some(&last_time) = timestamps.last() {
-            current_section.push(format!("t<{}:\\left[\\right]", last_time + 0.1));
+        if let (Some(&last_time), Some(section)) = (timestamps.last(), sections.last_mut()) {
+            // At least one emitted digit past the last change, so the silence is reachable
+            let offset = 0.1f64.max(10f64.powi(-(self.time_precision as i32)));
+            section.push(Piece {
+                end: Number::Shortest(round_seconds(last_time, self.time_precision, offset)),
+                value: Expression::List(Vec::new()),
+            });
         }
 
-        // Add the last section
-        if !current_section.is_empty() {
-            section_count += 1;
-            let section_name = format!("A_{{{}}}", section_count);
-            section_names.push(section_name.clone());
+        name_sections(sections, self.time_precision)
+    }
 
-            let section_formula = format!(
-                "{}=\\left\\{{{}\\right\\}}",
-                section_name,
-                current_section.join(",")
-            );
-            formulas.push(section_formula);
-        }
+    /// Collects the rows of the event-list encoding.
+    ///
+    /// Every note segment becomes one event, or one per velocity step while its
+    /// envelope changes. Events are ordered by start time and note, and times are
+    /// rounded to the time precision; events that round to no length are left out.
+    ///
+    /// # Returns
+    /// * `Vec<[Number; 5]>` - Start time, end time, relative note, velocity, and
+    ///   soundfont index of each event
+    pub(crate) fn event_rows(&self) -> Vec<[Number; 5]> {
+        let mut spans: Vec<(Timestamp, Timestamp, &Note, Velocity)> = Vec::new();
+        for event in &self.note_changes {
+            for note in &event.notes {
+                let mut bounds = vec![event.timestamp];
+                bounds.extend(self.envelope_steps(event.timestamp, note));
+                bounds.push(note.sounding_end());
+                bounds.dedup();
 
-        // Create the main formula
-        if section_count > 1 {
-            let main_formula = create_main_formula(&formulas, &section_names);
-            formulas.insert(0, main_formula);
-        } else {
-            // If there's only one section, rename it to A
-            formulas[0] = formulas[0].replace("A_{1}", "A");
+                for window in bounds.windows(2) {
+                    let velocity = note.velocity_at((window[0] + window[1]) as f64 / 2.0);
+                    if velocity == 0 && note.envelope.is_some() {
+                        continue;
+                    }
+                    // Extend the previous step of the note while its velocity holds
+                    match spans.last_mut() {
+                        Some(last)
+                            if std::ptr::eq(last.2, note)
+                                && last.1 == window[0]
+                                && last.3 == velocity =>
+                        {
+                            last.1 = window[1];
+                        }
+                        _ => spans.push((window[0], window[1], note, velocity)),
+                    }
+                }
+            }
         }
+        let mut rows: Vec<(MidiNote, [Number; 5])> = spans
+            .into_iter()
+            .filter_map(|(start, end, note, velocity)| {
+                let start = round_seconds(start, self.time_precision, 0.0);
+                let end = round_seconds(end, self.time_precision, 0.0);
+                (start < end).then(|| {
+                    let row = [
+                        Number::Shortest(start),
+                        Number::Shortest(end),
+                        relative_note(note.note, note.bend),
+                        Number::from(velocity),
+                        Number::from(note.soundfont),
+                    ];
+                    (note.note, row)
+                })
+            })
+            .collect();
 
-        // Add soundfont array (B) and max size (C)
-        add_soundfont_formulas(&mut formulas, &self.soundfonts);
+        // Order by the start as written, so events that round to the same start are
+        // ordered by note, and otherwise kept in song order
+        rows.sort_by(|(a_note, a), (b_note, b)| {
+            a[0].value()
+                .total_cmp(&b[0].value())
+                .then(a_note.cmp(b_note))
+        });
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
 
-        formulas.join("\n")
+    /// Returns the largest number of notes that play at once in the song's formulas.
+    ///
+    /// # Arguments
+    /// * `expressions` - Formulas of the song in its encoding
+    ///
+    /// # Returns
+    /// * `usize` - Number of voices the player needs
+    fn max_voices(&self, expressions: &[Assignment]) -> usize {
+        match self.encoding {
+            Encoding::Piecewise => expressions
+                .iter()
+                .filter_map(|assignment| match &assignment.value {
+                    Expression::Piecewise(pieces) => Some(pieces),
+                    _ => None,
+                })
+                .flatten()
+                .filter_map(|piece| match &piece.value {
+                    Expression::List(values) => Some(values.len() / 3),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0),
+            Encoding::EventList => {
+                // Events end before the ones starting at the same time
+                let mut changes: Vec<(f64, i32)> = self
+                    .event_rows()
+                    .iter()
+                    .flat_map(|row| [(row[0].value(), 1), (row[1].value(), -1)])
+                    .collect();
+                changes.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                changes
+                    .iter()
+                    .scan(0, |active, &(_, change)| {
+                        *active += change;
+                        Some(*active)
+                    })
+                    .max()
+                    .unwrap_or(0) as usize
+            }
+        }
     }
 
     /// Collects all timestamps where notes start or end.
     ///
+    /// Notes with a velocity envelope also get a timestamp for every velocity step
+    /// while their envelope changes, and end once their release has faded out.
+    ///
     /// # Returns
-    /// * `Vec<f64>` - Sorted vector of unique timestamps in seconds
-    fn collect_all_timestamps(&self) -> Vec<f64> {
-        let mut timestamps: Vec<f64> = self
+    /// * `Vec<Timestamp>` - Sorted vector of unique timestamps in microseconds
+    fn collect_all_timestamps(&self) -> Vec<Timestamp> {
+        let mut timestamps: Vec<Timestamp> = self
             .note_changes
             .iter()
             .flat_map(|event| {
-                let start = event.timestamp as f64 / 1000.0;
-                let ends = event
-                    .notes
-                    .iter()
-                    .map(|(_, _, _, end)| *end as f64 / 1000.0);
-                std::iter::once(start).chain(ends)
+                let ends = event.notes.iter().flat_map(|note| {
+                    std::iter::once(note.sounding_end())
+                        .chain(self.envelope_steps(event.timestamp, note))
+                });
+                std::iter::once(event.timestamp).chain(ends)
             })
             .collect();
-        timestamps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        timestamps.sort_unstable();
         timestamps.dedup();
         timestamps
     }
 
-    /// Processes timestamps and builds the piecewise formula sections.
+    /// Returns the times within a note segment where its envelope velocity steps.
+    ///
+    /// Steps are spaced by the envelope resolution, counting from the note's onset
+    /// during its attack and decay and from its release while it fades out.
+    ///
+    /// # Arguments
+    /// * `start` - Time in microseconds when the segment starts
+    /// * `note` - Note segment
+    ///
+    /// # Returns
+    /// * `Vec<Timestamp>` - Times in microseconds between the segment start and its sounding end
+    fn envelope_steps(&self, start: Timestamp, note: &Note) -> Vec<Timestamp> {
+        let Some(envelope) = note.envelope else {
+            return Vec::new();
+        };
+        let step = (self.envelope_resolution.max(1) * US_PER_MS) as usize;
+
+        // Attack and decay, up to where the sustain level is reached
+        let decayed = note.onset + ((envelope.attack + envelope.decay) * US_PER_MS) as i64;
+        let mut steps: Vec<Timestamp> = (note.onset..decayed)
+            .step_by(step)
+            .chain(std::iter::once(decayed))
+            .filter(|&time| time > start as i64 && time < note.end as i64)
+            .map(|time| time as Timestamp)
+            .collect();
+
+        // Release, until the note has faded out
+        if note.releases {
+            steps.extend((note.end..note.sounding_end()).step_by(step));
+        }
+        steps
+    }
+
+    /// Processes timestamps and builds the pieces of the piecewise formula.
+    ///
+    /// For each timestamp window, finds active notes and adds them to a piece.
+    /// Windows whose end rounds to the same emitted time as the previous window's
+    /// are skipped, since they can never be selected.
+    ///
+    /// # Arguments
+    /// * `timestamps` - Sorted vector of unique timestamps in microseconds
     ///
-    /// For each timestamp window, finds active notes and adds them to the formula.
-    /// Splits the formula into sections if it exceeds the maximum length.
-    fn process_timestamps(
-        &self,
-        timestamps: &[f64],
-        formulas: &mut Vec<String>,
-        current_section: &mut Vec<String>,
-        current_length: &mut usize,
-        section_count: &mut usize,
-        section_names: &mut Vec<String>,
-    ) {
+    /// # Returns
+    /// * `Vec<Piece>` - One piece per window, ending at the window's end
+    fn process_timestamps(&self, timestamps: &[Timestamp]) -> Vec<Piece> {
+        let mut pieces: Vec<Piece> = Vec::new();
+        let mut sweep = NoteSweep::new(&self.note_changes);
         for window in timestamps.windows(2) {
             let current_time = window[0];
             let next_time = window[1];
 
-            // Find all notes that are active at this time
-            let active_notes = self.collect_active_notes(current_time);
-
-            // Format the array of active notes
-            let array_str = format_note_array_simple(&active_notes);
-            let piece = format!("t<{:0.3}:{}", next_time, array_str);
-
-            // Check if adding this piece would exceed the limit
-            if *current_length + piece.len() > MAX_FORMULA_LENGTH && !current_section.is_empty() {
-                *section_count += 1;
-                let section_name = format!("A_{{{}}}", section_count);
-                section_names.push(section_name.clone());
-
-                let section_formula = format!(
-                    "{}=\\left\\{{{}\\right\\}}",
-                    section_name,
-                    current_section.join(",")
-                );
-                formulas.push(section_formula);
-
-                current_section.clear();
-                *current_length = 0;
+            let end = Number::Fixed(seconds(next_time), self.time_precision);
+            if let Some(previous) = pieces.last() {
+                if previous.end.to_string() == end.to_string() {
+                    continue;
+                }
             }
 
-            let piece_len = piece.len();
-            current_section.push(piece);
-            *current_length += piece_len;
+            // Find all notes that are active at this time
+            let active_notes = sweep.active_notes(current_time, next_time);
+            pieces.push(Piece {
+                end,
+                value: note_array(&active_notes),
+            });
+        }
+        pieces
+    }
+}
+
+/// Sweep over a song's note events that keeps track of the sounding notes
+///
+/// Windows must be visited in chronological order. Notes are added to the active
+/// set when their event starts and dropped once they stop sounding, so each note
+/// is only looked at while it is active.
+struct NoteSweep<'a> {
+    /// Note events sorted by start time, with their index in the song
+    events: Vec<(usize, &'a NoteEvent)>,
+    /// Index in `events` of the next event to start
+    next_event: usize,
+    /// Sounding notes keyed by event and note index, which keeps them in song order
+    active: BTreeMap<(usize, usize), &'a Note>,
+}
+
+impl<'a> NoteSweep<'a> {
+    /// Creates a sweep positioned before the first note event.
+    ///
+    /// # Arguments
+    /// * `note_changes` - Note events of the song
+    fn new(note_changes: &'a [NoteEvent]) -> Self {
+        let mut events: Vec<(usize, &NoteEvent)> = note_changes.iter().enumerate().collect();
+        events.sort_by_key(|(_, event)| event.timestamp);
+        Self {
+            events,
+            next_event: 0,
+            active: BTreeMap::new(),
         }
     }
 
-    /// Collects all notes that are active at a given time.
+    /// Advances to a window and collects the notes active in it, with their velocities.
+    ///
+    /// Notes with a velocity envelope are sampled halfway through the window, and
+    /// left out while the envelope makes them silent.
     ///
     /// # Arguments
-    /// * `current_time` - Time in seconds
+    /// * `current_time` - Time in microseconds
+    /// * `next_time` - Time in microseconds when the window ends
     ///
     /// # Returns
-    /// * `Vec<(MidiNote, Velocity, usize)>` - Vector of (note, velocity, soundfont_index) tuples
-    fn collect_active_notes(&self, current_time: f64) -> Vec<(MidiNote, Velocity, usize)> {
-        let mut active_notes = Vec::new();
-        for event in &self.note_changes {
-            let event_time = event.timestamp as f64 / 1000.0;
-            if event_time <= current_time {
-                // Add notes from this event that are still playing
-                for &(note, vel, sf, end_time) in &event.notes {
-                    if (end_time as f64 / 1000.0) > current_time {
-                        active_notes.push((note, vel, sf));
-                    }
-                }
+    /// * `Vec<(&Note, Velocity)>` - Vector of notes playing at the given time
+    fn active_notes(
+        &mut self,
+        current_time: Timestamp,
+        next_time: Timestamp,
+    ) -> Vec<(&'a Note, Velocity)> {
+        // Start the notes of every event that has begun
+        while let Some(&(index, event)) = self.events.get(self.next_event) {
+            if event.timestamp > current_time {
+                break;
+            }
+            for (note_index, note) in event.notes.iter().enumerate() {
+                self.active.insert((index, note_index), note);
             }
+            self.next_event += 1;
         }
 
-        // Sort notes for consistent output
-        active_notes.sort_unstable_by_key(|(note, _, _)| *note);
+        // Drop the notes that are no longer playing
+        self.active
+            .retain(|_, note| note.sounding_end() > current_time);
+
+        let sample_time = (current_time + next_time) as f64 / 2.0;
+        let mut active_notes: Vec<(&Note, Velocity)> = self
+            .active
+            .values()
+            .map(|&note| (note, note.velocity_at(sample_time)))
+            .filter(|(note, velocity)| *velocity > 0 || note.envelope.is_none())
+            .collect();
+
+        // Sort notes for consistent output, keeping instances of the same note in song order
+        active_notes.sort_by_key(|(note, _)| note.note);
         active_notes
     }
 }
 
-/// Formats a list of active notes into a Desmos array string.
+impl Note {
+    /// Returns the time in microseconds when the note stops sounding,
+    /// including the release of its envelope.
+    pub fn sounding_end(&self) -> Timestamp {
+        match self.envelope {
+            Some(envelope) if self.releases => self.end + envelope.release * US_PER_MS,
+            _ => self.end,
+        }
+    }
+
+    /// Returns the velocity of the note shaped by its envelope.
+    ///
+    /// # Arguments
+    /// * `time` - Time in microseconds
+    ///
+    /// # Returns
+    /// * `Velocity` - Velocity at the given time
+    pub fn velocity_at(&self, time: f64) -> Velocity {
+        let Some(envelope) = self.envelope else {
+            return self.velocity;
+        };
+
+        // Envelopes are given in milliseconds
+        let us_per_ms = US_PER_MS as f64;
+        let held = (time.min(self.end as f64) - self.onset as f64) / us_per_ms;
+        let gain = if time < self.end as f64 {
+            envelope.held_gain(held)
+        } else {
+            envelope.released_gain(held, (time - self.end as f64) / us_per_ms)
+        };
+        (self.velocity as f64 * gain).round() as Velocity
+    }
+}
+
+/// Builds the Desmos array of a list of active notes.
 ///
 /// # Arguments
-/// * `notes` - List of (note, velocity, soundfont_index) tuples
+/// * `notes` - List of active notes with their velocities
 ///
 /// # Returns
-/// * `String` - Desmos array representation
-fn format_note_array_simple(notes: &[(MidiNote, Velocity, usize)]) -> String {
-    let note_array: Vec<String> = notes
+/// * `Expression` - List of relative note, velocity, and soundfont index for each note
+fn note_array(notes: &[(&Note, Velocity)]) -> Expression {
+    let values = notes
         .iter()
-        .flat_map(|&(note, velocity, soundfont_idx)| {
-            vec![
-                midi_note_to_relative(note).to_string(),
-                velocity.to_string(),
-                soundfont_idx.to_string(),
+        .flat_map(|(note, velocity)| {
+            [
+                relative_note(note.note, note.bend),
+                Number::from(*velocity),
+                Number::from(note.soundfont),
             ]
         })
+        .map(Expression::Number)
         .collect();
-    format!("\\left[{}\\right]", note_array.join(","))
+    Expression::List(values)
+}
+
+/// Returns a note and its pitch bend offset as a relative note value.
+///
+/// Unbent notes are whole numbers. Bent notes are rounded to three decimal places,
+/// and written without trailing zeros (e.g. `-8.5`).
+///
+/// # Arguments
+/// * `note` - MIDI note number (0-127)
+/// * `bend` - Pitch bend offset in semitones
+///
+/// # Returns
+/// * `Number` - Relative note value
+fn relative_note(note: MidiNote, bend: f32) -> Number {
+    let relative = midi_note_to_relative(note) as f64;
+    if bend == 0.0 {
+        return Number::Shortest(relative);
+    }
+    Number::Shortest(round_decimals(relative + bend as f64, 3))
+}
+
+/// Converts a time to seconds.
+///
+/// # Arguments
+/// * `time` - Time in microseconds
+///
+/// # Returns
+/// * `f64` - Time in seconds
+fn seconds(time: Timestamp) -> f64 {
+    time as f64 / US_PER_SECOND
+}
+
+/// Converts a time to seconds and rounds it to a number of decimal places.
+///
+/// Other than the fixed decimal places written by the formula serializer, this is
+/// the only place where the microsecond timeline is rounded.
+///
+/// # Arguments
+/// * `time` - Time in microseconds
+/// * `precision` - Number of decimal places
+/// * `offset` - Number of seconds added to the time
+///
+/// # Returns
+/// * `f64` - Rounded time in seconds
+fn round_seconds(time: Timestamp, precision: usize, offset: f64) -> f64 {
+    round_decimals(seconds(time) + offset, precision)
+}
+
+/// Rounds a value to a number of decimal places, the same way as it would be
+/// rounded when written with that many decimal places.
+///
+/// # Arguments
+/// * `value` - Value to round
+/// * `decimals` - Number of decimal places
+///
+/// # Returns
+/// * `f64` - Rounded value
+fn round_decimals(value: f64, decimals: usize) -> f64 {
+    Number::Fixed(value, decimals)
+        .to_string()
+        .parse()
+        .unwrap_or(value)
 }
 
-/// Creates the main formula that selects the appropriate section based on time.
+/// Splits the pieces of the piecewise formula into sections.
+///
+/// A new section is started whenever adding a piece would make the current
+/// section longer than the maximum formula length.
 ///
 /// # Arguments
-/// * `formulas` - Vector of section formulas
-/// * `section_names` - Vector of section names (A_1, A_2, etc.)
+/// * `pieces` - Pieces of the whole song in order
 ///
 /// # Returns
-/// * `String` - Main formula that selects sections based on time
-fn create_main_formula(formulas: &[String], section_names: &[String]) -> String {
-    let mut main_pieces = Vec::new();
-
-    for (i, section_formula) in formulas.iter().enumerate() {
-        if let Some(last_time_str) = section_formula
-            .split("t<")
-            .filter(|s| s.contains(':'))
-            .last()
-            .and_then(|s| s.split(':').next())
+/// * `Vec<Vec<Piece>>` - Pieces of each section, with at least one (possibly empty) section
+fn split_sections(pieces: Vec<Piece>) -> Vec<Vec<Piece>> {
+    let mut sections = vec![Vec::new()];
+    let mut current_length = 0;
+    for piece in pieces {
+        let piece_length = piece.to_string().len();
+        if current_length + piece_length > MAX_FORMULA_LENGTH
+            && sections.last().is_some_and(|section| !section.is_empty())
         {
-            if let Ok(end_time) = last_time_str.parse::<f64>() {
-                main_pieces.push(format!("t<{:0.3}:{}", end_time, section_names[i]));
-            }
+            sections.push(Vec::new());
+            current_length = 0;
+        }
+        if let Some(section) = sections.last_mut() {
+            section.push(piece);
+        }
+        current_length += piece_length;
+    }
+    sections
+}
+
+/// Names the sections of the piecewise formula.
+///
+/// A single section is named `A`. Otherwise sections are named A_1, A_2, etc.,
+/// preceded by a main formula `A` that selects the appropriate section based on time.
+///
+/// # Arguments
+/// * `sections` - Pieces of each section
+/// * `precision` - Number of decimal places of the section end times
+///
+/// # Returns
+/// * `Vec<Assignment>` - Main formula followed by the sections
+fn name_sections(mut sections: Vec<Vec<Piece>>, precision: usize) -> Vec<Assignment> {
+    if sections.len() == 1 {
+        let section = sections.pop().unwrap_or_default();
+        return vec![Assignment::new("A", Expression::Piecewise(section))];
+    }
+
+    let names: Vec<String> = (1..=sections.len())
+        .map(|i| format!("A_{{{}}}", i))
+        .collect();
+    let main_pieces = sections
+        .iter()
+        .zip(&names)
+        .filter_map(|(section, name)| {
+            section.last().map(|piece| Piece {
+                end: Number::Fixed(piece.end.value(), precision),
+                value: Expression::Variable(name.clone()),
+            })
+        })
+        .collect();
+
+    std::iter::once(Assignment::new("A", Expression::Piecewise(main_pieces)))
+        .chain(
+            names
+                .into_iter()
+                .zip(sections)
+                .map(|(name, section)| Assignment::new(name, Expression::Piecewise(section))),
+        )
+        .collect()
+}
+
+/// Builds the formula `A` of a song without notes.
+fn silent_song() -> Assignment {
+    Assignment::new(
+        "A",
+        Expression::Piecewise(vec![Piece {
+            end: Number::Shortest(0.0),
+            value: Expression::List(Vec::new()),
+        }]),
+    )
+}
+
+/// Builds the formulas of the event-list encoding.
+///
+/// Each list is split into sections named `E_{start1}`, `E_{start2}`, etc. when it
+/// would be longer than the maximum formula length, and joined back together.
+///
+/// # Arguments
+/// * `rows` - Start time, end time, relative note, velocity, and soundfont index of each event
+///
+/// # Returns
+/// * `Vec<Assignment>` - Formula `A` filtering the active notes, followed by the lists
+fn event_list_assignments(rows: &[[Number; 5]]) -> Vec<Assignment> {
+    // The filter needs at least one event to index
+    if rows.is_empty() {
+        return vec![silent_song()];
+    }
+
+    let mut assignments = vec![
+        Assignment::new("A", Expression::Latex(EVENT_NOTES.to_string())),
+        Assignment::new("E_{active}", Expression::Latex(EVENT_ACTIVE.to_string())),
+    ];
+    let chunks = split_rows(rows);
+    for (column, list) in EVENT_LISTS.iter().enumerate() {
+        let mut lists: Vec<Expression> = chunks
+            .iter()
+            .map(|chunk| {
+                Expression::List(
+                    chunk
+                        .iter()
+                        .map(|row| Expression::Number(row[column]))
+                        .collect(),
+                )
+            })
+            .collect();
+        let name = format!("E_{{{}}}", list);
+        if lists.len() == 1 {
+            assignments.push(Assignment::new(name, lists.remove(0)));
+            continue;
         }
+
+        let names: Vec<String> = (1..=lists.len())
+            .map(|i| format!("E_{{{}{}}}", list, i))
+            .collect();
+        let sections = names.iter().cloned().map(Expression::Variable).collect();
+        assignments.push(Assignment::new(
+            name,
+            Expression::Call("join".to_string(), sections),
+        ));
+        assignments.extend(
+            names
+                .into_iter()
+                .zip(lists)
+                .map(|(name, list)| Assignment::new(name, list)),
+        );
     }
+    assignments
+}
 
-    format!("A=\\left\\{{{}\\right\\}}", main_pieces.join(","))
+/// Splits the rows of the event-list encoding into sections.
+///
+/// A new section is started whenever adding a row would make one of the section's
+/// lists longer than the maximum formula length.
+///
+/// # Arguments
+/// * `rows` - Rows of the whole song in order
+///
+/// # Returns
+/// * `Vec<&[[Number; 5]]>` - Rows of each section, with at least one section
+fn split_rows(rows: &[[Number; 5]]) -> Vec<&[[Number; 5]]> {
+    let mut sections = Vec::new();
+    let mut section_start = 0;
+    let mut lengths = [0; 5];
+    for (i, row) in rows.iter().enumerate() {
+        // Each number is followed by a comma
+        let row_lengths = row.map(|number| number.to_string().len() + 1);
+        let too_long = lengths
+            .iter()
+            .zip(&row_lengths)
+            .any(|(length, row_length)| length + row_length > MAX_FORMULA_LENGTH);
+        if too_long && i > section_start {
+            sections.push(&rows[section_start..i]);
+            section_start = i;
+            lengths = [0; 5];
+        }
+        for (length, row_length) in lengths.iter_mut().zip(row_lengths) {
+            *length += row_length;
+        }
+    }
+    sections.push(&rows[section_start..]);
+    sections
 }
 
-/// Adds the soundfont array (B) and size (C) formulas.
+/// Builds the soundfont array (B) and size (C) formulas.
 ///
 /// # Arguments
-/// * `formulas` - Vector to append formulas to
 /// * `soundfonts` - SoundFontMap containing the soundfonts
-fn add_soundfont_formulas(formulas: &mut Vec<String>, soundfonts: &SoundFontMap) {
-    let soundfont_values: Vec<String> = soundfonts
+///
+/// # Returns
+/// * `[Assignment; 2]` - Formulas `B` and `C`
+fn soundfont_assignments(soundfonts: &SoundFontMap) -> [Assignment; 2] {
+    let weights = soundfonts
         .fonts
         .iter()
-        .flat_map(|font| font.iter().map(|v| v.to_string()))
+        .flat_map(|font| font.iter().map(|&weight| Expression::Number(weight.into())))
         .collect();
-    formulas.push(format!("B=\\left[{}\\right]", soundfont_values.join(",")));
-    formulas.push(format!("C={}", soundfonts.max_size));
+    [
+        Assignment::new("B", Expression::List(weights)),
+        Assignment::new("C", Expression::Number(soundfonts.max_size.into())),
+    ]
 }
 
 /// Converts a MIDI note number to a relative note value.
@@ -380,6 +1674,30 @@ pub enum MidiError {
     #[error("Soundfont mismatch: {0}")]
     SoundfontMismatch(String),
 
+    #[error("Invalid envelope: {0}")]
+    InvalidEnvelope(String),
+
+    #[error("Invalid trim point: {0}")]
+    InvalidTrim(String),
+
+    #[error("Invalid quantization grid: {0}")]
+    InvalidGrid(String),
+
+    #[error("Invalid expression: {0}")]
+    InvalidExpression(String),
+
+    #[error("Invalid formula: {0}")]
+    InvalidFormula(String),
+
+    #[error("Invalid options: {0}")]
+    InvalidOptions(String),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("WAV error: {0}")]
+    Wav(#[from] hound::Error),
+
     #[error("Parsing error: {0}")]
     Parse(#[from] std::num::ParseFloatError),
 
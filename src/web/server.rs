@@ -6,8 +6,14 @@ use axum::{
     Router,
 };
 use clap::Parser;
-use desmos_midi::audio::{analyze_harmonics, read_wav_file, AnalysisConfig, AudioError};
+use desmos_midi::audio::{
+    analyze_harmonics_detailed, read_wav_file, AnalysisConfig, AudioError, AveragedHarmonics,
+    Averaging, HarmonicAnalysis, WindowFunction, DEFAULT_KAISER_BETA,
+};
 use desmos_midi::config;
+use desmos_midi::midi::{
+    format_latex, validate_expressions, MidiError, ProcessingOptions, SoundfontMapping, TrimPoint,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
@@ -50,6 +56,7 @@ struct AppState {
 #[derive(Serialize)]
 struct MidiInfoResponse {
     channels: Vec<ChannelInfo>,
+    tracks: Vec<TrackInfo>,
 }
 
 #[derive(Serialize)]
@@ -59,6 +66,14 @@ struct ChannelInfo {
     is_drum: bool,
 }
 
+#[derive(Serialize)]
+struct TrackInfo {
+    index: usize,
+    name: Option<String>,
+    channels: Vec<u8>,
+    is_drum: bool,
+}
+
 // Response for conversion
 #[derive(Serialize)]
 struct ConversionResponse {
@@ -70,6 +85,76 @@ struct ConversionResponse {
 struct ConversionRequest {
     filename: String,
     soundfonts: Vec<String>,
+    #[serde(rename = "sustainPedal")]
+    sustain_pedal: Option<bool>,
+    #[serde(rename = "sostenutoPedal")]
+    sostenuto_pedal: Option<bool>,
+    #[serde(rename = "channelVolume")]
+    channel_volume: Option<bool>,
+    #[serde(rename = "timePrecision")]
+    time_precision: Option<usize>,
+    optimize: Option<bool>,
+    // Seconds (12.5), bar:beat (17:1), or ticks (7680t)
+    #[serde(rename = "trimStart")]
+    trim_start: Option<String>,
+    #[serde(rename = "trimEnd")]
+    trim_end: Option<String>,
+    #[serde(rename = "keepLeadingSilence")]
+    keep_leading_silence: Option<bool>,
+    // One of "channel", "channelProgram", "program", or "track"
+    #[serde(rename = "soundfontMapping")]
+    soundfont_mapping: Option<String>,
+}
+
+impl ConversionRequest {
+    // Build MIDI processing options, using defaults for anything not specified
+    fn processing_options(&self) -> Result<ProcessingOptions, (StatusCode, String)> {
+        let defaults = ProcessingOptions::default();
+        let options = ProcessingOptions {
+            sustain_pedal: self.sustain_pedal.unwrap_or(defaults.sustain_pedal),
+            sostenuto_pedal: self.sostenuto_pedal.unwrap_or(defaults.sostenuto_pedal),
+            channel_volume: self.channel_volume.unwrap_or(defaults.channel_volume),
+            time_precision: self.time_precision.unwrap_or(defaults.time_precision),
+            optimize: self.optimize.unwrap_or(defaults.optimize),
+            trim_start: parse_trim_point(self.trim_start.as_deref())?,
+            trim_end: parse_trim_point(self.trim_end.as_deref())?,
+            strip_leading_silence: self
+                .keep_leading_silence
+                .map_or(defaults.strip_leading_silence, |keep| !keep),
+            ..defaults
+        };
+        options
+            .validate()
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        Ok(options)
+    }
+
+    // Parse how soundfonts are assigned, defaulting to one per channel
+    fn soundfont_mapping(&self) -> Result<SoundfontMapping, (StatusCode, String)> {
+        match self.soundfont_mapping.as_deref() {
+            None | Some("channel") => Ok(SoundfontMapping::Channel),
+            Some("channelProgram") => Ok(SoundfontMapping::ChannelProgram),
+            Some("program") => Ok(SoundfontMapping::Program),
+            Some("track") => Ok(SoundfontMapping::Track),
+            Some(other) => Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown soundfont mapping: {}", other),
+            )),
+        }
+    }
+}
+
+// Parse an optional trim point, treating an empty one as not given
+fn parse_trim_point(point: Option<&str>) -> Result<Option<TrimPoint>, (StatusCode, String)> {
+    point
+        .map(str::trim)
+        .filter(|point| !point.is_empty())
+        .map(|point| {
+            point
+                .parse()
+                .map_err(|e: MidiError| (StatusCode::BAD_REQUEST, e.to_string()))
+        })
+        .transpose()
 }
 
 // Request for refreshing file expiration
@@ -82,6 +167,15 @@ struct RefreshFileRequest {
 #[derive(Serialize)]
 struct HarmonicResponse {
     harmonics: Vec<f32>,
+    #[serde(rename = "baseFreq")]
+    base_freq: f32,
+    // Null when the base frequency was given rather than detected
+    confidence: Option<f32>,
+    // Variance of each harmonic across the averaged frames
+    variance: Vec<f32>,
+    frames: usize,
+    // Measurements of every harmonic
+    analysis: HarmonicAnalysis,
 }
 
 // Query parameters for harmonic analysis
@@ -94,6 +188,18 @@ struct HarmonicParams {
     base_freq: Option<f32>,
     harmonics: Option<usize>,
     boost: Option<f32>,
+    // Average frames from the start time up to this time
+    #[serde(rename = "endTime")]
+    end_time: Option<f32>,
+    overlap: Option<f32>,
+    // Either "mean" or "median"
+    averaging: Option<String>,
+    // One of "hann", "hamming", "blackmanHarris", "flatTop", or "kaiser"
+    window: Option<String>,
+    #[serde(rename = "kaiserBeta")]
+    kaiser_beta: Option<f32>,
+    #[serde(rename = "zeroPadding")]
+    zero_padding: Option<usize>,
 }
 
 // Get the directory where static files are located
@@ -184,6 +290,7 @@ async fn main() {
         .route("/upload", post(upload_handler))
         .route("/midi-info/{filename}", get(midi_info_handler))
         .route("/convert", post(convert_handler))
+        .route("/player", post(player_handler))
         .route("/soundfonts", get(list_soundfonts_handler))
         .route("/refresh-file", post(refresh_file_handler))
         .route("/getfile/{filename}", get(get_file_handler))
@@ -512,7 +619,18 @@ async fn midi_info_handler(
         })
         .collect();
 
-    Ok(Json(MidiInfoResponse { channels }))
+    let tracks = song
+        .tracks
+        .iter()
+        .map(|track| TrackInfo {
+            index: track.index + 1, // Tracks are 1-based in display
+            name: track.name.clone(),
+            channels: track.channels.iter().map(|ch| ch + 1).collect(),
+            is_drum: track.is_drum(),
+        })
+        .collect();
+
+    Ok(Json(MidiInfoResponse { channels, tracks }))
 }
 
 // Handler for converting MIDI files
@@ -520,6 +638,47 @@ async fn convert_handler(
     State(state): State<Arc<AppState>>,
     Json(request): Json<ConversionRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let song = process_conversion(&state, request)?;
+
+    // Convert to Desmos formula, checking it before it is sent
+    let expressions = song.to_expressions();
+    validate_expressions(&expressions).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to create formula: {}", e),
+        )
+    })?;
+    let formula = format_latex(&expressions);
+
+    Ok(Json(ConversionResponse { formula }))
+}
+
+// Handler for creating a standalone page that plays a converted MIDI file
+async fn player_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ConversionRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // Title the player after the MIDI file
+    let title = ::desmos_midi::midi::song_title(&request.filename);
+    let song = process_conversion(&state, request)?;
+
+    let html = song
+        .to_html_player(&title, state.config.desmos_api_key.as_deref())
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to create HTML player: {}", e),
+            )
+        })?;
+
+    Ok(Html(html))
+}
+
+// Process an uploaded MIDI file with the soundfonts and options of a conversion request
+fn process_conversion(
+    state: &AppState,
+    request: ConversionRequest,
+) -> Result<::desmos_midi::midi::ProcessedSong, (StatusCode, String)> {
     // Check if the file exists
     let file_path = state.temp_dir.join(&request.filename);
     if !file_path.exists() {
@@ -529,28 +688,27 @@ async fn convert_handler(
     // Refresh the file expiration
     {
         let mut expirations = state.file_expirations.lock().unwrap();
-        expirations.insert(request.filename, Instant::now());
+        expirations.insert(request.filename.clone(), Instant::now());
     }
 
     // Create MIDI processor with soundfont directory from config
     let processor = ::desmos_midi::midi::MidiProcessor::with_soundfont_dir(
         state.soundfont_dir.to_str().unwrap(),
-    );
+    )
+    .with_options(request.processing_options()?)
+    .with_soundfont_mapping(request.soundfont_mapping()?);
 
     // Process the MIDI file with soundfonts
-    let song = processor
+    processor
         .process_with_soundfonts(file_path.to_str().unwrap(), request.soundfonts)
         .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to process MIDI file: {}", e),
-            )
-        })?;
-
-    // Convert to Desmos formula
-    let formula = song.to_piecewise_function();
-
-    Ok(Json(ConversionResponse { formula }))
+            // Trim points that do not fit the file are a problem with the request
+            let status = match e {
+                MidiError::InvalidTrim(_) | MidiError::InvalidGrid(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, format!("Failed to process MIDI file: {}", e))
+        })
 }
 
 // Handler for refreshing file expiration
@@ -655,7 +813,7 @@ async fn list_soundfonts_handler(
         )
     })? {
         let path = entry.path();
-        if path.is_file() && path.extension().map_or(false, |ext| ext == "txt") {
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "txt") {
             if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
                 soundfonts.push(name.to_string());
             }
@@ -728,8 +886,7 @@ async fn harmonic_info_handler(
         .clamp(limits.min_start_time, limits.max_start_time);
     let base_freq = params
         .base_freq
-        .unwrap_or(440.0)
-        .clamp(limits.min_base_freq, limits.max_base_freq);
+        .map(|base_freq| base_freq.clamp(limits.min_base_freq, limits.max_base_freq));
     let harmonics = params
         .harmonics
         .unwrap_or(16)
@@ -739,12 +896,59 @@ async fn harmonic_info_handler(
         .unwrap_or(1.0)
         .clamp(limits.min_boost, limits.max_boost);
 
+    let end_time = params
+        .end_time
+        .map(|end_time| end_time.clamp(limits.min_start_time, limits.max_start_time));
+    let overlap = params.overlap.unwrap_or(0.5).clamp(0.0, 0.95);
+    let averaging = match params.averaging.as_deref() {
+        None | Some("mean") => Averaging::Mean,
+        Some("median") => Averaging::Median,
+        Some(other) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown averaging: {}", other),
+            ))
+        }
+    };
+    // A beta selects the Kaiser window if no window is given
+    let window_name = params
+        .window
+        .as_deref()
+        .or(params.kaiser_beta.map(|_| "kaiser"));
+    let window = match window_name {
+        None | Some("hann") => WindowFunction::Hann,
+        Some("hamming") => WindowFunction::Hamming,
+        Some("blackmanHarris") => WindowFunction::BlackmanHarris,
+        Some("flatTop") => WindowFunction::FlatTop,
+        Some("kaiser") => WindowFunction::Kaiser {
+            beta: params.kaiser_beta.unwrap_or(DEFAULT_KAISER_BETA),
+        },
+        Some(other) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown window: {}", other),
+            ))
+        }
+    };
+    if params.kaiser_beta.is_some() && !matches!(window, WindowFunction::Kaiser { .. }) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "kaiserBeta only applies to the Kaiser window".to_string(),
+        ));
+    }
+    let zero_padding = params.zero_padding.unwrap_or(1);
+
     let analysis_config = AnalysisConfig {
         samples,
         start_time,
         base_freq,
         num_harmonics: harmonics,
         boost,
+        end_time,
+        overlap,
+        averaging,
+        window,
+        zero_padding,
     };
 
     // Check if the file exists
@@ -769,14 +973,23 @@ async fn harmonic_info_handler(
         ),
     })?;
 
-    let harmonics = analyze_harmonics(&wav_data, &analysis_config).map_err(|e| match e {
-        AudioError::InvalidParams(msg) => (StatusCode::BAD_REQUEST, msg),
-        AudioError::ProcessingError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-        _ => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error analyzing WAV file: {}", e),
-        ),
-    })?;
+    let analysis =
+        analyze_harmonics_detailed(&wav_data, &analysis_config).map_err(|e| match e {
+            AudioError::InvalidParams(msg) => (StatusCode::BAD_REQUEST, msg),
+            AudioError::ProcessingError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error analyzing WAV file: {}", e),
+            ),
+        })?;
 
-    Ok(Json(HarmonicResponse { harmonics }))
+    let averaged = AveragedHarmonics::from(&analysis);
+    Ok(Json(HarmonicResponse {
+        harmonics: averaged.weights,
+        base_freq: analysis.base_freq,
+        confidence: analysis.pitch_confidence,
+        variance: averaged.variance,
+        frames: averaged.frames,
+        analysis,
+    }))
 }
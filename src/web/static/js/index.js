@@ -4,6 +4,7 @@ import * as FileManager from './modules/FileManager.js';
 let uploadedFilename = null;
 let availableSoundfonts = [];
 let channelInfo = [];
+let convertedSoundfonts = null;
 
 // DOM elements
 const uploadArea = document.getElementById('upload-area');
@@ -20,6 +21,7 @@ const resultArea = document.getElementById('result-area');
 const convertLoading = document.getElementById('convert-loading');
 const copyBtn = document.getElementById('copy-btn');
 const copySuccess = document.getElementById('copy-success');
+const downloadBtn = document.getElementById('download-btn');
 
 // Event listeners
 uploadArea.addEventListener('click', () => fileInput.click());
@@ -29,6 +31,7 @@ uploadArea.addEventListener('drop', handleDrop);
 fileInput.addEventListener('change', handleFileSelect);
 convertBtn.addEventListener('click', convertMidi);
 copyBtn.addEventListener('click', copyToClipboard);
+downloadBtn.addEventListener('click', downloadPlayer);
 
 // Initialize by loading available soundfonts
 loadSoundfonts();
@@ -180,12 +183,15 @@ async function convertMidi() {
     step3.classList.remove('hidden');
     resultArea.textContent = '';
     copyBtn.disabled = true;
+    downloadBtn.disabled = true;
 
     try {
         // Convert the MIDI file using the file manager
         const data = await FileManager.convertMidi(uploadedFilename, soundfonts);
         resultArea.textContent = data.formula;
+        convertedSoundfonts = soundfonts;
         copyBtn.disabled = false;
+        downloadBtn.disabled = false;
     } catch (error) {
         showError(error.message);
     } finally {
@@ -206,6 +212,29 @@ function copyToClipboard() {
         });
 }
 
+async function downloadPlayer() {
+    downloadBtn.disabled = true;
+
+    try {
+        // Create the page with the soundfonts of the last conversion
+        const html = await FileManager.createPlayer(uploadedFilename, convertedSoundfonts);
+        const blob = new Blob([html], { type: 'text/html' });
+        const url = URL.createObjectURL(blob);
+
+        // Name the page after the MIDI file
+        const link = document.createElement('a');
+        link.href = url;
+        link.download = uploadedFilename.replace(/\.midi?$/i, '') + '.html';
+        link.click();
+
+        URL.revokeObjectURL(url);
+    } catch (error) {
+        showError(error.message);
+    } finally {
+        downloadBtn.disabled = false;
+    }
+}
+
 function showError(message) {
     uploadError.textContent = message;
     uploadError.classList.remove('hidden');
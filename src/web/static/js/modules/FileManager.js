@@ -84,6 +84,32 @@ export async function convertMidi(filename, soundfonts) {
     return await response.json();
 }
 
+/**
+ * Create a standalone page that plays a MIDI file with selected soundfonts
+ * @param {string} filename - The filename to convert
+ * @param {Array<string>} soundfonts - Array of soundfont selections
+ * @returns {Promise<string>} - Promise resolving to the HTML of the page
+ */
+export async function createPlayer(filename, soundfonts) {
+    const response = await fetch('/player', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({
+            filename: filename,
+            soundfonts: soundfonts
+        })
+    });
+
+    if (!response.ok) {
+        const errorText = await response.text();
+        throw new Error(errorText || 'Failed to create player');
+    }
+
+    return await response.text();
+}
+
 /**
  * Get a file from the server
  * @param {string} filename - The filename to retrieve
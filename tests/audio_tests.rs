@@ -37,6 +37,7 @@ fn test_wav_file_loading() {
 /// - Extraction of harmonic weights with FFT analysis
 /// - Verification that sine wave has mainly fundamental harmonic
 #[test]
+#[allow(clippy::needless_range_loop)]
 fn test_harmonic_analysis() {
     let wav_path = Path::new("tests/samples/440hz_44100hz_16bit_5sec.wav");
     let wav_data = audio::read_wav_file(wav_path).expect("Failed to load WAV file");
@@ -44,9 +45,10 @@ fn test_harmonic_analysis() {
     let config = audio::AnalysisConfig {
         samples: 8192,
         start_time: 0.0,
-        base_freq: 440.0,
+        base_freq: Some(440.0),
         num_harmonics: 16,
         boost: 1.0,
+        ..Default::default()
     };
 
     let harmonics =
@@ -87,9 +89,10 @@ fn test_analysis_config_validation() {
     let valid_config = audio::AnalysisConfig {
         samples: 8192,
         start_time: 0.0,
-        base_freq: 440.0,
+        base_freq: Some(440.0),
         num_harmonics: 16,
         boost: 1.0,
+        ..Default::default()
     };
     assert!(valid_config.validate(&wav_data).is_ok());
 
@@ -97,9 +100,10 @@ fn test_analysis_config_validation() {
     let invalid_config = audio::AnalysisConfig {
         samples: 8192,
         start_time: 10.0, // Way beyond the 5-second sample
-        base_freq: 440.0,
+        base_freq: Some(440.0),
         num_harmonics: 16,
         boost: 1.0,
+        ..Default::default()
     };
     assert!(invalid_config.validate(&wav_data).is_err());
 }
@@ -125,9 +129,10 @@ fn test_audio_error_cases() {
     let invalid_nyquist_config = audio::AnalysisConfig {
         samples: 8192,
         start_time: 0.0,
-        base_freq: 5000.0, // High base frequency
-        num_harmonics: 16, // With 16 harmonics will exceed Nyquist frequency
+        base_freq: Some(5000.0), // High base frequency
+        num_harmonics: 16,       // With 16 harmonics will exceed Nyquist frequency
         boost: 1.0,
+        ..Default::default()
     };
 
     // Analyze harmonics should fail with high frequency harmonics
@@ -137,3 +142,352 @@ fn test_audio_error_cases() {
         "Should return error for harmonics exceeding Nyquist frequency"
     );
 }
+
+/// Test detection of the fundamental frequency.
+///
+/// This test verifies:
+/// - Detection of the 440Hz pitch of a sine wave with high confidence
+/// - Harmonic analysis without a base frequency matches analysis at the detected one
+/// - Error reporting for audio too short to detect a pitch in
+#[test]
+fn test_pitch_detection() {
+    let wav_path = Path::new("tests/samples/440hz_44100hz_16bit_5sec.wav");
+    let wav_data = audio::read_wav_file(wav_path).expect("Failed to load WAV file");
+
+    let config = audio::AnalysisConfig {
+        samples: 8192,
+        start_time: 0.5,
+        base_freq: None,
+        num_harmonics: 16,
+        boost: 1.0,
+        ..Default::default()
+    };
+
+    let detected =
+        audio::analyze_harmonics_detailed(&wav_data, &config).expect("Failed to detect pitch");
+    assert!(
+        (detected.base_freq - 440.0).abs() < 1.0,
+        "Detected {}Hz instead of 440Hz",
+        detected.base_freq
+    );
+    let confidence = detected
+        .pitch_confidence
+        .expect("Detected pitch should have a confidence");
+    assert!(confidence > 0.9, "Sine wave should be clearly periodic");
+
+    // Analysis without a base frequency uses the detected one
+    let given = audio::analyze_harmonics(
+        &wav_data,
+        &audio::AnalysisConfig {
+            base_freq: Some(detected.base_freq),
+            ..config
+        },
+    )
+    .expect("Failed to analyze harmonics");
+    assert_eq!(detected.soundfont(), given);
+    assert!(given[0] > 0.9, "First harmonic should be close to 1.0");
+
+    // Too few samples to find a period in
+    let short_config = audio::AnalysisConfig {
+        samples: 4,
+        start_time: 0.0,
+        base_freq: None,
+        num_harmonics: 16,
+        boost: 1.0,
+        ..Default::default()
+    };
+    assert!(audio::analyze_harmonics(&wav_data, &short_config).is_err());
+}
+
+/// Test harmonic analysis averaged over several frames.
+///
+/// This test verifies:
+/// - Overlapping frames are taken across the whole time range
+/// - Mean and median averaging both keep a sine wave's fundamental dominant
+/// - A steady sine wave has near-zero variance in every harmonic
+/// - A single frame matches plain harmonic analysis
+/// - The averaged weights and variances match the detailed analysis
+/// - Error reporting for time ranges shorter than a frame or past the end
+#[test]
+fn test_frame_averaging() {
+    let wav_path = Path::new("tests/samples/440hz_44100hz_16bit_5sec.wav");
+    let wav_data = audio::read_wav_file(wav_path).expect("Failed to load WAV file");
+
+    let config = audio::AnalysisConfig {
+        samples: 8192,
+        start_time: 0.0,
+        base_freq: Some(440.0),
+        num_harmonics: 16,
+        boost: 1.0,
+        end_time: Some(2.0),
+        overlap: 0.5,
+        averaging: audio::Averaging::Mean,
+        ..Default::default()
+    };
+
+    // 88200 samples hold frames starting every 4096 samples up to 80000
+    let mean = audio::analyze_harmonics_detailed(&wav_data, &config).expect("Failed to analyze");
+    assert_eq!(mean.frames, 20);
+    assert_eq!(mean.harmonics.len(), 16);
+    assert!(
+        mean.harmonics[0].weight > 0.9,
+        "First harmonic should be close to 1.0"
+    );
+    for harmonic in &mean.harmonics {
+        assert!(
+            harmonic.variance < 0.01,
+            "A steady sine wave should not vary"
+        );
+    }
+
+    let median = audio::analyze_harmonics_detailed(
+        &wav_data,
+        &audio::AnalysisConfig {
+            averaging: audio::Averaging::Median,
+            ..config
+        },
+    )
+    .expect("Failed to analyze");
+    assert_eq!(median.frames, 20);
+    let weights = median.soundfont();
+    assert!(weights[0] > 0.9, "First harmonic should be close to 1.0");
+    for harmonic in weights.iter().skip(1) {
+        assert!(*harmonic < 0.1, "Higher harmonics should be close to 0.0");
+    }
+
+    // A single frame gives the same weights as plain analysis, without variance
+    let single_config = audio::AnalysisConfig {
+        end_time: None,
+        ..config
+    };
+    let single = audio::analyze_harmonics_detailed(&wav_data, &single_config).expect("Failed");
+    assert_eq!(single.frames, 1);
+    assert!(single.harmonics.iter().all(|h| h.variance == 0.0));
+    assert_eq!(
+        single.soundfont(),
+        audio::analyze_harmonics(&wav_data, &single_config).expect("Failed")
+    );
+
+    let averaged = audio::analyze_harmonic_frames(&wav_data, &config).expect("Failed");
+    assert_eq!(averaged.frames, 20);
+    assert_eq!(averaged.weights, mean.soundfont());
+    assert!(averaged
+        .variance
+        .iter()
+        .zip(&mean.harmonics)
+        .all(|(variance, harmonic)| *variance == harmonic.variance));
+
+    // The range must hold at least one frame and end within the file
+    let short_config = audio::AnalysisConfig {
+        end_time: Some(0.1),
+        ..config
+    };
+    assert!(audio::analyze_harmonics_detailed(&wav_data, &short_config).is_err());
+    let late_config = audio::AnalysisConfig {
+        end_time: Some(10.0),
+        ..config
+    };
+    assert!(audio::analyze_harmonics_detailed(&wav_data, &late_config).is_err());
+}
+
+/// Test detailed measurements of each harmonic.
+///
+/// This test verifies:
+/// - The peak of a full-scale 440Hz sine wave is found at 440Hz
+/// - Raw magnitudes and levels are measured relative to full scale
+/// - The fundamental stands far above the noise floor and the noise overall
+/// - The weights of a synthetic signal follow the amplitudes of its harmonics
+/// - Inharmonicity is near zero for whole multiples and grows when they are stretched
+/// - The analysis serializes to JSON with camelCase fields
+#[test]
+fn test_detailed_analysis() {
+    let wav_path = Path::new("tests/samples/440hz_44100hz_16bit_5sec.wav");
+    let wav_data = audio::read_wav_file(wav_path).expect("Failed to load WAV file");
+
+    let config = audio::AnalysisConfig {
+        samples: 8192,
+        start_time: 0.0,
+        base_freq: Some(440.0),
+        num_harmonics: 16,
+        boost: 1.0,
+        ..Default::default()
+    };
+
+    let analysis =
+        audio::analyze_harmonics_detailed(&wav_data, &config).expect("Failed to analyze");
+    assert_eq!(analysis.base_freq, 440.0);
+    assert_eq!(analysis.pitch_confidence, None);
+    assert_eq!(analysis.frames, 1);
+
+    let fundamental = &analysis.harmonics[0];
+    assert_eq!(fundamental.number, 1);
+    assert_eq!(fundamental.frequency, 440.0);
+    assert!(
+        (fundamental.peak_frequency - 440.0).abs() < 1.0,
+        "Peak found at {}Hz instead of 440Hz",
+        fundamental.peak_frequency
+    );
+    assert!(fundamental.magnitude > 0.0 && fundamental.magnitude <= 1.05);
+    assert!(fundamental.db <= 0.5, "A sample cannot exceed full scale");
+    assert!(
+        fundamental.db - fundamental.noise_floor_db > 40.0,
+        "Fundamental should stand far above the noise floor"
+    );
+    assert!(analysis.harmonics[1].db < fundamental.db - 40.0);
+    assert!(analysis.snr_db > 30.0, "A clean sine wave has little noise");
+    assert!(analysis.inharmonicity.is_finite());
+
+    let json = serde_json::to_value(&analysis).expect("Failed to serialize analysis");
+    assert_eq!(json["baseFreq"], 440.0);
+    assert_eq!(json["harmonics"].as_array().map(Vec::len), Some(16));
+    assert!(json["harmonics"][0]["peakFrequency"].is_number());
+    assert!(json["harmonics"][0]["noiseFloorDb"].is_number());
+    assert!(json["snrDb"].is_number());
+
+    // Harmonics at amplitudes of 0.6, 0.3, and 0.15, optionally stretched sharp
+    let synthetic = |stretch: f32| {
+        let sample_rate = 44100;
+        let samples = (0..sample_rate)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                [0.6, 0.3, 0.15]
+                    .iter()
+                    .enumerate()
+                    .map(|(n, amplitude)| {
+                        let number = (n + 1) as f32;
+                        let frequency = 440.0 * number + stretch * (number - 1.0);
+                        amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin()
+                    })
+                    .sum()
+            })
+            .collect();
+        audio::WavData {
+            samples,
+            sample_rate,
+            channels: 1,
+        }
+    };
+    let synthetic_config = audio::AnalysisConfig {
+        num_harmonics: 4,
+        ..config
+    };
+
+    let harmonic = audio::analyze_harmonics_detailed(&synthetic(0.0), &synthetic_config)
+        .expect("Failed to analyze");
+    for (weight, expected) in harmonic.soundfont().iter().zip([1.0, 0.5, 0.25, 0.0]) {
+        assert!(
+            (weight - expected).abs() < 0.01,
+            "Weight {} should be {}",
+            weight,
+            expected
+        );
+    }
+    assert!(
+        harmonic.inharmonicity < 0.5,
+        "Whole multiples measured {} cents from harmonic",
+        harmonic.inharmonicity
+    );
+
+    let stretched = audio::analyze_harmonics_detailed(&synthetic(3.0), &synthetic_config)
+        .expect("Failed to analyze");
+    assert!(
+        stretched.inharmonicity > 2.0,
+        "Stretched partials measured only {} cents from harmonic",
+        stretched.inharmonicity
+    );
+}
+
+/// Test window functions and zero-padding.
+///
+/// This test verifies:
+/// - Every window measures the amplitude and frequency of harmonics falling
+///   between FFT bins, with and without zero-padding
+/// - Error reporting for zero-padding factors and Kaiser betas out of range
+#[test]
+fn test_window_functions() {
+    // Half-scale 443.7Hz sine wave with a second harmonic at 0.15
+    let sample_rate = 44100;
+    let samples = (0..sample_rate)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            0.5 * (2.0 * std::f32::consts::PI * 443.7 * t).sin()
+                + 0.15 * (2.0 * std::f32::consts::PI * 887.4 * t + 1.0).sin()
+        })
+        .collect();
+    let wav_data = audio::WavData {
+        samples,
+        sample_rate,
+        channels: 1,
+    };
+
+    let windows = [
+        audio::WindowFunction::Hann,
+        audio::WindowFunction::Hamming,
+        audio::WindowFunction::BlackmanHarris,
+        audio::WindowFunction::FlatTop,
+        audio::WindowFunction::Kaiser { beta: 8.6 },
+    ];
+    for window in windows {
+        for zero_padding in [1, 4] {
+            let config = audio::AnalysisConfig {
+                base_freq: Some(443.7),
+                num_harmonics: 4,
+                window,
+                zero_padding,
+                ..Default::default()
+            };
+            let analysis =
+                audio::analyze_harmonics_detailed(&wav_data, &config).expect("Failed to analyze");
+
+            for (harmonic, amplitude) in analysis.harmonics.iter().zip([0.5, 0.15]) {
+                assert!(
+                    (harmonic.magnitude - amplitude).abs() < 0.005,
+                    "{:?} padded {}x measured harmonic {} at {} instead of {}",
+                    window,
+                    zero_padding,
+                    harmonic.number,
+                    harmonic.magnitude,
+                    amplitude
+                );
+                assert!(
+                    (harmonic.peak_frequency - harmonic.frequency).abs() < 0.1,
+                    "{:?} padded {}x found harmonic {} at {}Hz instead of {}Hz",
+                    window,
+                    zero_padding,
+                    harmonic.number,
+                    harmonic.peak_frequency,
+                    harmonic.frequency
+                );
+            }
+            assert!((analysis.harmonics[1].weight - 0.3).abs() < 0.01);
+        }
+    }
+
+    let unpadded_config = audio::AnalysisConfig {
+        base_freq: Some(443.7),
+        zero_padding: 0,
+        ..Default::default()
+    };
+    assert!(audio::analyze_harmonics(&wav_data, &unpadded_config).is_err());
+    let overpadded_config = audio::AnalysisConfig {
+        base_freq: Some(443.7),
+        zero_padding: audio::MAX_ZERO_PADDING + 1,
+        ..Default::default()
+    };
+    assert!(audio::analyze_harmonics(&wav_data, &overpadded_config).is_err());
+
+    let kaiser_config = audio::AnalysisConfig {
+        base_freq: Some(443.7),
+        window: audio::WindowFunction::Kaiser { beta: -1.0 },
+        ..Default::default()
+    };
+    assert!(audio::analyze_harmonics(&wav_data, &kaiser_config).is_err());
+    let steep_kaiser_config = audio::AnalysisConfig {
+        base_freq: Some(443.7),
+        window: audio::WindowFunction::Kaiser {
+            beta: audio::MAX_KAISER_BETA + 1.0,
+        },
+        ..Default::default()
+    };
+    assert!(audio::analyze_harmonics(&wav_data, &steep_kaiser_config).is_err());
+}
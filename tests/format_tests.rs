@@ -12,12 +12,43 @@ use desmos_midi::midi;
 
 // Import the test utils
 mod test_utils;
-use test_utils::{SAMPLES_DIR, SINE_SOUNDFONT, SQUARE_SOUNDFONT};
+use test_utils::{SAMPLES_DIR, SINE_PLUCK_SOUNDFONT, SINE_SOUNDFONT, SQUARE_SOUNDFONT};
 
 // Constants for expected outputs
 const EXPECTED_C4_CHROMATIC_60BPM: &str = "A=\\left\\{t<1.000:\\left[-9,100,0\\right],t<2.000:\\left[-8,100,0\\right],t<3.000:\\left[-7,100,0\\right],t<4.000:\\left[-6,100,0\\right],t<5.000:\\left[-5,100,0\\right],t<6.000:\\left[-4,100,0\\right],t<7.000:\\left[-3,100,0\\right],t<8.000:\\left[-2,100,0\\right],t<9.000:\\left[-1,100,0\\right],t<10.000:\\left[0,100,0\\right],t<11.000:\\left[1,100,0\\right],t<12.000:\\left[2,100,0\\right],t<13.000:\\left[3,100,0\\right],t<13.1:\\left[\\right]\\right\\}";
 const EXPECTED_C4_CHROMATIC_DYNAMICBPM: &str = "A=\\left\\{t<1.000:\\left[-9,100,0\\right],t<2.000:\\left[-8,100,0\\right],t<3.000:\\left[-7,100,0\\right],t<4.000:\\left[-6,100,0\\right],t<4.250:\\left[-5,100,0\\right],t<4.500:\\left[-4,100,0\\right],t<4.750:\\left[-3,100,0\\right],t<5.000:\\left[-2,100,0\\right],t<5.250:\\left[-1,100,0\\right],t<5.500:\\left[0,100,0\\right],t<5.750:\\left[1,100,0\\right],t<6.000:\\left[2,100,0\\right],t<6.250:\\left[3,100,0\\right],t<6.35:\\left[\\right]\\right\\}";
 const EXPECTED_C4C5_CHROMATIC_PIANO_SAX_DYNAMICBPM: &str = "A=\\left\\{t<1.000:\\left[-9,100,0,3,100,1\\right],t<2.000:\\left[-8,100,0,2,100,1\\right],t<3.000:\\left[-7,100,0,1,100,1\\right],t<4.000:\\left[-6,100,0,0,100,1\\right],t<4.250:\\left[-5,100,0,-1,100,1\\right],t<4.500:\\left[-4,100,0,-2,100,1\\right],t<4.750:\\left[-3,100,0,-3,100,1\\right],t<5.000:\\left[-4,100,1,-2,100,0\\right],t<5.250:\\left[-5,100,1,-1,100,0\\right],t<5.500:\\left[-6,100,1,0,100,0\\right],t<5.750:\\left[-7,100,1,1,100,0\\right],t<6.000:\\left[-8,100,1,2,100,0\\right],t<6.250:\\left[-9,100,1,3,100,0\\right],t<6.35:\\left[\\right]\\right\\}";
+const EXPECTED_C4_CHROMATIC_SMPTE2997DF: &str = "A=\\left\\{t<1.001:\\left[-9,100,0\\right],t<2.002:\\left[-8,100,0\\right],t<3.003:\\left[-7,100,0\\right],t<4.004:\\left[-6,100,0\\right],t<5.005:\\left[-5,100,0\\right],t<6.006:\\left[-4,100,0\\right],t<7.007:\\left[-3,100,0\\right],t<8.008:\\left[-2,100,0\\right],t<9.009:\\left[-1,100,0\\right],t<10.010:\\left[0,100,0\\right],t<11.011:\\left[1,100,0\\right],t<12.012:\\left[2,100,0\\right],t<13.013:\\left[3,100,0\\right],t<13.113:\\left[\\right]\\right\\}";
+const EXPECTED_A4_PITCHBEND: &str = "A=\\left\\{t<1.000:\\left[0,100,0\\right],t<2.000:\\left[6,100,0\\right],t<3.000:\\left[0.5,100,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_PITCHBEND_WHOLE_SEMITONES: &str = "A=\\left\\{t<1.000:\\left[0,100,0\\right],t<2.000:\\left[6,100,0\\right],t<3.000:\\left[0,100,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_PITCHBEND_MERGED: &str =
+    "A=\\left\\{t<3.000:\\left[0.5,100,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_C4_SUSTAIN_SOSTENUTO: &str = "A=\\left\\{t<1.000:\\left[-9,100,0\\right],t<2.000:\\left[-9,100,0,-5,100,0\\right],t<2.500:\\left[-2,100,0\\right],t<3.250:\\left[-2,100,0,0,100,0\\right],t<3.500:\\left[-2,100,0\\right],t<3.6:\\left[\\right]\\right\\}";
+const EXPECTED_C4_NO_SUSTAIN: &str = "A=\\left\\{t<0.500:\\left[-9,100,0\\right],t<1.000:\\left[\\right],t<1.500:\\left[-5,100,0\\right],t<2.000:\\left[\\right],t<2.500:\\left[-2,100,0\\right],t<3.250:\\left[-2,100,0,0,100,0\\right],t<3.500:\\left[-2,100,0\\right],t<3.6:\\left[\\right]\\right\\}";
+const EXPECTED_C4_NO_PEDALS: &str = "A=\\left\\{t<0.500:\\left[-9,100,0\\right],t<1.000:\\left[\\right],t<1.500:\\left[-5,100,0\\right],t<2.000:\\left[\\right],t<2.500:\\left[-2,100,0\\right],t<3.000:\\left[-2,100,0,0,100,0\\right],t<3.250:\\left[0,100,0\\right],t<3.35:\\left[\\right]\\right\\}";
+const EXPECTED_A4_VOLUME: &str = "A=\\left\\{t<1.000:\\left[0,127,0\\right],t<2.000:\\left[0,64,0\\right],t<3.000:\\left[0,32,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_VOLUME_STEP_10: &str = "A=\\left\\{t<1.000:\\left[0,127,0\\right],t<2.000:\\left[0,60,0\\right],t<3.000:\\left[0,30,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_VOLUME_STEP_100: &str = "A=\\left\\{t<2.000:\\left[0,100,0\\right],t<3.000:\\left[0,1,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_VOLUME_FADE: &str = "A=\\left\\{t<1.000:\\left[0,127,0\\right],t<2.000:\\left[0,0,0\\right],t<3.000:\\left[0,127,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_VOLUME_IGNORED: &str =
+    "A=\\left\\{t<3.000:\\left[0,127,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_C4_PROGRAM_CHANGES: &str = "A=\\left\\{t<1.000:\\left[-9,100,0,3,100,1\\right],t<2.000:\\left[-5,100,1,3,100,1\\right],t<3.000:\\left[-2,100,0,3,100,1\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_RETRIGGER_FIFO: &str = "A=\\left\\{t<1.000:\\left[0,100,0\\right],t<2.000:\\left[0,100,0,0,50,0\\right],t<3.000:\\left[0,50,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_RETRIGGER_LIFO: &str = "A=\\left\\{t<1.000:\\left[0,100,0\\right],t<2.000:\\left[0,100,0,0,50,0\\right],t<3.000:\\left[0,100,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_REPEATED: &str = "A=\\left\\{t<1.000:\\left[0,100,0\\right],t<2.000:\\left[0,100,0\\right],t<2.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_REPEATED_GAP: &str = "A=\\left\\{t<0.970:\\left[0,100,0\\right],t<1.000:\\left[\\right],t<2.000:\\left[0,100,0\\right],t<2.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_REPEATED_DIP: &str = "A=\\left\\{t<1.000:\\left[0,100,0\\right],t<1.030:\\left[0,50,0\\right],t<2.000:\\left[0,100,0\\right],t<2.1:\\left[\\right]\\right\\}";
+const EXPECTED_A4_ENVELOPE: &str = "A=\\left\\{t<0.050:\\left[0,119,0\\right],t<0.100:\\left[0,103,0\\right],t<0.150:\\left[0,87,0\\right],t<0.200:\\left[0,71,0\\right],t<3.000:\\left[0,64,0\\right],t<3.050:\\left[0,48,0\\right],t<3.100:\\left[0,16,0\\right],t<3.2:\\left[\\right]\\right\\}";
+const EXPECTED_C4_SIXTEENTHS_180BPM: &str = "A=\\left\\{t<0.083:\\left[-9,100,0\\right],t<0.167:\\left[-7,100,0\\right],t<0.250:\\left[-5,100,0\\right],t<0.333:\\left[-4,100,0\\right],t<0.433:\\left[\\right]\\right\\}";
+const EXPECTED_C4_SIXTEENTHS_180BPM_MICROSECONDS: &str = "A=\\left\\{t<0.083333:\\left[-9,100,0\\right],t<0.166667:\\left[-7,100,0\\right],t<0.250000:\\left[-5,100,0\\right],t<0.333333:\\left[-4,100,0\\right],t<0.433333:\\left[\\right]\\right\\}";
+const EXPECTED_C4_SIXTEENTHS_180BPM_TENTHS: &str = "A=\\left\\{t<0.1:\\left[-9,100,0\\right],t<0.2:\\left[-7,100,0\\right],t<0.3:\\left[-4,100,0\\right],t<0.4:\\left[\\right]\\right\\}";
+const EXPECTED_A4_PITCHBEND_OCTAVE_UP: &str = "A=\\left\\{t<1.000:\\left[12,100,0\\right],t<2.000:\\left[18,100,0\\right],t<3.000:\\left[12.5,100,0\\right],t<3.1:\\left[\\right]\\right\\}";
+const EXPECTED_C4_SUSTAIN_SOSTENUTO_EVENTS: &str = "E_{start}=\\left[0,1,2,2.5\\right]\nE_{end}=\\left[2,2,3.5,3.25\\right]\nE_{note}=\\left[-9,-5,-2,0\\right]\nE_{velocity}=\\left[100,100,100,100\\right]\nE_{font}=\\left[0,0,0,0\\right]";
+const EXPECTED_A4_ENVELOPE_EVENTS: &str = "E_{start}=\\left[0,0.05,0.1,0.15,0.2,3,3.05\\right]\nE_{end}=\\left[0.05,0.1,0.15,0.2,3,3.05,3.1\\right]\nE_{note}=\\left[0,0,0,0,0,0,0\\right]\nE_{velocity}=\\left[119,103,87,71,64,48,16\\right]";
+const EXPECTED_A4_REPEATED_OPTIMIZED: &str =
+    "A=\\left\\{t<2:\\left[0,100,0\\right],t<2.1:\\left[\\right]\\right\\}";
+const EXPECTED_C4_SUSTAIN_SOSTENUTO_OPTIMIZED: &str = "A=\\left\\{t<1:\\left[-9,100,0\\right],t<2:\\left[-9,100,0,-5,100,0\\right],t<2.5:\\left[-2,100,0\\right],t<3.25:\\left[-2,100,0,0,100,0\\right],t<3.5:\\left[-2,100,0\\right],t<3.6:\\left[\\right]\\right\\}";
+const EXPECTED_OFFSET_SECTIONS: &str = "A=\\left\\{t<150.5:A_{1},t<155.5:A_{2}\\left(t-150\\right),t<1000.5:A_{3}\\right\\}\nA_{1}=\\left\\{t<150.5:\\left[0\\right]\\right\\}\nA_{2}\\left(t\\right)=\\left\\{t<0.75:\\left[1\\right],t<1:\\left[2\\right],t<1.25:\\left[3\\right],t<1.5:\\left[4\\right],t<1.75:\\left[5\\right],t<2:\\left[6\\right],t<2.25:\\left[7\\right],t<2.5:\\left[8\\right],t<2.75:\\left[9\\right],t<3:\\left[10\\right],t<3.25:\\left[11\\right],t<3.5:\\left[12\\right],t<3.75:\\left[13\\right],t<4:\\left[14\\right],t<4.25:\\left[15\\right],t<4.5:\\left[16\\right],t<4.75:\\left[17\\right],t<5:\\left[18\\right],t<5.25:\\left[19\\right],t<5.5:\\left[20\\right]\\right\\}\nA_{3}=\\left\\{t<1000.5:\\left[21\\right]\\right\\}";
 const EXPECTED_440HZ_HARMONICS: &str = "1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
 
 // Expected outputs for B and C components
@@ -133,6 +164,901 @@ fn test_format_c4c5_chromatic_piano_sax_dynamicbpm() {
     );
 }
 
+/// Test that processing a MIDI file with 25 fps SMPTE timing produces the expected formula.
+///
+/// This test verifies:
+/// - Processing of a C4 chromatic scale timed in frames and subframes (1000 ticks per second)
+/// - That tempo events are ignored for timecode files
+/// - That the output matches the equivalent metrical file at 60 BPM
+#[test]
+fn test_format_c4_chromatic_smpte25fps() {
+    let midi_path = "tests/samples/c4_chromatic_smpte25fps.mid";
+    let soundfonts = vec![String::from(SINE_SOUNDFONT)];
+
+    // Create a MidiProcessor with the custom samples directory
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, soundfonts)
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let formula = result.to_piecewise_function();
+
+    assert!(
+        formula.contains(EXPECTED_C4_CHROMATIC_60BPM),
+        "Formula A component should match the 60 BPM output"
+    );
+    assert!(
+        formula.contains(EXPECTED_B_COMPONENT),
+        "Formula B component should match expected output"
+    );
+    assert!(
+        formula.contains(EXPECTED_C_COMPONENT),
+        "Formula C component should match expected output"
+    );
+}
+
+/// Test that processing a MIDI file with 29.97 drop-frame SMPTE timing produces the expected formula.
+///
+/// This test verifies:
+/// - Processing of a C4 chromatic scale where each note lasts 30 frames
+/// - That 30 frames at 29.97 fps last exactly 1.001 seconds
+#[test]
+fn test_format_c4_chromatic_smpte2997df() {
+    let midi_path = "tests/samples/c4_chromatic_smpte2997df.mid";
+    let soundfonts = vec![String::from(SINE_SOUNDFONT)];
+
+    // Create a MidiProcessor with the custom samples directory
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, soundfonts)
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let formula = result.to_piecewise_function();
+
+    assert!(
+        formula.contains(EXPECTED_C4_CHROMATIC_SMPTE2997DF),
+        "Formula A component should match expected output"
+    );
+    assert!(
+        formula.contains(EXPECTED_B_COMPONENT),
+        "Formula B component should match expected output"
+    );
+}
+
+/// Test that pitch bends produce fractional relative notes in the formula.
+///
+/// This test verifies:
+/// - Pitch bend sensitivity set through RPN 0 (12 semitones) is respected
+/// - A new piece starts wherever the bend changes
+/// - Fractional bends are written without trailing zeros
+#[test]
+fn test_format_a4_pitchbend() {
+    let midi_path = "tests/samples/a4_pitchbend.mid";
+    let soundfonts = vec![String::from(SINE_SOUNDFONT)];
+
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, soundfonts)
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let formula = result.to_piecewise_function();
+    assert!(
+        formula.contains(EXPECTED_A4_PITCHBEND),
+        "Formula A component should match expected output"
+    );
+}
+
+/// Test that the pitch bend resolution options limit the detail of the formula.
+///
+/// This test verifies:
+/// - Bends are rounded to the configured step size
+/// - Bend changes within the minimum interval are merged into one piece
+#[test]
+fn test_format_a4_pitchbend_resolution() {
+    let midi_path = "tests/samples/a4_pitchbend.mid";
+
+    // Round bends to whole semitones
+    let options = midi::ProcessingOptions {
+        pitch_bend_resolution: 1.0,
+        ..Default::default()
+    };
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+    let result = processor
+        .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+        .expect("Failed to process MIDI file with soundfonts");
+    assert!(
+        result
+            .to_piecewise_function()
+            .contains(EXPECTED_A4_PITCHBEND_WHOLE_SEMITONES),
+        "Bends should be rounded to whole semitones"
+    );
+
+    // Merge all bend changes into a single segment
+    let options = midi::ProcessingOptions {
+        segment_interval: 5000,
+        ..Default::default()
+    };
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+    let result = processor
+        .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+        .expect("Failed to process MIDI file with soundfonts");
+    assert!(
+        result
+            .to_piecewise_function()
+            .contains(EXPECTED_A4_PITCHBEND_MERGED),
+        "Bend changes within the interval should be merged"
+    );
+}
+
+/// Test that the sustain and sostenuto pedals extend note lifetimes.
+///
+/// This test verifies:
+/// - Notes released while the sustain pedal is down last until it is lifted
+/// - The sostenuto pedal only holds notes whose keys were down when it was pressed
+/// - Each pedal can be switched off through the processing options
+#[test]
+fn test_format_c4_sustain_sostenuto() {
+    let midi_path = "tests/samples/c4_sustain_sostenuto.mid";
+    let cases = [
+        (true, true, EXPECTED_C4_SUSTAIN_SOSTENUTO),
+        (false, true, EXPECTED_C4_NO_SUSTAIN),
+        (false, false, EXPECTED_C4_NO_PEDALS),
+    ];
+
+    for (sustain_pedal, sostenuto_pedal, expected) in cases {
+        let options = midi::ProcessingOptions {
+            sustain_pedal,
+            sostenuto_pedal,
+            ..Default::default()
+        };
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts");
+
+        assert!(
+            result.to_piecewise_function().contains(expected),
+            "Formula A component should match expected output (sustain: {}, sostenuto: {})",
+            sustain_pedal,
+            sostenuto_pedal
+        );
+    }
+}
+
+/// Test that channel volume and expression scale the emitted velocities.
+///
+/// This test verifies:
+/// - Volume (CC7) and expression (CC11) changes during a note split it into segments
+/// - Scaled velocities are rounded to the configured step size
+/// - Sounding notes never round down to a velocity of 0
+/// - A volume of 0 silences a note until the volume comes back up
+/// - Controllers are ignored unless channel volume handling is enabled
+#[test]
+fn test_format_a4_volume() {
+    let cases = [
+        ("tests/samples/a4_volume.mid", true, 1, EXPECTED_A4_VOLUME),
+        (
+            "tests/samples/a4_volume.mid",
+            true,
+            10,
+            EXPECTED_A4_VOLUME_STEP_10,
+        ),
+        (
+            "tests/samples/a4_volume.mid",
+            true,
+            100,
+            EXPECTED_A4_VOLUME_STEP_100,
+        ),
+        (
+            "tests/samples/a4_volume.mid",
+            false,
+            1,
+            EXPECTED_A4_VOLUME_IGNORED,
+        ),
+        (
+            "tests/samples/a4_volume_fade.mid",
+            true,
+            1,
+            EXPECTED_A4_VOLUME_FADE,
+        ),
+    ];
+
+    for (midi_path, channel_volume, volume_resolution, expected) in cases {
+        let options = midi::ProcessingOptions {
+            channel_volume,
+            volume_resolution,
+            ..Default::default()
+        };
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts");
+
+        assert!(
+            result.to_piecewise_function().contains(expected),
+            "Formula A component should match expected output (volume: {}, step: {})",
+            channel_volume,
+            volume_resolution
+        );
+    }
+}
+
+/// Test the formula output when soundfonts are assigned per program.
+///
+/// This test verifies:
+/// - Notes use the soundfont of the program active when they started
+/// - Channels using the same program share a soundfont
+#[test]
+fn test_format_c4_program_changes() {
+    let midi_path = "tests/samples/c4_program_changes.mid";
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+        .with_soundfont_mapping(midi::SoundfontMapping::Program);
+    let result = processor
+        .process_with_soundfonts(
+            midi_path,
+            vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+
+    assert!(
+        result
+            .to_piecewise_function()
+            .contains(EXPECTED_C4_PROGRAM_CHANGES),
+        "Formula A component should match expected output"
+    );
+}
+
+/// Test the formula output for a note that is re-triggered while still sounding.
+///
+/// This test verifies:
+/// - Both overlapping instances of the note are emitted
+/// - FIFO stacking ends the oldest instance at the first note-off
+/// - LIFO stacking ends the newest instance at the first note-off
+#[test]
+fn test_format_a4_retrigger() {
+    let midi_path = "tests/samples/a4_retrigger.mid";
+    let cases = [
+        (midi::VoiceStacking::Fifo, EXPECTED_A4_RETRIGGER_FIFO),
+        (midi::VoiceStacking::Lifo, EXPECTED_A4_RETRIGGER_LIFO),
+    ];
+
+    for (voice_stacking, expected) in cases {
+        let options = midi::ProcessingOptions {
+            voice_stacking,
+            ..Default::default()
+        };
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts");
+
+        assert!(
+            result.to_piecewise_function().contains(expected),
+            "Formula A component should match expected output ({:?})",
+            voice_stacking
+        );
+    }
+}
+
+/// Test the formula output for a note struck again right as it ends.
+///
+/// This test verifies:
+/// - Without re-articulation, the repeated note produces identical adjacent pieces
+/// - A gap shortens the first note so that silence separates the two
+/// - A dip starts the repeated note at a reduced velocity
+/// - Segments continuing a bent note are not treated as re-attacks
+#[test]
+fn test_format_a4_rearticulation() {
+    let cases = [
+        (
+            "tests/samples/a4_repeated.mid",
+            midi::Rearticulation::None,
+            EXPECTED_A4_REPEATED,
+        ),
+        (
+            "tests/samples/a4_repeated.mid",
+            midi::Rearticulation::Gap,
+            EXPECTED_A4_REPEATED_GAP,
+        ),
+        (
+            "tests/samples/a4_repeated.mid",
+            midi::Rearticulation::Dip,
+            EXPECTED_A4_REPEATED_DIP,
+        ),
+        (
+            "tests/samples/a4_pitchbend.mid",
+            midi::Rearticulation::Gap,
+            EXPECTED_A4_PITCHBEND,
+        ),
+        (
+            "tests/samples/a4_pitchbend.mid",
+            midi::Rearticulation::Dip,
+            EXPECTED_A4_PITCHBEND,
+        ),
+    ];
+
+    for (midi_path, rearticulation, expected) in cases {
+        let options = midi::ProcessingOptions {
+            rearticulation,
+            ..Default::default()
+        };
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts");
+
+        assert!(
+            result.to_piecewise_function().contains(expected),
+            "Formula A component should match expected output ({}, {:?})",
+            midi_path,
+            rearticulation
+        );
+    }
+}
+
+/// Test the formula output for a note shaped by a velocity envelope.
+///
+/// This test verifies:
+/// - An envelope carried by a soundfont file is sampled into stepped velocities
+/// - The decay is stepped at the envelope resolution and the sustain is a single piece
+/// - The release fades the note out after its note-off
+/// - A per-channel envelope produces the same output as the soundfont's envelope
+#[test]
+fn test_format_a4_envelope() {
+    let midi_path = "tests/samples/a4_volume.mid";
+    let envelope: midi::Envelope = "0,200,0.5,100".parse().expect("Invalid envelope");
+    let channel_options = midi::ProcessingOptions {
+        channel_envelopes: [(0, envelope)].into_iter().collect(),
+        ..Default::default()
+    };
+    let cases = [
+        (SINE_PLUCK_SOUNDFONT, midi::ProcessingOptions::default()),
+        (SINE_SOUNDFONT, channel_options),
+    ];
+
+    for (soundfont, options) in cases {
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(soundfont)])
+            .expect("Failed to process MIDI file with soundfonts");
+
+        assert!(
+            result
+                .to_piecewise_function()
+                .contains(EXPECTED_A4_ENVELOPE),
+            "Formula A component should match expected output ({})",
+            soundfont
+        );
+    }
+}
+
+/// Test that times are kept in microseconds and rounded only when written.
+///
+/// This test verifies:
+/// - Note boundaries between milliseconds are rounded to the nearest emitted digit
+/// - The number of decimal places follows the time precision option
+/// - Pieces whose end rounds to the same time as the previous piece are left out
+/// - Precisions finer than a microsecond are rejected
+#[test]
+fn test_format_c4_sixteenths_time_precision() {
+    let midi_path = "tests/samples/c4_sixteenths_180bpm.mid";
+    let cases = [
+        (3, EXPECTED_C4_SIXTEENTHS_180BPM),
+        (6, EXPECTED_C4_SIXTEENTHS_180BPM_MICROSECONDS),
+        (1, EXPECTED_C4_SIXTEENTHS_180BPM_TENTHS),
+    ];
+
+    for (time_precision, expected) in cases {
+        let options = midi::ProcessingOptions {
+            time_precision,
+            ..Default::default()
+        };
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts");
+
+        assert!(
+            result.to_piecewise_function().contains(expected),
+            "Formula A component should match expected output (precision {})",
+            time_precision
+        );
+    }
+
+    let options = midi::ProcessingOptions {
+        time_precision: midi::MAX_TIME_PRECISION + 1,
+        ..Default::default()
+    };
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+    assert!(processor
+        .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+        .is_err());
+}
+
+/// Test the expressions that the formula is serialized from.
+///
+/// This test verifies:
+/// - Serializing the expressions gives the same formula as `to_piecewise_function`
+/// - Expressions can be transformed before they are serialized
+/// - Validation rejects unordered pieces and references to unassigned variables
+#[test]
+fn test_format_expressions() {
+    let midi_path = "tests/samples/a4_pitchbend.mid";
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let mut expressions = result.to_expressions();
+    assert_eq!(
+        midi::format_latex(&expressions),
+        result.to_piecewise_function()
+    );
+    assert!(midi::validate_expressions(&expressions).is_ok());
+
+    // Transpose every note up an octave
+    let midi::Expression::Piecewise(pieces) = &mut expressions[0].value else {
+        panic!("A should be a piecewise expression");
+    };
+    for piece in pieces.iter_mut() {
+        if let midi::Expression::List(values) = &mut piece.value {
+            for note in values.iter_mut().step_by(3) {
+                if let midi::Expression::Number(number) = note {
+                    *number = midi::Number::Shortest(number.value() + 12.0);
+                }
+            }
+        }
+    }
+    assert!(midi::format_latex(&expressions).contains(EXPECTED_A4_PITCHBEND_OCTAVE_UP));
+
+    // Pieces must end in order
+    let mut unordered = expressions.clone();
+    if let midi::Expression::Piecewise(pieces) = &mut unordered[0].value {
+        pieces.swap(0, 1);
+    }
+    assert!(midi::validate_expressions(&unordered).is_err());
+
+    // Variables must be assigned
+    let dangling = vec![midi::Assignment::new(
+        "A",
+        midi::Expression::Variable(String::from("A_{1}")),
+    )];
+    assert!(midi::validate_expressions(&dangling).is_err());
+}
+
+/// Test the Desmos graph state output.
+///
+/// This test verifies:
+/// - The state is valid JSON with an expression list and a ticker advancing `t`
+/// - The song's formulas are included unchanged
+/// - The player has enough voices for the most notes playing at once
+/// - The settings are sliders
+#[test]
+fn test_format_desmos_state() {
+    let midi_path = "tests/samples/c4c5_chromatic_piano_sax_dynamicbpm.mid";
+    let soundfonts = vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)];
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, soundfonts)
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let state = result
+        .to_desmos_state()
+        .expect("Failed to create graph state");
+    let state: serde_json::Value = serde_json::from_str(&state).expect("State should be JSON");
+    assert_eq!(
+        state["expressions"]["ticker"]["handlerLatex"],
+        "t\\to t+\\frac{dt}{1000}"
+    );
+
+    let list = state["expressions"]["list"]
+        .as_array()
+        .expect("State should have an expression list");
+    let latex: Vec<&str> = list
+        .iter()
+        .filter_map(|item| item["latex"].as_str())
+        .collect();
+    for formula in result.to_piecewise_function().lines() {
+        assert!(latex.contains(&formula), "Missing formula {}", formula);
+    }
+    assert!(latex.contains(&"N_{voices}=2"));
+    assert!(latex.contains(&"t\\to0"));
+
+    let sliders = list
+        .iter()
+        .filter(|item| item.get("slider").is_some())
+        .count();
+    assert_eq!(sliders, 6, "Every setting should be a slider");
+}
+
+/// Test the event-list encoding of the notes.
+///
+/// This test verifies:
+/// - Every note is listed once, with its start and end in the parallel lists
+/// - Envelope steps become separate events with the same velocities as the pieces
+/// - `A` filters the active events, and the formulas are valid
+/// - The formula sizes of both encodings are reported, and the state has enough voices
+#[test]
+fn test_format_event_list() {
+    let options = midi::ProcessingOptions {
+        encoding: midi::Encoding::EventList,
+        ..Default::default()
+    };
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+
+    let cases = [
+        (
+            "tests/samples/c4_sustain_sostenuto.mid",
+            SINE_SOUNDFONT,
+            EXPECTED_C4_SUSTAIN_SOSTENUTO_EVENTS,
+        ),
+        (
+            "tests/samples/a4_volume.mid",
+            SINE_PLUCK_SOUNDFONT,
+            EXPECTED_A4_ENVELOPE_EVENTS,
+        ),
+    ];
+    for (midi_path, soundfont, expected) in cases {
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(soundfont)])
+            .expect("Failed to process MIDI file with soundfonts");
+        let formula = result.to_piecewise_function();
+
+        assert!(
+            formula.contains(expected),
+            "Event lists should match expected output ({})",
+            midi_path
+        );
+        assert!(formula.starts_with("A=\\operatorname{sort}"));
+        assert!(formula.contains("E_{active}="));
+        assert!(midi::validate_expressions(&result.to_expressions()).is_ok());
+
+        assert_eq!(
+            result.formula_size(midi::Encoding::EventList),
+            formula.len()
+        );
+        assert!(result.formula_size(midi::Encoding::Piecewise) > 0);
+    }
+
+    let result = processor
+        .process_with_soundfonts(
+            "tests/samples/c4c5_chromatic_piano_sax_dynamicbpm.mid",
+            vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+    let state = result
+        .to_desmos_state()
+        .expect("Failed to create graph state");
+    assert!(state.contains("N_{voices}=2"));
+}
+
+/// Test that lists longer than Desmos allows are found.
+///
+/// This test verifies:
+/// - Lists split into sections are measured after they are joined
+/// - Piecewise expressions are measured by their longest piece
+/// - The formulas of sample songs have no oversized lists
+#[test]
+fn test_format_list_limit() {
+    let list = |length| {
+        midi::Expression::List(vec![
+            midi::Expression::Number(midi::Number::Shortest(0.0));
+            length
+        ])
+    };
+    let section = |name: &str| midi::Expression::Variable(name.to_string());
+    let expressions = vec![
+        midi::Assignment::new(
+            "E_{start}",
+            midi::Expression::Call(
+                "join".to_string(),
+                vec![section("E_{start1}"), section("E_{start2}")],
+            ),
+        ),
+        midi::Assignment::new("E_{start1}", list(6000)),
+        midi::Assignment::new("E_{start2}", list(6000)),
+        midi::Assignment::new(
+            "A",
+            midi::Expression::Piecewise(vec![
+                midi::Piece {
+                    end: midi::Number::Shortest(1.0),
+                    value: list(3),
+                },
+                midi::Piece {
+                    end: midi::Number::Shortest(2.0),
+                    value: list(midi::MAX_LIST_LENGTH + 1),
+                },
+            ]),
+        ),
+        midi::Assignment::new("B", list(midi::MAX_LIST_LENGTH)),
+    ];
+
+    assert_eq!(
+        midi::oversized_lists(&expressions),
+        vec![
+            (String::from("E_{start}"), 12000),
+            (String::from("A"), midi::MAX_LIST_LENGTH + 1)
+        ]
+    );
+
+    let result = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+        .process_with_soundfonts(
+            "tests/samples/c4c5_chromatic_piano_sax_dynamicbpm.mid",
+            vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+    assert!(midi::oversized_lists(&result.to_expressions()).is_empty());
+}
+
+/// Test the formula optimizer.
+///
+/// This test verifies:
+/// - Consecutive pieces with the same notes are merged
+/// - Times are written without trailing zeros
+/// - The bytes saved are the difference between the unoptimized and optimized formulas
+#[test]
+fn test_format_optimize() {
+    let cases = [
+        (
+            "tests/samples/a4_repeated.mid",
+            EXPECTED_A4_REPEATED_OPTIMIZED,
+        ),
+        (
+            "tests/samples/c4_sustain_sostenuto.mid",
+            EXPECTED_C4_SUSTAIN_SOSTENUTO_OPTIMIZED,
+        ),
+    ];
+
+    for (midi_path, expected) in cases {
+        let unoptimized = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts")
+            .to_piecewise_function();
+
+        let options = midi::ProcessingOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+        let result = processor
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts");
+        let optimized = result.to_piecewise_function();
+
+        assert!(
+            optimized.contains(expected),
+            "Optimized formula should match expected output ({})",
+            midi_path
+        );
+        assert_eq!(
+            result.optimization_savings(),
+            unoptimized.len() - optimized.len()
+        );
+    }
+}
+
+/// Test that sections of a split formula are timed from their start when shorter.
+///
+/// This test verifies:
+/// - The first section is left as is
+/// - Later sections become functions of the time since a whole second before them
+/// - A section is only offset when that makes the formulas shorter
+#[test]
+fn test_format_optimize_section_offsets() {
+    let piece = |end: f64, value: usize| midi::Piece {
+        end: midi::Number::Fixed(end, 3),
+        value: midi::Expression::List(vec![midi::Expression::Number(value.into())]),
+    };
+    let section = |name: &str, end: f64| midi::Piece {
+        end: midi::Number::Fixed(end, 3),
+        value: midi::Expression::Variable(name.to_string()),
+    };
+    // Only the second section has enough pieces for the offset to pay off
+    let expressions = vec![
+        midi::Assignment::new(
+            "A",
+            midi::Expression::Piecewise(vec![
+                section("A_{1}", 150.5),
+                section("A_{2}", 155.5),
+                section("A_{3}", 1000.5),
+            ]),
+        ),
+        midi::Assignment::new("A_{1}", midi::Expression::Piecewise(vec![piece(150.5, 0)])),
+        midi::Assignment::new(
+            "A_{2}",
+            midi::Expression::Piecewise(
+                (1..=20)
+                    .map(|i| piece(150.5 + i as f64 * 0.25, i))
+                    .collect(),
+            ),
+        ),
+        midi::Assignment::new(
+            "A_{3}",
+            midi::Expression::Piecewise(vec![piece(1000.5, 21)]),
+        ),
+    ];
+
+    let optimized = midi::optimize_expressions(expressions);
+    assert_eq!(midi::format_latex(&optimized), EXPECTED_OFFSET_SECTIONS);
+}
+
+/// Test that the HTML player embeds the graph state.
+///
+/// This test verifies:
+/// - The page loads the Desmos API and sets the state
+/// - The embedded state is the one written by `to_desmos_state`
+/// - The title is escaped
+/// - The given API key is used, or the demo key when none is given
+/// - Titles are taken from the MIDI file name
+#[test]
+fn test_format_html_player() {
+    let midi_path = "tests/samples/c4c5_chromatic_piano_sax_dynamicbpm.mid";
+    let soundfonts = vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)];
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, soundfonts)
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let html = result
+        .to_html_player("Piano & Sax <Demo>", Some("0123abcd"))
+        .expect("Failed to create HTML player");
+    let demo_html = result
+        .to_html_player(&midi::song_title(midi_path), None)
+        .expect("Failed to create HTML player");
+    let state = result
+        .to_desmos_state()
+        .expect("Failed to create graph state");
+
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("https://www.desmos.com/api/"));
+    assert!(html.contains("calculator.setState(state)"));
+    assert!(html.contains(&format!("const state = {};", state)));
+    assert!(html.contains("<title>Piano &amp; Sax &lt;Demo&gt;</title>"));
+    assert!(html.contains("calculator.js?apiKey=0123abcd\""));
+    assert!(!html.contains("{{"), "Every placeholder should be filled");
+
+    assert!(demo_html.contains(&format!("apiKey={}", midi::DESMOS_DEMO_API_KEY)));
+    assert!(demo_html.contains("<title>c4c5_chromatic_piano_sax_dynamicbpm</title>"));
+}
+
+/// Test that generated formulas decode back into the song they were written from.
+///
+/// This test verifies:
+/// - Decoding and encoding again gives the same formulas for every sample
+/// - Optimized formulas, coarse time precisions, and event lists are recognized
+/// - The time precision is recovered from the piece ends
+#[test]
+fn test_format_decode_round_trip() {
+    let configurations = [
+        (midi::Encoding::Piecewise, false, 3),
+        (midi::Encoding::Piecewise, true, 3),
+        (midi::Encoding::Piecewise, false, 1),
+        (midi::Encoding::EventList, false, 3),
+    ];
+
+    let mut midi_paths: Vec<_> = std::fs::read_dir(SAMPLES_DIR)
+        .expect("Failed to read samples directory")
+        .map(|entry| entry.expect("Failed to read sample").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "mid"))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    midi_paths.sort();
+    assert!(!midi_paths.is_empty());
+
+    for midi_path in &midi_paths {
+        for (encoding, optimize, time_precision) in configurations {
+            let options = midi::ProcessingOptions {
+                encoding,
+                optimize,
+                time_precision,
+                ..Default::default()
+            };
+            let processor =
+                midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR).with_options(options);
+            let result = processor
+                .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+                .expect("Failed to process MIDI file with soundfonts");
+            let formula = result.to_piecewise_function();
+
+            let mut decoded = midi::decode_formula(&formula).expect("Failed to decode formula");
+            decoded.encoding = encoding;
+            decoded.optimize = optimize;
+
+            assert_eq!(
+                decoded.to_piecewise_function(),
+                formula,
+                "Decoded formula should encode to the same formula ({}, {:?})",
+                midi_path,
+                encoding
+            );
+            if !optimize && encoding == midi::Encoding::Piecewise {
+                assert_eq!(decoded.time_precision, time_precision);
+            }
+        }
+    }
+}
+
+/// Test decoding formulas split into sections timed from their start.
+#[test]
+fn test_format_decode_section_offsets() {
+    let piece = |end: f64, note: f32| midi::Piece {
+        end: midi::Number::Fixed(end, 3),
+        value: midi::Expression::List(vec![
+            midi::Expression::Number(note.into()),
+            midi::Expression::Number(100u8.into()),
+            midi::Expression::Number(0u8.into()),
+        ]),
+    };
+    let section = |name: &str, end: f64| midi::Piece {
+        end: midi::Number::Fixed(end, 3),
+        value: midi::Expression::Variable(name.to_string()),
+    };
+    let mut expressions = midi::optimize_expressions(vec![
+        midi::Assignment::new(
+            "A",
+            midi::Expression::Piecewise(vec![section("A_{1}", 150.5), section("A_{2}", 155.5)]),
+        ),
+        midi::Assignment::new(
+            "A_{1}",
+            midi::Expression::Piecewise(vec![piece(150.5, 0.0)]),
+        ),
+        midi::Assignment::new(
+            "A_{2}",
+            midi::Expression::Piecewise(
+                (1..=20)
+                    .map(|i| piece(150.5 + i as f64 * 0.25, i as f32))
+                    .collect(),
+            ),
+        ),
+    ]);
+    assert!(
+        midi::format_latex(&expressions).contains("A_{2}\\left(t-150\\right)"),
+        "The second section should be offset"
+    );
+    expressions.push(midi::Assignment::new(
+        "B",
+        midi::Expression::List(vec![midi::Expression::Number(1u8.into())]),
+    ));
+    expressions.push(midi::Assignment::new(
+        "C",
+        midi::Expression::Number(1u8.into()),
+    ));
+
+    let decoded = midi::decode_expressions(&expressions).expect("Failed to decode formulas");
+    let notes: Vec<(u64, u8)> = decoded
+        .note_changes
+        .iter()
+        .filter_map(|event| event.notes.first().map(|note| (event.timestamp, note.note)))
+        .collect();
+    assert_eq!(notes.len(), 21);
+    assert_eq!(notes[0], (0, 69));
+    assert_eq!(notes[1], (150_500_000, 70));
+    assert_eq!(notes[2], (150_750_000, 71));
+    assert_eq!(notes[20], (155_250_000, 89));
+}
+
+/// Test that text that is not a generated formula is rejected.
+#[test]
+fn test_format_decode_invalid() {
+    let invalid = [
+        // Missing soundfonts
+        "A=\\left\\{t<1:\\left[0,100,0\\right]\\right\\}",
+        // Note without a velocity and soundfont
+        "A=\\left\\{t<1:\\left[0\\right]\\right\\}\nB=\\left[1\\right]\nC=1",
+        // Soundfont that does not exist
+        "A=\\left\\{t<1:\\left[0,100,1\\right]\\right\\}\nB=\\left[1\\right]\nC=1",
+        "not a formula",
+    ];
+
+    for text in invalid {
+        assert!(
+            midi::decode_formula(text).is_err(),
+            "Should reject {:?}",
+            text
+        );
+    }
+}
+
 /// Test that the harmonics analysis produces the expected format.
 ///
 /// This test verifies:
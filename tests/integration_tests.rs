@@ -146,3 +146,85 @@ fn test_end_to_end_pipeline() {
         "Should contain proper ending"
     );
 }
+
+/// Test rendering a song to audio.
+///
+/// This test verifies:
+/// - Notes are rendered at their frequency, shifted by the transpose setting
+/// - Tones are scaled by the volume and the velocity of each note
+/// - The WAV file is written with the length of the song and can be read back
+/// - The WAV file holds the same samples as the rendered song
+/// - A sample rate of 0 is rejected
+#[test]
+fn test_render_wav() {
+    let options = midi::ProcessingOptions {
+        channel_volume: true,
+        ..Default::default()
+    };
+    let result = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+        .with_options(options)
+        .process_with_soundfonts(
+            "tests/samples/a4_volume.mid",
+            vec![String::from(SINE_SOUNDFONT)],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+
+    // Without detune both tones of A4 play at 440Hz, crossing zero twice a cycle
+    let zero_crossings = |samples: &[f32]| {
+        samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count()
+    };
+    let options = midi::RenderOptions {
+        detune: 0.0,
+        ..Default::default()
+    };
+    let samples = midi::render_samples(&result, &options).expect("Failed to render samples");
+    assert_eq!(samples.len(), 3 * 44100);
+    assert!((879..=881).contains(&zero_crossings(&samples[..44100])));
+
+    // Two tones at a volume of 0.5 and a velocity of 127 add up to 0.5
+    let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    assert!((peak(&samples[..44100]) - 0.5).abs() < 0.01);
+    assert!((peak(&samples[44100..88200]) - 0.252).abs() < 0.01);
+
+    let transposed = midi::render_samples(
+        &result,
+        &midi::RenderOptions {
+            transpose: 12.0,
+            ..options
+        },
+    )
+    .expect("Failed to render samples");
+    assert!((1759..=1761).contains(&zero_crossings(&transposed[..44100])));
+
+    // Unique to this process, so concurrent test runs do not share the file
+    let path = std::env::temp_dir().join(format!(
+        "desmos_midi_test_render_{}.wav",
+        std::process::id()
+    ));
+    midi::render_wav(&result, &path, &options).expect("Failed to render WAV file");
+    let wav = desmos_midi::audio::read_wav_file(&path).expect("Failed to read rendered WAV file");
+    std::fs::remove_file(&path).ok();
+    assert_eq!(wav.sample_rate, 44100);
+    assert_eq!(wav.channels, 1);
+    assert_eq!(wav.samples.len(), 3 * 44100);
+
+    // The file is written in chunks, which join up into the same samples
+    let largest_difference = wav
+        .samples
+        .iter()
+        .zip(&samples)
+        .fold(0.0f32, |largest, (written, sample)| {
+            largest.max((written - sample).abs())
+        });
+    assert!(largest_difference < 0.001);
+
+    let silent = midi::RenderOptions {
+        sample_rate: 0,
+        ..options
+    };
+    assert!(midi::render_samples(&result, &silent).is_err());
+    assert!(midi::render_wav(&result, &path, &silent).is_err());
+}
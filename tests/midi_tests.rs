@@ -11,13 +11,25 @@
 // - Files with constant BPM
 // - Files with dynamic BPM changes
 // - Files with multiple channels and instruments
+// - Files using SMPTE timecode timing
+// - Files with pitch bends
+// - Files with program changes partway through a channel
+// - Files with several tracks sharing a channel
+// - Files that re-trigger a note while it is still sounding
+// - Limiting the number of notes that sound at once
+// - Quantizing note times to a grid
+// - Trimming songs to a time range
+// - Soundfonts with velocity envelopes
 // - Edge cases and error handling
 
-use desmos_midi::midi::{self, ProcessedSong};
+use desmos_midi::midi::{self, Envelope, ProcessedSong, SoundfontMapping, SoundfontSlot};
+use std::path::Path;
 
 // Import the test utils
 mod test_utils;
-use test_utils::{SAMPLES_DIR, SINE_SOUNDFONT, SQUARE_SOUNDFONT};
+use test_utils::{
+    MIDI_C4_G4_E4_TWO_CHANNELS, SAMPLES_DIR, SINE_PLUCK_SOUNDFONT, SINE_SOUNDFONT, SQUARE_SOUNDFONT,
+};
 
 /// Test MIDI processing with constant BPM.
 ///
@@ -111,6 +123,649 @@ fn test_midi_multiple_channels() {
     );
 }
 
+/// Test MIDI processing with SMPTE timecode timing.
+///
+/// This test verifies:
+/// - Timecode files are accepted instead of failing with an unsupported timing error
+/// - Channel information is extracted as for metrical files
+/// - Notes are evenly spaced, since tempo events do not apply to timecode files
+#[test]
+fn test_midi_smpte_timecode() {
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+
+    for midi_path in [
+        "tests/samples/c4_chromatic_smpte25fps.mid",
+        "tests/samples/c4_chromatic_smpte2997df.mid",
+    ] {
+        let info = processor
+            .process_info(midi_path)
+            .expect("Failed to process SMPTE MIDI info");
+        assert_eq!(info.channels.len(), 1, "Should have a single channel");
+
+        let soundfonts = vec![String::from(SINE_SOUNDFONT)];
+        let result = processor
+            .process_with_soundfonts(midi_path, soundfonts)
+            .expect("Failed to process SMPTE MIDI file with soundfonts");
+
+        let timestamps = collect_note_timestamps(&result);
+        assert_eq!(timestamps.len(), 13, "Should have 13 note events");
+        let time_diffs: Vec<f64> = timestamps.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(time_diffs.windows(2).all(|w| (w[1] - w[0]).abs() < 0.001));
+    }
+}
+
+/// Test MIDI processing with pitch bends.
+///
+/// This test verifies:
+/// - A bent note is split into one segment per bend value
+/// - Each segment carries the bend offset in semitones
+/// - Segments follow each other without gaps
+#[test]
+fn test_midi_pitch_bend() {
+    let midi_path = "tests/samples/a4_pitchbend.mid";
+    let soundfonts = vec![String::from(SINE_SOUNDFONT)];
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, soundfonts)
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let segments: Vec<(u64, &midi::Note)> = result
+        .note_changes
+        .iter()
+        .flat_map(|event| event.notes.iter().map(move |note| (event.timestamp, note)))
+        .collect();
+    assert_eq!(
+        segments.len(),
+        3,
+        "Bent note should be split into 3 segments"
+    );
+
+    let bends: Vec<f32> = segments.iter().map(|(_, note)| note.bend).collect();
+    assert_eq!(bends, vec![0.0, 6.0, 0.5]);
+    assert!(segments.iter().all(|(_, note)| note.note == 69));
+    assert!(
+        segments.windows(2).all(|w| w[0].1.end == w[1].0),
+        "Segments should be contiguous"
+    );
+}
+
+/// Test soundfont mapping for channels that change programs.
+///
+/// This test verifies:
+/// - Every program a channel plays notes with is listed in order of first use
+/// - Notes record the program active when they started
+/// - Soundfont slots are listed per channel program or per program
+/// - The number of soundfonts is validated against the slots of the mapping
+#[test]
+fn test_midi_program_mapping() {
+    let midi_path = "tests/samples/c4_program_changes.mid";
+    let info = midi::process_midi_info(midi_path).expect("Failed to process MIDI file");
+
+    let programs: Vec<&[u8]> = info.channels.iter().map(|c| &c.programs[..]).collect();
+    assert_eq!(programs, vec![&[0, 48][..], &[48][..]]);
+
+    assert_eq!(
+        SoundfontMapping::ChannelProgram.slots(&info),
+        vec![
+            SoundfontSlot::ChannelProgram(0, 0),
+            SoundfontSlot::ChannelProgram(0, 48),
+            SoundfontSlot::ChannelProgram(1, 48),
+        ]
+    );
+    assert_eq!(
+        SoundfontMapping::Program.slots(&info),
+        vec![SoundfontSlot::Program(0), SoundfontSlot::Program(48)]
+    );
+
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+        .with_soundfont_mapping(SoundfontMapping::ChannelProgram);
+    let result = processor
+        .process_with_soundfonts(
+            midi_path,
+            vec![
+                String::from(SINE_SOUNDFONT),
+                String::from(SQUARE_SOUNDFONT),
+                String::from(SQUARE_SOUNDFONT),
+            ],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let notes: Vec<(u8, u8, usize)> = result
+        .note_changes
+        .iter()
+        .flat_map(|event| event.notes.iter())
+        .filter(|note| note.channel == 0)
+        .map(|note| (note.note, note.program, note.soundfont))
+        .collect();
+    assert_eq!(notes, vec![(60, 0, 0), (64, 48, 1), (67, 0, 0)]);
+
+    let mismatch = processor.process_with_soundfonts(
+        midi_path,
+        vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)],
+    );
+    assert!(
+        mismatch.is_err(),
+        "Should return error when soundfonts don't match the channel programs"
+    );
+}
+
+/// Test soundfont mapping for tracks that share a channel.
+///
+/// This test verifies:
+/// - Tracks with MIDI messages are listed with their names and channels
+/// - Notes record the track that started them
+/// - Each track gets its own soundfont in track mode
+#[test]
+fn test_midi_track_mapping() {
+    let midi_path = "tests/samples/c4_shared_channel_tracks.mid";
+    let info = midi::process_midi_info(midi_path).expect("Failed to process MIDI file");
+
+    assert_eq!(
+        info.channels.len(),
+        1,
+        "Both tracks should share one channel"
+    );
+    let tracks: Vec<(usize, Option<&str>, &[u8])> = info
+        .tracks
+        .iter()
+        .map(|t| (t.index, t.name.as_deref(), &t.channels[..]))
+        .collect();
+    assert_eq!(
+        tracks,
+        vec![(1, Some("Piano"), &[0][..]), (2, Some("Bass"), &[0][..])]
+    );
+    assert_eq!(
+        SoundfontMapping::Track.slots(&info),
+        vec![SoundfontSlot::Track(1), SoundfontSlot::Track(2)]
+    );
+
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+        .with_soundfont_mapping(SoundfontMapping::Track);
+    let result = processor
+        .process_with_soundfonts(
+            midi_path,
+            vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let mut notes: Vec<(u8, usize, usize)> = result
+        .note_changes
+        .iter()
+        .flat_map(|event| event.notes.iter())
+        .map(|note| (note.note, note.track, note.soundfont))
+        .collect();
+    notes.sort();
+    assert_eq!(notes, vec![(48, 2, 1), (60, 1, 0), (64, 1, 0)]);
+}
+
+/// Test voice stacking for a re-triggered note.
+///
+/// This test verifies:
+/// - A second note-on for a sounding note keeps the first instance
+/// - Each note-off ends one instance
+/// - The number of stacked notes is counted
+#[test]
+fn test_midi_voice_stacking() {
+    let midi_path = "tests/samples/a4_retrigger.mid";
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+        .expect("Failed to process MIDI file with soundfonts");
+
+    let instances: Vec<(u64, u8, u64)> = result
+        .note_changes
+        .iter()
+        .flat_map(|event| {
+            event
+                .notes
+                .iter()
+                .map(move |note| (event.timestamp, note.velocity, note.end))
+        })
+        .collect();
+    assert_eq!(
+        instances,
+        vec![(0, 100, 2_000_000), (1_000_000, 50, 3_000_000)]
+    );
+    assert_eq!(result.stats.stacked_notes, 1);
+    assert_eq!(result.stats.max_stacked, 2);
+}
+
+/// Test the polyphony limit.
+///
+/// This test verifies:
+/// - Quietest stealing leaves out a new note that is quieter than the sounding one
+/// - Oldest stealing cuts the sounding note short where the new note starts
+/// - Inner stealing keeps the highest and lowest notes
+/// - A limit per channel leaves notes on other channels alone
+/// - A limit of 0 is rejected
+#[test]
+fn test_midi_polyphony_limit() {
+    let options = |max_polyphony, voice_stealing, polyphony_per_channel| midi::ProcessingOptions {
+        max_polyphony: Some(max_polyphony),
+        voice_stealing,
+        polyphony_per_channel,
+        ..Default::default()
+    };
+    let process = |midi_path: &str, options| {
+        midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+            .with_options(options)
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+            .expect("Failed to process MIDI file with soundfonts")
+    };
+    let instances = |song: &ProcessedSong| -> Vec<(u64, u8, u64)> {
+        song.note_changes
+            .iter()
+            .flat_map(|event| {
+                event
+                    .notes
+                    .iter()
+                    .map(move |note| (event.timestamp, note.velocity, note.end))
+            })
+            .collect()
+    };
+    let pitches = |song: &ProcessedSong| -> Vec<(u64, u8, u64)> {
+        song.note_changes
+            .iter()
+            .flat_map(|event| {
+                event
+                    .notes
+                    .iter()
+                    .map(move |note| (event.timestamp, note.note, note.end))
+            })
+            .collect()
+    };
+
+    let quietest = process(
+        "tests/samples/a4_retrigger.mid",
+        options(1, midi::VoiceStealing::Quietest, false),
+    );
+    assert_eq!(instances(&quietest), vec![(0, 100, 2_000_000)]);
+    assert_eq!(quietest.stats.dropped_notes, 1);
+    assert_eq!(quietest.stats.truncated_notes, 0);
+
+    let oldest = process(
+        "tests/samples/a4_retrigger.mid",
+        options(1, midi::VoiceStealing::Oldest, false),
+    );
+    assert_eq!(
+        instances(&oldest),
+        vec![(0, 100, 1_000_000), (1_000_000, 50, 3_000_000)]
+    );
+    assert_eq!(oldest.stats.dropped_notes, 0);
+    assert_eq!(oldest.stats.truncated_notes, 1);
+
+    // C4 and G4 on channel 1 are joined by E4 on channel 2, which falls between
+    // them and is left out by a global limit of 2
+    let inner = process(
+        MIDI_C4_G4_E4_TWO_CHANNELS,
+        options(2, midi::VoiceStealing::Inner, false),
+    );
+    assert_eq!(
+        pitches(&inner),
+        vec![(0, 60, 4_000_000), (1_000_000, 67, 4_000_000)]
+    );
+    assert_eq!(inner.stats.dropped_notes, 1);
+    assert_eq!(inner.stats.truncated_notes, 0);
+
+    // Oldest stealing cuts C4 short instead
+    let oldest = process(
+        MIDI_C4_G4_E4_TWO_CHANNELS,
+        options(2, midi::VoiceStealing::Oldest, false),
+    );
+    assert_eq!(
+        pitches(&oldest),
+        vec![
+            (0, 60, 2_000_000),
+            (1_000_000, 67, 4_000_000),
+            (2_000_000, 64, 4_000_000)
+        ]
+    );
+    assert_eq!(oldest.stats.truncated_notes, 1);
+
+    // Each channel stays within a limit of 2 on its own
+    let per_channel = process(
+        MIDI_C4_G4_E4_TWO_CHANNELS,
+        options(2, midi::VoiceStealing::Inner, true),
+    );
+    assert_eq!(
+        pitches(&per_channel),
+        vec![
+            (0, 60, 4_000_000),
+            (1_000_000, 67, 4_000_000),
+            (2_000_000, 64, 4_000_000)
+        ]
+    );
+    assert_eq!(per_channel.stats.dropped_notes, 0);
+    assert_eq!(per_channel.stats.truncated_notes, 0);
+
+    let silent = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+        .with_options(options(0, midi::VoiceStealing::Quietest, false))
+        .process_with_soundfonts(
+            MIDI_C4_G4_E4_TWO_CHANNELS,
+            vec![String::from(SINE_SOUNDFONT)],
+        );
+    assert!(silent.is_err(), "A polyphony limit of 0 should be rejected");
+}
+
+/// Test quantization of note times.
+///
+/// This test verifies:
+/// - Notes are moved onto a grid of note values following the tempo
+/// - Notes are moved onto a grid of milliseconds
+/// - A partial strength moves notes part of the way
+/// - Notes that would lose their length end on the grid line after their start
+/// - Program changes stay before the notes they were set for
+/// - Strengths outside 0 to 1 are rejected
+/// - Grids of note values are rejected for SMPTE timecode files
+/// - Grids are parsed from their written form
+#[test]
+fn test_midi_quantization() {
+    let process = |grid: &str, quantize_strength| {
+        let options = midi::ProcessingOptions {
+            quantize: Some(grid.parse().expect("Failed to parse grid")),
+            quantize_strength,
+            ..Default::default()
+        };
+        let result = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+            .with_options(options)
+            .process_with_soundfonts(
+                "tests/samples/c4_sixteenths_180bpm.mid",
+                vec![String::from(SINE_SOUNDFONT)],
+            )
+            .expect("Failed to process MIDI file with soundfonts");
+        result
+            .note_changes
+            .iter()
+            .flat_map(|event| {
+                event
+                    .notes
+                    .iter()
+                    .map(move |note| (event.timestamp, note.note, note.end))
+            })
+            .collect::<Vec<(u64, u8, u64)>>()
+    };
+
+    // Sixteenth notes at 180 BPM, on a grid of eighth notes
+    assert_eq!(
+        process("1/8", 1.0),
+        vec![
+            (0, 60, 166_667),
+            (166_667, 62, 333_333),
+            (166_667, 64, 333_333),
+            (333_333, 65, 500_000)
+        ]
+    );
+    assert_eq!(
+        process("100ms", 1.0),
+        vec![
+            (0, 60, 100_000),
+            (100_000, 62, 200_000),
+            (200_000, 64, 300_000),
+            (300_000, 65, 400_000)
+        ]
+    );
+    assert_eq!(
+        process("100ms", 0.5),
+        vec![
+            (0, 60, 91_667),
+            (91_667, 62, 183_334),
+            (183_334, 64, 275_000),
+            (275_000, 65, 366_667)
+        ]
+    );
+
+    // The notes at 1 and 2 seconds move to 900 and 2100 milliseconds, and are
+    // still played with the programs set right before them
+    let programs = |quantize: Option<&str>| {
+        let options = midi::ProcessingOptions {
+            quantize: quantize.map(|grid| grid.parse().expect("Failed to parse grid")),
+            ..Default::default()
+        };
+        let result = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+            .with_options(options)
+            .with_soundfont_mapping(SoundfontMapping::Program)
+            .process_with_soundfonts(
+                "tests/samples/c4_program_changes.mid",
+                vec![String::from(SINE_SOUNDFONT), String::from(SQUARE_SOUNDFONT)],
+            )
+            .expect("Failed to process MIDI file with soundfonts");
+        result
+            .note_changes
+            .iter()
+            .flat_map(|event| event.notes.iter().map(|note| (note.note, note.program)))
+            .collect::<Vec<(u8, u8)>>()
+    };
+    assert_eq!(programs(Some("300ms")), programs(None));
+
+    // Strengths beyond moving notes onto the grid are rejected
+    for quantize_strength in [-0.5, 1.5] {
+        let options = midi::ProcessingOptions {
+            quantize: Some("100ms".parse().expect("Failed to parse grid")),
+            quantize_strength,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    // Timecode files have no tempo to lay out a grid of note values with
+    let smpte = |grid: &str| {
+        let options = midi::ProcessingOptions {
+            quantize: Some(grid.parse().expect("Failed to parse grid")),
+            ..Default::default()
+        };
+        midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+            .with_options(options)
+            .process_with_soundfonts(
+                "tests/samples/c4_chromatic_smpte25fps.mid",
+                vec![String::from(SINE_SOUNDFONT)],
+            )
+    };
+    assert!(smpte("1/8").is_err());
+    assert!(smpte("100ms").is_ok());
+
+    assert_eq!(
+        "1/8t".parse::<midi::QuantizeGrid>().ok(),
+        Some(midi::QuantizeGrid::Note {
+            division: 8,
+            triplet: true
+        })
+    );
+    assert_eq!(
+        "25ms".parse::<midi::QuantizeGrid>().ok(),
+        Some(midi::QuantizeGrid::Milliseconds(25))
+    );
+    for invalid in ["", "16", "1/0", "0ms", "1/8x", "ms"] {
+        assert!(
+            invalid.parse::<midi::QuantizeGrid>().is_err(),
+            "Should reject {:?}",
+            invalid
+        );
+    }
+}
+
+/// Test trimming a song to a time range.
+///
+/// This test verifies:
+/// - Trimming by seconds, by bar and beat, and by ticks
+/// - Notes sounding at the start or end are clipped, without their attack at the start
+/// - The trimmed song is timed from its start
+/// - The silence before the first note is removed unless it is kept
+/// - Trimming to end before the start, past the end of a bar, or at a bar of a
+///   timecode file is rejected
+/// - Envelopes of notes clipped at the start keep their phase
+/// - Trim points are parsed from their written form
+#[test]
+fn test_midi_trimming() {
+    let convert = |midi_path: &str, options: midi::ProcessingOptions| {
+        let options = midi::ProcessingOptions {
+            sustain_pedal: false,
+            ..options
+        };
+        midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR)
+            .with_options(options)
+            .process_with_soundfonts(midi_path, vec![String::from(SINE_SOUNDFONT)])
+    };
+    let process = |midi_path: &str, options: midi::ProcessingOptions| {
+        let result =
+            convert(midi_path, options).expect("Failed to process MIDI file with soundfonts");
+        result
+            .note_changes
+            .iter()
+            .flat_map(|event| {
+                event
+                    .notes
+                    .iter()
+                    .map(move |note| (event.timestamp, note.note, note.end, note.attack))
+            })
+            .collect::<Vec<(u64, u8, u64, bool)>>()
+    };
+    let trim = |start: &str, end: &str| midi::ProcessingOptions {
+        trim_start: Some(start.parse().expect("Failed to parse trim start")),
+        trim_end: Some(end.parse().expect("Failed to parse trim end")),
+        ..Default::default()
+    };
+
+    // One note a second, with four beats of a second in every bar
+    let midi_path = "tests/samples/c4_chromatic_60bpm.mid";
+    assert_eq!(
+        process(midi_path, trim("2.5", "5s")),
+        vec![
+            (0, 62, 500_000, false),
+            (500_000, 63, 1_500_000, true),
+            (1_500_000, 64, 2_500_000, true)
+        ]
+    );
+    let second_bar = vec![
+        (0, 64, 1_000_000, true),
+        (1_000_000, 65, 2_000_000, true),
+        (2_000_000, 66, 3_000_000, true),
+        (3_000_000, 67, 4_000_000, true),
+    ];
+    assert_eq!(process(midi_path, trim("2:1", "3:1")), second_bar);
+    assert_eq!(process(midi_path, trim("1920t", "3840t")), second_bar);
+    assert_eq!(process(midi_path, trim("1:5", "2:5")), second_bar);
+
+    // The end must come after the start, and beats must fall within their bar
+    for (start, end) in [
+        ("3", "2"),
+        ("2", "2s"),
+        ("2:1", "1:4"),
+        ("2:1", "1920t"),
+        ("1:6", "3:1"),
+    ] {
+        assert!(
+            convert(midi_path, trim(start, end)).is_err(),
+            "Should reject trimming from {} to {}",
+            start,
+            end
+        );
+    }
+
+    // Timecode files have no bars to trim at
+    let midi_path = "tests/samples/c4_chromatic_smpte25fps.mid";
+    assert!(convert(midi_path, trim("1:1", "2:1")).is_err());
+    assert!(convert(midi_path, trim("1", "2")).is_ok());
+
+    // A note struck before the start keeps fading out as it would have, rather
+    // than being struck again at the start
+    let decay = Envelope {
+        attack: 0,
+        decay: 1000,
+        sustain: 0.0,
+        release: 0,
+    };
+    let result = convert(
+        "tests/samples/c4_chromatic_60bpm.mid",
+        midi::ProcessingOptions {
+            channel_envelopes: [(0, decay)].into_iter().collect(),
+            ..trim("2.5", "5")
+        },
+    )
+    .expect("Failed to process MIDI file with soundfonts");
+    let clipped = &result.note_changes[0].notes[0];
+    assert_eq!(clipped.onset, -500_000);
+    assert_eq!(clipped.velocity_at(0.0), clipped.velocity / 2);
+
+    // Without the sustain pedal, the next note after 0.6 seconds starts at 1 second
+    let midi_path = "tests/samples/c4_sustain_sostenuto.mid";
+    let trim_start = Some(midi::TrimPoint::Seconds(0.6));
+    assert_eq!(
+        process(
+            midi_path,
+            midi::ProcessingOptions {
+                trim_start,
+                ..Default::default()
+            }
+        )[0],
+        (0, 64, 500_000, true)
+    );
+    assert_eq!(
+        process(
+            midi_path,
+            midi::ProcessingOptions {
+                trim_start,
+                strip_leading_silence: false,
+                ..Default::default()
+            }
+        )[0],
+        (400_000, 64, 900_000, true)
+    );
+
+    assert_eq!(
+        "17:2.5".parse::<midi::TrimPoint>().ok(),
+        Some(midi::TrimPoint::BarBeat { bar: 17, beat: 2.5 })
+    );
+    assert_eq!(
+        "7680t".parse::<midi::TrimPoint>().ok(),
+        Some(midi::TrimPoint::Ticks(7680))
+    );
+    for invalid in ["", "-1", "0:1", "1:0", "abc", "5x", "1:2:3"] {
+        assert!(
+            invalid.parse::<midi::TrimPoint>().is_err(),
+            "Should reject {:?}",
+            invalid
+        );
+    }
+}
+
+/// Test velocity envelopes.
+///
+/// This test verifies:
+/// - Reading the envelope line of a soundfont file
+/// - Attaching the soundfont's envelope to its notes
+/// - Rejecting malformed envelopes
+#[test]
+fn test_midi_envelopes() {
+    let expected = Envelope {
+        attack: 0,
+        decay: 200,
+        sustain: 0.5,
+        release: 100,
+    };
+    let (_, envelope) =
+        midi::parse_soundfont_with_envelope(SINE_PLUCK_SOUNDFONT, Some(Path::new(SAMPLES_DIR)))
+            .expect("Failed to read soundfont")
+            .expect("Soundfont should not be empty");
+    assert_eq!(envelope, Some(expected));
+
+    let processor = midi::MidiProcessor::with_soundfont_dir(SAMPLES_DIR);
+    let result = processor
+        .process_with_soundfonts(
+            "tests/samples/a4_volume.mid",
+            vec![String::from(SINE_PLUCK_SOUNDFONT)],
+        )
+        .expect("Failed to process MIDI file with soundfonts");
+    assert!(result
+        .note_changes
+        .iter()
+        .flat_map(|event| event.notes.iter())
+        .all(|note| note.envelope == Some(expected)));
+
+    assert!("10,20,0.5".parse::<Envelope>().is_err());
+    assert!("10,20,1.5,30".parse::<Envelope>().is_err());
+    assert!("10,x,0.5,30".parse::<Envelope>().is_err());
+}
+
 /// Test MIDI information extraction.
 ///
 /// This test verifies:
@@ -197,7 +852,7 @@ fn collect_note_timestamps(song: &ProcessedSong) -> Vec<f64> {
     let mut timestamps: Vec<f64> = song
         .note_changes
         .iter()
-        .map(|event| event.timestamp as f64 / 1000.0)
+        .map(|event| event.timestamp as f64 / 1_000_000.0)
         .collect();
     timestamps.sort_by(|a, b| a.partial_cmp(b).unwrap());
     timestamps
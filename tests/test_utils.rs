@@ -17,14 +17,29 @@ pub const MIDI_C4_CHROMATIC_60BPM: &str = "tests/samples/c4_chromatic_60bpm.mid"
 pub const MIDI_C4_CHROMATIC_DYNAMICBPM: &str = "tests/samples/c4_chromatic_dynamicbpm.mid";
 pub const MIDI_C4C5_CHROMATIC_PIANO_SAX_DYNAMICBPM: &str =
     "tests/samples/c4c5_chromatic_piano_sax_dynamicbpm.mid";
+pub const MIDI_C4_CHROMATIC_SMPTE25FPS: &str = "tests/samples/c4_chromatic_smpte25fps.mid";
+pub const MIDI_C4_CHROMATIC_SMPTE2997DF: &str = "tests/samples/c4_chromatic_smpte2997df.mid";
+pub const MIDI_A4_PITCHBEND: &str = "tests/samples/a4_pitchbend.mid";
+pub const MIDI_C4_SUSTAIN_SOSTENUTO: &str = "tests/samples/c4_sustain_sostenuto.mid";
+pub const MIDI_A4_VOLUME: &str = "tests/samples/a4_volume.mid";
+pub const MIDI_A4_VOLUME_FADE: &str = "tests/samples/a4_volume_fade.mid";
+pub const MIDI_C4_PROGRAM_CHANGES: &str = "tests/samples/c4_program_changes.mid";
+pub const MIDI_C4_SHARED_CHANNEL_TRACKS: &str = "tests/samples/c4_shared_channel_tracks.mid";
+pub const MIDI_A4_RETRIGGER: &str = "tests/samples/a4_retrigger.mid";
+pub const MIDI_A4_REPEATED: &str = "tests/samples/a4_repeated.mid";
+pub const MIDI_C4_SIXTEENTHS_180BPM: &str = "tests/samples/c4_sixteenths_180bpm.mid";
+pub const MIDI_C4_G4_E4_TWO_CHANNELS: &str = "tests/samples/c4_g4_e4_two_channels.mid";
 pub const WAV_440HZ_5SEC: &str = "tests/samples/440hz_44100hz_16bit_5sec.wav";
 
 /// Constants for soundfont locations
 pub const SAMPLES_DIR: &str = "tests/samples";
 pub const SINE_SOUNDFONT: &str = "sine.txt";
 pub const SQUARE_SOUNDFONT: &str = "square.txt";
+#[allow(dead_code)]
+pub const SINE_PLUCK_SOUNDFONT: &str = "sine_pluck.txt";
 pub const SINE_SOUNDFONT_PATH: &str = "tests/samples/sine.txt";
 pub const SQUARE_SOUNDFONT_PATH: &str = "tests/samples/square.txt";
+pub const SINE_PLUCK_SOUNDFONT_PATH: &str = "tests/samples/sine_pluck.txt";
 
 /// Check if a file exists at the specified path.
 ///
@@ -96,6 +111,66 @@ pub fn validate_test_files() {
         "Missing test file: {}",
         MIDI_C4C5_CHROMATIC_PIANO_SAX_DYNAMICBPM
     );
+    assert!(
+        file_exists(MIDI_C4_CHROMATIC_SMPTE25FPS),
+        "Missing test file: {}",
+        MIDI_C4_CHROMATIC_SMPTE25FPS
+    );
+    assert!(
+        file_exists(MIDI_C4_CHROMATIC_SMPTE2997DF),
+        "Missing test file: {}",
+        MIDI_C4_CHROMATIC_SMPTE2997DF
+    );
+    assert!(
+        file_exists(MIDI_A4_PITCHBEND),
+        "Missing test file: {}",
+        MIDI_A4_PITCHBEND
+    );
+    assert!(
+        file_exists(MIDI_C4_SUSTAIN_SOSTENUTO),
+        "Missing test file: {}",
+        MIDI_C4_SUSTAIN_SOSTENUTO
+    );
+    assert!(
+        file_exists(MIDI_A4_VOLUME),
+        "Missing test file: {}",
+        MIDI_A4_VOLUME
+    );
+    assert!(
+        file_exists(MIDI_A4_VOLUME_FADE),
+        "Missing test file: {}",
+        MIDI_A4_VOLUME_FADE
+    );
+    assert!(
+        file_exists(MIDI_C4_PROGRAM_CHANGES),
+        "Missing test file: {}",
+        MIDI_C4_PROGRAM_CHANGES
+    );
+    assert!(
+        file_exists(MIDI_C4_SHARED_CHANNEL_TRACKS),
+        "Missing test file: {}",
+        MIDI_C4_SHARED_CHANNEL_TRACKS
+    );
+    assert!(
+        file_exists(MIDI_A4_RETRIGGER),
+        "Missing test file: {}",
+        MIDI_A4_RETRIGGER
+    );
+    assert!(
+        file_exists(MIDI_A4_REPEATED),
+        "Missing test file: {}",
+        MIDI_A4_REPEATED
+    );
+    assert!(
+        file_exists(MIDI_C4_SIXTEENTHS_180BPM),
+        "Missing test file: {}",
+        MIDI_C4_SIXTEENTHS_180BPM
+    );
+    assert!(
+        file_exists(MIDI_C4_G4_E4_TWO_CHANNELS),
+        "Missing test file: {}",
+        MIDI_C4_G4_E4_TWO_CHANNELS
+    );
     assert!(
         file_exists(WAV_440HZ_5SEC),
         "Missing test file: {}",
@@ -113,6 +188,11 @@ pub fn validate_test_files() {
         "Missing soundfont file: {}",
         SQUARE_SOUNDFONT_PATH
     );
+    assert!(
+        file_exists(SINE_PLUCK_SOUNDFONT_PATH),
+        "Missing soundfont file: {}",
+        SINE_PLUCK_SOUNDFONT_PATH
+    );
 }
 
 /// Test that verifies the test environment is correctly set up.